@@ -1,9 +1,15 @@
 mod watchdog_config;
 mod watchdog_mode;
 
+use hyper::StatusCode;
+use std::net::IpAddr;
+#[cfg(feature = "wasm")]
+use std::path::PathBuf;
 use std::time::Duration;
 pub(crate) use watchdog_config::*;
 
+use crate::HeaderFilter;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub(crate) enum WatchdogMode {
     ModeUnknown = 0,
@@ -15,24 +21,83 @@ pub(crate) enum WatchdogMode {
     ModeWasm = 6,
 }
 
+/// the body format for error responses
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum ErrorFormat {
+    /// just the error message
+    Text,
+    /// json object like `{"error": "...", "request_id": "..."}`
+    Json,
+}
+
+/// a host directory preopened at the guest path for wasm
+#[cfg(feature = "wasm")]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MapDir {
+    pub(crate) _guest: String,
+    pub(crate) _host: PathBuf,
+    /// if the function may write it, which also needs the `fs_write` capability
+    pub(crate) _writable: bool,
+}
+
 /// configuration for a watchdog
 #[derive(Debug, Clone)]
 pub(crate) struct WatchdogConfig {
     /// TCP port for watchdog server
     pub(crate) _tcp_port: u16,
+    /// If sets `TCP_NODELAY` on the accepted connections to disable Nagle's algorithm
+    pub(crate) _tcp_nodelay: bool,
 
     pub(crate) _http_read_timeout: Duration,
     pub(crate) _http_write_timeout: Duration,
     pub(crate) _exec_timeout: Duration,
+    /// The max time to receive the complete request headers of a connection, zero means no limit
+    pub(crate) _handshake_timeout: Duration,
+    /// The max time of the whole request including body reading, zero means no limit
+    pub(crate) _request_timeout: Duration,
+    /// The max time a request waits for a free function before 503, zero means no limit
+    pub(crate) _queue_timeout: Duration,
+    /// The max time to wait for the running functions when shutting down, zero means no limit
+    pub(crate) _join_timeout: Duration,
+    /// The max bytes of the request headers, the larger ones get 431
+    pub(crate) _max_header_bytes: Option<usize>,
+    /// The max bytes of the request path and query, the longer ones get 414
+    pub(crate) _max_uri_bytes: usize,
+    /// The time to keep answering the new requests with the reject status when shutting down,
+    /// so the orchestrator notices the failed health check before the listeners close
     pub(crate) _health_check_interval: Duration,
 
     pub(crate) _function_process: String,
     pub(crate) _content_type: String,
 
     pub(crate) _inject_cgi_headers: bool,
+    /// The request headers injected as environment, by `env_header_allowlist` and `env_header_denylist`
+    pub(crate) _env_header_filter: HeaderFilter,
     pub(crate) _operational_mode: WatchdogMode,
     pub(crate) _suppress_lock: bool,
+
+    /// The file created when ready to serve and removed on shutdown
+    pub(crate) _ready_file_path: Option<String>,
+
     pub(crate) _upstream_url: Option<String>,
+    /// The comma separated hosts (with optional ports) which `upstream_url` must match at startup
+    pub(crate) _http_upstream_allowlist: Option<String>,
+    /// If the http mode forks the function process as the local server of `upstream_url`
+    pub(crate) _http_fork_process: bool,
+    /// The max time to wait for the forked server to accept connections
+    pub(crate) _http_fork_ready_timeout: Duration,
+    /// The path of upstream polled until it responds 200, the http mode responds 503 before it
+    pub(crate) _http_healthcheck_path: Option<String>,
+    /// The interval to poll the healthcheck path of upstream
+    pub(crate) _http_healthcheck_interval: Duration,
+    /// The max restarts of the crashed forked server in `restart_window`, the watchdog exits on one more crash
+    pub(crate) _max_process_restarts: usize,
+    /// The time window to count the restarts of the forked server
+    pub(crate) _restart_window: Duration,
+    /// The max address space in MB of the process forked for every request, only on unix
+    pub(crate) _child_rlimit_as_mb: Option<u64>,
+    /// The max cpu seconds of the process forked for every request, only on unix
+    pub(crate) _child_rlimit_cpu: Option<u64>,
     pub(crate) _static_path: String,
 
     /// If buffers the HTTP body in memory to prevent transfer type of chunked encoding which some servers do not support.
@@ -41,6 +106,15 @@ pub(crate) struct WatchdogConfig {
     /// TCP port on which to serve HTTP Prometheus metrics
     pub(crate) _metrics_port: u16,
 
+    /// The function name set as the `function` label of all metrics
+    pub(crate) _function_name: Option<String>,
+
+    /// The prefix of all metric names, such as `myfn` for `myfn_requests_total`
+    pub(crate) _metrics_prefix: Option<String>,
+
+    /// The buckets in seconds of the request duration histogram
+    pub(crate) _duration_buckets: Vec<f64>,
+
     /// limits the number of simultaneous requests that the watchdog allows concurrently.
     /// Any request which exceeds this limit will have an immediate response of 429.
     pub(crate) _max_inflight: i32,
@@ -51,12 +125,93 @@ pub(crate) struct WatchdogConfig {
     /// The size for scanning logs for stdout/stderr
     pub(crate) _log_buffer_size: i32,
 
+    /// The body format for error responses
+    pub(crate) _error_format: ErrorFormat,
+
+    /// The number of request body chunks buffered before the body reading waits for the function
+    pub(crate) _body_channel_capacity: Option<usize>,
+
+    /// The max number of request bodies read at the same time, the others wait before reading
+    pub(crate) _max_body_pumps: Option<usize>,
+
+    /// If rejects the request body without `Content-Length` with 411
+    pub(crate) _require_content_length: bool,
+
+    /// If decompresses the `gzip`/`deflate` request body before passing it to the function
+    pub(crate) _decompress_request: bool,
+
+    /// The max size in bytes of the decompressed request body
+    pub(crate) _decompress_max_size: usize,
+
+    /// The response status for requests which come after shutdown begins
+    pub(crate) _shutdown_reject_status: StatusCode,
+
+    /// The seconds of `Access-Control-Max-Age` for the OPTIONS responses
+    pub(crate) _cors_max_age: Option<u64>,
+
+    /// If the OPTIONS requests go to the function instead of being answered with CORS headers
+    pub(crate) _options_passthrough: bool,
+
+    /// TCP port for the control endpoints, which are not served on the function port if set
+    pub(crate) _admin_port: Option<u16>,
+
+    /// If `POST /_/reset-counters` resets the invocation count, for the test harnesses
+    pub(crate) _enable_reset_counters: bool,
+
+    /// The IP address which the watchdog, metrics and admin listeners bind to
+    pub(crate) _listen_address: IpAddr,
+
+    /// The file served for `GET /` instead of invoking the function in wasm mode
+    pub(crate) _root_page_file: Option<String>,
+
+    /// The response for GET whose function output is empty, a leading `@` means reading from the file
+    pub(crate) _get_default_response: Option<String>,
+
+    /// The body served with 504 when the request timeout, a leading `@` means reading from the file
+    pub(crate) _timeout_response_body: Option<String>,
+
     /// The min running function number
     pub(crate) _min_scale: Option<usize>,
 
     /// The max running function number
     pub(crate) _max_scale: Option<usize>,
 
+    /// If the workers grow toward the max scale when requests queue up, and shrink after
+    #[cfg(feature = "wasm")]
+    pub(crate) _burst_autoscale: bool,
+
+    /// The queue depth over which the burst autoscale grows a worker
+    #[cfg(feature = "wasm")]
+    pub(crate) _burst_queue_threshold: usize,
+
+    /// The OTLP/HTTP collector endpoint to export a span for every request
+    #[cfg(feature = "otel")]
+    pub(crate) _otel_endpoint: Option<String>,
+
+    /// If guesses the response content type from the function output when `content_type` is not set
+    #[cfg(feature = "wasm")]
+    pub(crate) _sniff_content_type: bool,
+
+    /// If the leading `HTTP/1.1 404 Not Found` or `Status: 404` line of stdout sets the response status
+    #[cfg(feature = "wasm")]
+    pub(crate) _parse_status_line: bool,
+
+    /// The output size over which the wasm response is streamed instead of buffered
+    #[cfg(feature = "wasm")]
+    pub(crate) _response_stream_threshold: Option<usize>,
+
+    /// If every write to the wasm stdout is streamed in the response body as it arrives
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_stream_stdout: bool,
+
+    /// If an empty request body reads as EOF, else the stdin stays open without data
+    #[cfg(feature = "wasm")]
+    pub(crate) _stdin_eof_on_empty: bool,
+
+    /// The max bytes returned by one read of stdin in wasm function
+    #[cfg(feature = "wasm")]
+    pub(crate) _stdin_max_chunk: Option<usize>,
+
     /// The root directory for wasm file system
     #[cfg(feature = "wasm")]
     pub(crate) _wasm_root: Option<String>,
@@ -69,7 +224,107 @@ pub(crate) struct WatchdogConfig {
     #[cfg(feature = "wasm")]
     pub(crate) _wasm_c_cpu_features: Option<String>,
 
+    /// WebAssembly compiler: `llvm`, `cranelift` or `singlepass`
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_compiler: Option<String>,
+
+    /// The days after which the cached compiled module is ignored, default is unlimited
+    #[cfg(feature = "wasm")]
+    pub(crate) _artifact_max_age_days: Option<u64>,
+
+    /// The wasi capabilities allowed for the function separated by comma, default is all but `fs_write`
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_capabilities: Option<String>,
+
     /// WebAssembly run instance with cuda support
     #[cfg(feature = "wasm")]
     pub(crate) _use_cuda: Option<bool>,
+
+    /// If fails the startup when `use_cuda` is true but cuda is not compiled in
+    #[cfg(feature = "wasm")]
+    pub(crate) _cuda_required: Option<bool>,
+
+    /// If injects every query param as `Http_Query_<Name>` besides the raw `Http_Query`
+    #[cfg(feature = "wasm")]
+    pub(crate) _parse_query_params: bool,
+
+    /// The max bytes of an injected environment value, the longer ones are truncated
+    #[cfg(feature = "wasm")]
+    pub(crate) _max_env_value_bytes: usize,
+
+    /// If the i32 returned by `_start` is taken as the response status or exit code
+    #[cfg(feature = "wasm")]
+    pub(crate) _start_return_as_status: bool,
+
+    /// The exported function called for every invocation, default is `_start`
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_entrypoint: String,
+
+    /// If the invocations are serialized for the function which is not thread safe
+    #[cfg(feature = "wasm")]
+    pub(crate) _function_single_threaded: bool,
+
+    /// The response size over which a warning is logged, default is never
+    #[cfg(feature = "wasm")]
+    pub(crate) _large_response_warn_bytes: Option<usize>,
+
+    /// The max bytes of stderr to log for one invocation, default is unlimited
+    #[cfg(feature = "wasm")]
+    pub(crate) _max_stderr_bytes: Option<usize>,
+
+    /// The number of recent stderr lines kept for `/_/logs`, zero or not set means disabled
+    #[cfg(feature = "wasm")]
+    pub(crate) _log_ring_size: Option<usize>,
+
+    /// If also log the function stdout with a `stdout:` prefix, default is false
+    #[cfg(feature = "wasm")]
+    pub(crate) _log_stdout: bool,
+
+    /// If warn the request body which the function does not read, default is false
+    #[cfg(feature = "wasm")]
+    pub(crate) _warn_unread_body: bool,
+
+    /// If every invocation gets a fresh temp directory, which needs the `fs_write` capability, default is false
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_per_request_tmp: bool,
+
+    /// The guest path of the temp directory for every invocation, default is `/tmp`
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_tmp_path: String,
+
+    /// The `guest_path:host_path[:ro|rw]` directories preopened instead of `wasm_root`, default is none
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_map_dirs: Vec<MapDir>,
+
+    /// The stack size in bytes of the wasm worker threads, default is the system default
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_worker_stack_size: Option<usize>,
+
+    /// If every wasm worker instantiates the module once before serving, default is false
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_warmup: bool,
+
+    /// The max number of requests queued for the wasm workers before 429, default is unbounded
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_max_queue: Option<usize>,
+
+    /// The max number of wasm worker panics in recent one minute before the health check fails
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_max_recent_panics: Option<usize>,
+
+    /// The initial pages of the memory supplied to the modules importing memory
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_import_memory_pages: Option<u32>,
+
+    /// The fuel metered for every invocation, default is unlimited
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_fuel_limit: Option<u64>,
+
+    /// The cap of the memory pages of every instance, default is unlimited
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_max_memory_pages: Option<u32>,
+
+    /// The max pages of the memory supplied to the modules importing memory
+    #[cfg(feature = "wasm")]
+    pub(crate) _wasm_import_memory_max_pages: Option<u32>,
 }
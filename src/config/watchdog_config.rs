@@ -1,17 +1,28 @@
 use anyhow::{anyhow, Result};
+use hyper::StatusCode;
 use std::collections::HashMap;
+use std::net::IpAddr;
+#[cfg(feature = "wasm")]
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 
 use super::watchdog_mode::WATCHDOG_MODE_STR;
-use super::{WatchdogConfig, WatchdogMode};
+#[cfg(feature = "wasm")]
+use super::MapDir;
+use super::{ErrorFormat, WatchdogConfig, WatchdogMode};
 
 #[cfg(feature = "wasm")]
 use crate::runner::wasm_runner::*;
+use crate::{parse_size, HeaderFilter};
+use hyper::header::HeaderName;
 
 const KET_PORT: &str = "port";
 const DEFAULT_PORT: u16 = 8080;
 
+const KEY_METRICS_PORT: &str = "metrics_port";
+const DEFAULT_METRICS_PORT: u16 = 8081;
+
 const KEY_READ_TIMEOUT: &str = "read_timeout";
 const DEFAULT_READ_TIMEOUT_SEC: u64 = 10;
 
@@ -22,6 +33,31 @@ const KEY_HEALTH_CHECK_INTERVAL: &str = "healthcheck_interval";
 const KEY_EXEC_TIMEOUT: &str = "exec_timeout";
 const DEFAULT_EXEC_TIMEOUT_SEC: u64 = 10;
 
+const KEY_TCP_NODELAY: &str = "tcp_nodelay";
+const DEFAULT_TCP_NODELAY: bool = true;
+
+const KEY_HANDSHAKE_TIMEOUT: &str = "handshake_timeout";
+const DEFAULT_HANDSHAKE_TIMEOUT_SEC: u64 = 0;
+
+#[cfg(feature = "otel")]
+const KEY_OTEL_ENDPOINT: &str = "otel_endpoint";
+
+const KEY_QUEUE_TIMEOUT: &str = "queue_timeout";
+const DEFAULT_QUEUE_TIMEOUT_SEC: u64 = 0;
+
+const KEY_JOIN_TIMEOUT: &str = "join_timeout";
+const DEFAULT_JOIN_TIMEOUT_SEC: u64 = 0;
+
+const KEY_MAX_HEADER_BYTES: &str = "max_header_bytes";
+/// the min read buffer size of hyper
+const MIN_MAX_HEADER_BYTES: usize = 8 << 10;
+
+const KEY_MAX_URI_BYTES: &str = "max_uri_bytes";
+const DEFAULT_MAX_URI_BYTES: usize = 64 << 10;
+
+const KEY_REQUEST_TIMEOUT: &str = "request_timeout";
+const DEFAULT_REQUEST_TIMEOUT_SEC: u64 = 0;
+
 const KEY_MODE: &str = "mode";
 const DEFAULT_MODE: WatchdogMode = WatchdogMode::ModeWasm;
 
@@ -29,6 +65,23 @@ const KEY_FUNC_NAME_1: &str = "function_process";
 const KEY_FUNC_NAME_2: &str = "fprocess";
 const KEY_UPSTREAM_URL_1: &str = "http_upstream_url";
 const KEY_UPSTREAM_URL_2: &str = "upstream_url";
+const KEY_ENV_HEADER_ALLOWLIST: &str = "env_header_allowlist";
+const KEY_ENV_HEADER_DENYLIST: &str = "env_header_denylist";
+
+const KEY_HTTP_UPSTREAM_ALLOWLIST: &str = "http_upstream_allowlist";
+const KEY_HTTP_FORK_PROCESS: &str = "http_fork_process";
+const DEFAULT_HTTP_FORK_PROCESS: bool = false;
+const KEY_HTTP_FORK_READY_TIMEOUT: &str = "http_fork_ready_timeout";
+const DEFAULT_HTTP_FORK_READY_TIMEOUT_SEC: u64 = 10;
+const KEY_HTTP_HEALTHCHECK_PATH: &str = "http_healthcheck_path";
+const KEY_HTTP_HEALTHCHECK_INTERVAL: &str = "http_healthcheck_interval";
+const DEFAULT_HTTP_HEALTHCHECK_INTERVAL_SEC: f64 = 1.0;
+const KEY_MAX_PROCESS_RESTARTS: &str = "max_process_restarts";
+const DEFAULT_MAX_PROCESS_RESTARTS: usize = 5;
+const KEY_RESTART_WINDOW: &str = "restart_window";
+const DEFAULT_RESTART_WINDOW_SEC: u64 = 60;
+const KEY_CHILD_RLIMIT_AS_MB: &str = "child_rlimit_as_mb";
+const KEY_CHILD_RLIMIT_CPU: &str = "child_rlimit_cpu";
 
 const KEY_CONTENT_TYPE: &str = "content_type";
 const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
@@ -39,6 +92,8 @@ const DEFAULT_STATIC_PATH: &str = "/home/app/public";
 const KEY_SUPPRESS_LOCK: &str = "suppress_lock";
 const DEFAULT_SUPPRESS_LOCK: bool = false;
 
+const KEY_READY_FILE_PATH: &str = "ready_file_path";
+
 const KEY_MAX_INFLIGHT: &str = "max_inflight";
 const DEFAULT_MAX_INFLIGHT: i32 = 0;
 
@@ -52,16 +107,60 @@ const DEFAULT_PREFIX_LOGS: bool = true;
 const KEY_LOG_BUFFER_SIZE: &str = "log_buffer_size";
 const DEFAULT_LOG_BUFFER_SIZE: i32 = 65536;
 
+const KEY_ERROR_FORMAT: &str = "error_format";
+const DEFAULT_ERROR_FORMAT: ErrorFormat = ErrorFormat::Text;
+
+const KEY_BODY_CHANNEL_CAPACITY: &str = "body_channel_capacity";
+
+const KEY_MAX_BODY_PUMPS: &str = "max_body_pumps";
+
+const KEY_REQUIRE_CONTENT_LENGTH: &str = "require_content_length";
+const DEFAULT_REQUIRE_CONTENT_LENGTH: bool = false;
+
+const KEY_DECOMPRESS_REQUEST: &str = "decompress_request";
+const DEFAULT_DECOMPRESS_REQUEST: bool = false;
+
+const KEY_DECOMPRESS_MAX_SIZE: &str = "decompress_max_size";
+const DEFAULT_DECOMPRESS_MAX_SIZE: usize = 32 << 20;
+
+const KEY_SHUTDOWN_REJECT_STATUS: &str = "shutdown_reject_status";
+const DEFAULT_SHUTDOWN_REJECT_STATUS: StatusCode = StatusCode::SERVICE_UNAVAILABLE;
+
+const KEY_CORS_MAX_AGE: &str = "cors_max_age";
+
+const KEY_OPTIONS_PASSTHROUGH: &str = "options_passthrough";
+const DEFAULT_OPTIONS_PASSTHROUGH: bool = false;
+
+const KEY_ADMIN_PORT: &str = "admin_port";
+
+const KEY_ENABLE_RESET_COUNTERS: &str = "enable_reset_counters";
+const DEFAULT_ENABLE_RESET_COUNTERS: bool = false;
+
+const KEY_LISTEN_ADDRESS: &str = "listen_address";
+const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0";
+
+const KEY_FUNCTION_NAME: &str = "function_name";
+
+const KEY_METRICS_PREFIX: &str = "metrics_prefix";
+
+const KEY_DURATION_BUCKETS: &str = "duration_buckets";
+
+const KEY_ROOT_PAGE_FILE: &str = "root_page_file";
+
+const KEY_GET_DEFAULT_RESPONSE: &str = "get_default_response";
+
+const KEY_TIMEOUT_RESPONSE_BODY: &str = "timeout_response_body";
+
 pub(crate) const KEY_MIN_SCALE: &str = "min_scale";
 pub(crate) const KEY_MAX_SCALE: &str = "max_scale";
 
 const INJECT_CGI_HEADERS: bool = true;
-const METRICS_PORT: u16 = 8081;
 
 impl WatchdogConfig {
     // generate the instance of WatchdogConfig from the given environment variable
     pub(crate) fn new(vars: &HashMap<String, String>) -> Result<Self> {
         let tcp_port = parse_var(vars, &KET_PORT).unwrap_or(DEFAULT_PORT);
+        let metrics_port = parse_var(vars, KEY_METRICS_PORT).unwrap_or(DEFAULT_METRICS_PORT);
 
         let http_read_timeout = Duration::from_secs(
             parse_var(vars, KEY_READ_TIMEOUT).unwrap_or(DEFAULT_READ_TIMEOUT_SEC),
@@ -82,6 +181,43 @@ impl WatchdogConfig {
             parse_var(vars, KEY_EXEC_TIMEOUT).unwrap_or(DEFAULT_EXEC_TIMEOUT_SEC),
         );
 
+        let handshake_timeout = Duration::from_secs(
+            parse_var(vars, KEY_HANDSHAKE_TIMEOUT).unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_SEC),
+        );
+        let request_timeout = Duration::from_secs(
+            parse_var(vars, KEY_REQUEST_TIMEOUT).unwrap_or(DEFAULT_REQUEST_TIMEOUT_SEC),
+        );
+
+        let queue_timeout = Duration::from_secs(
+            parse_var(vars, KEY_QUEUE_TIMEOUT).unwrap_or(DEFAULT_QUEUE_TIMEOUT_SEC),
+        );
+
+        let join_timeout = Duration::from_secs(
+            parse_var(vars, KEY_JOIN_TIMEOUT).unwrap_or(DEFAULT_JOIN_TIMEOUT_SEC),
+        );
+
+        let max_uri_bytes = match vars.get(KEY_MAX_URI_BYTES) {
+            Some(s) => parse_size(s)
+                .filter(|n| *n > 0)
+                .ok_or_else(|| anyhow!("\"{}\" is not a valid size", KEY_MAX_URI_BYTES))?,
+            None => DEFAULT_MAX_URI_BYTES,
+        };
+
+        let max_header_bytes = match vars.get(KEY_MAX_HEADER_BYTES) {
+            Some(s) => Some(
+                parse_size(s)
+                    .filter(|n| *n >= MIN_MAX_HEADER_BYTES)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "\"{}\" must be a size not less than {}",
+                            KEY_MAX_HEADER_BYTES,
+                            MIN_MAX_HEADER_BYTES
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+
         let operational_mode = match vars.get(KEY_MODE) {
             Some(str) => {
                 let mode = WatchdogMode::from(str);
@@ -125,6 +261,17 @@ impl WatchdogConfig {
         let content_type =
             parse_var(vars, KEY_CONTENT_TYPE).unwrap_or(DEFAULT_CONTENT_TYPE.to_string());
 
+        let env_header_filter = HeaderFilter {
+            _allow: match vars.get(KEY_ENV_HEADER_ALLOWLIST) {
+                Some(s) => Some(parse_header_names(s, KEY_ENV_HEADER_ALLOWLIST)?),
+                None => None,
+            },
+            _deny: match vars.get(KEY_ENV_HEADER_DENYLIST) {
+                Some(s) => parse_header_names(s, KEY_ENV_HEADER_DENYLIST)?,
+                None => Vec::new(),
+            },
+        };
+
         let upstream_url = match parse_var(vars, KEY_UPSTREAM_URL_1) {
             Some(u) => Some(u),
             None => parse_var(vars, KEY_UPSTREAM_URL_2),
@@ -142,6 +289,136 @@ impl WatchdogConfig {
         let prefix_logs = parse_var(vars, KEY_PREFIX_LOGS).unwrap_or(DEFAULT_PREFIX_LOGS);
         let log_buffer_size =
             parse_var(vars, KEY_LOG_BUFFER_SIZE).unwrap_or(DEFAULT_LOG_BUFFER_SIZE);
+        let error_format = parse_var(vars, KEY_ERROR_FORMAT).unwrap_or(DEFAULT_ERROR_FORMAT);
+        let body_channel_capacity = parse_var(vars, KEY_BODY_CHANNEL_CAPACITY);
+        let max_body_pumps = parse_var(vars, KEY_MAX_BODY_PUMPS);
+        let shutdown_reject_status = match parse_var(vars, KEY_SHUTDOWN_REJECT_STATUS) {
+            Some(code) => StatusCode::from_u16(code).map_err(|_| {
+                anyhow!(
+                    "\"{}\" is not a valid status code",
+                    KEY_SHUTDOWN_REJECT_STATUS
+                )
+            })?,
+            None => DEFAULT_SHUTDOWN_REJECT_STATUS,
+        };
+
+        let admin_port = parse_var(vars, KEY_ADMIN_PORT);
+
+        let http_healthcheck_path: Option<String> = parse_var(vars, KEY_HTTP_HEALTHCHECK_PATH);
+        if matches!(http_healthcheck_path, Some(ref p) if !p.starts_with('/')) {
+            return Err(anyhow!(
+                "\"{}\" must start with `/`",
+                KEY_HTTP_HEALTHCHECK_PATH
+            ));
+        }
+        let http_healthcheck_interval = match vars.get(KEY_HTTP_HEALTHCHECK_INTERVAL) {
+            Some(s) => s
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite() && *n > 0.0)
+                .map(Duration::from_secs_f64)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "\"{}\" must be the seconds over 0",
+                        KEY_HTTP_HEALTHCHECK_INTERVAL
+                    )
+                })?,
+            None => Duration::from_secs_f64(DEFAULT_HTTP_HEALTHCHECK_INTERVAL_SEC),
+        };
+        let listen_address: IpAddr = vars
+            .get(KEY_LISTEN_ADDRESS)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_LISTEN_ADDRESS)
+            .parse()
+            .map_err(|_| {
+                anyhow!(
+                    "\"{}\" must be a valid IPv4 or IPv6 address",
+                    KEY_LISTEN_ADDRESS
+                )
+            })?;
+
+        let decompress_request =
+            parse_var(vars, KEY_DECOMPRESS_REQUEST).unwrap_or(DEFAULT_DECOMPRESS_REQUEST);
+        let decompress_max_size = match vars.get(KEY_DECOMPRESS_MAX_SIZE) {
+            Some(s) => parse_size(s)
+                .ok_or_else(|| anyhow!("\"{}\" is not a valid size", KEY_DECOMPRESS_MAX_SIZE))?,
+            None => DEFAULT_DECOMPRESS_MAX_SIZE,
+        };
+
+        let duration_buckets = match vars.get(KEY_DURATION_BUCKETS) {
+            Some(s) => parse_buckets(s).ok_or_else(|| {
+                anyhow!(
+                    "\"{}\" must be increasing seconds separated by comma",
+                    KEY_DURATION_BUCKETS
+                )
+            })?,
+            None => prometheus::DEFAULT_BUCKETS.to_vec(),
+        };
+
+        #[cfg(feature = "wasm")]
+        let stdin_max_chunk = match vars.get(KEY_STDIN_MAX_CHUNK) {
+            Some(s) => Some(
+                parse_size(s)
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| anyhow!("\"{}\" is not a valid size", KEY_STDIN_MAX_CHUNK))?,
+            ),
+            None => None,
+        };
+        #[cfg(feature = "wasm")]
+        let response_stream_threshold = match vars.get(KEY_RESPONSE_STREAM_THRESHOLD) {
+            Some(s) => Some(parse_size(s).ok_or_else(|| {
+                anyhow!("\"{}\" is not a valid size", KEY_RESPONSE_STREAM_THRESHOLD)
+            })?),
+            None => None,
+        };
+        #[cfg(feature = "wasm")]
+        let max_env_value_bytes = match vars.get(KEY_MAX_ENV_VALUE_BYTES) {
+            Some(s) => parse_size(s)
+                .ok_or_else(|| anyhow!("\"{}\" is not a valid size", KEY_MAX_ENV_VALUE_BYTES))?,
+            None => DEFAULT_MAX_ENV_VALUE_BYTES,
+        };
+        #[cfg(feature = "wasm")]
+        let large_response_warn_bytes = match vars.get(KEY_LARGE_RESPONSE_WARN_BYTES) {
+            Some(s) => Some(parse_size(s).ok_or_else(|| {
+                anyhow!("\"{}\" is not a valid size", KEY_LARGE_RESPONSE_WARN_BYTES)
+            })?),
+            None => None,
+        };
+        #[cfg(feature = "wasm")]
+        let wasm_tmp_path: String =
+            parse_var(vars, KEY_WASM_TMP_PATH).unwrap_or_else(|| DEFAULT_WASM_TMP_PATH.to_string());
+        #[cfg(feature = "wasm")]
+        if !wasm_tmp_path.starts_with('/') {
+            return Err(anyhow!("\"{}\" must start with `/`", KEY_WASM_TMP_PATH));
+        }
+        #[cfg(feature = "wasm")]
+        let wasm_per_request_tmp =
+            parse_var(vars, KEY_WASM_PER_REQUEST_TMP).unwrap_or(DEFAULT_WASM_PER_REQUEST_TMP);
+        #[cfg(feature = "wasm")]
+        let wasm_map_dirs = match vars.get(KEY_WASM_MAP_DIR) {
+            Some(s) => {
+                let max_map_dirs =
+                    parse_var(vars, KEY_MAX_MAP_DIRS).unwrap_or(DEFAULT_MAX_MAP_DIRS);
+                let tmp_path = wasm_per_request_tmp.then_some(wasm_tmp_path.as_str());
+                parse_map_dirs(s, max_map_dirs, tmp_path)?
+            }
+            None => Vec::new(),
+        };
+        #[cfg(feature = "wasm")]
+        let max_stderr_bytes = match vars.get(KEY_MAX_STDERR_BYTES) {
+            Some(s) => Some(
+                parse_size(s)
+                    .ok_or_else(|| anyhow!("\"{}\" is not a valid size", KEY_MAX_STDERR_BYTES))?,
+            ),
+            None => None,
+        };
+        #[cfg(feature = "wasm")]
+        let wasm_worker_stack_size = match vars.get(KEY_WASM_WORKER_STACK_SIZE) {
+            Some(s) => Some(parse_size(s).ok_or_else(|| {
+                anyhow!("\"{}\" is not a valid size", KEY_WASM_WORKER_STACK_SIZE)
+            })?),
+            None => None,
+        };
 
         // check
         if operational_mode == WatchdogMode::ModeHTTP && upstream_url.is_none() {
@@ -149,6 +426,25 @@ impl WatchdogConfig {
                 "For \"mode=http\" you must specify a valid URL for \"http_upstream_url\""
             ));
         }
+        if body_channel_capacity == Some(0) {
+            return Err(anyhow!("\"{}\" must be over 0.", KEY_BODY_CHANNEL_CAPACITY));
+        }
+        if max_body_pumps == Some(0) {
+            return Err(anyhow!("\"{}\" must be over 0.", KEY_MAX_BODY_PUMPS));
+        }
+        if metrics_port == tcp_port {
+            return Err(anyhow!(
+                "\"{}\" must differ from the watchdog port {}",
+                KEY_METRICS_PORT,
+                tcp_port
+            ));
+        }
+        if admin_port == Some(tcp_port) || admin_port == Some(metrics_port) {
+            return Err(anyhow!(
+                "\"{}\" must differ from the watchdog and metrics port",
+                KEY_ADMIN_PORT
+            ));
+        }
         if operational_mode == WatchdogMode::ModeStatic && static_path == "" {
             return Err(anyhow!(
                 "For mode=static you must specify the \"static_path\" to serve"
@@ -157,25 +453,97 @@ impl WatchdogConfig {
 
         Ok(Self {
             _tcp_port: tcp_port,
+            _tcp_nodelay: parse_var(vars, KEY_TCP_NODELAY).unwrap_or(DEFAULT_TCP_NODELAY),
             _http_read_timeout: http_read_timeout,
             _http_write_timeout: http_write_timeout,
             _exec_timeout: exec_timeout,
+            _handshake_timeout: handshake_timeout,
+            _request_timeout: request_timeout,
+            _queue_timeout: queue_timeout,
+            _join_timeout: join_timeout,
+            _max_header_bytes: max_header_bytes,
+            _max_uri_bytes: max_uri_bytes,
             _health_check_interval: health_check_interval,
             _function_process: function_process,
             _content_type: content_type,
             _inject_cgi_headers: INJECT_CGI_HEADERS,
+            _env_header_filter: env_header_filter,
             _operational_mode: operational_mode,
             _suppress_lock: suppress_lock,
+            _ready_file_path: parse_var(vars, KEY_READY_FILE_PATH),
             _upstream_url: upstream_url,
+            _http_upstream_allowlist: parse_var(vars, KEY_HTTP_UPSTREAM_ALLOWLIST),
+            _http_fork_process: parse_var(vars, KEY_HTTP_FORK_PROCESS)
+                .unwrap_or(DEFAULT_HTTP_FORK_PROCESS),
+            _http_fork_ready_timeout: Duration::from_secs(
+                parse_var(vars, KEY_HTTP_FORK_READY_TIMEOUT)
+                    .unwrap_or(DEFAULT_HTTP_FORK_READY_TIMEOUT_SEC),
+            ),
+            _http_healthcheck_path: http_healthcheck_path,
+            _http_healthcheck_interval: http_healthcheck_interval,
+            _max_process_restarts: parse_var(vars, KEY_MAX_PROCESS_RESTARTS)
+                .unwrap_or(DEFAULT_MAX_PROCESS_RESTARTS),
+            _restart_window: Duration::from_secs(
+                parse_var(vars, KEY_RESTART_WINDOW).unwrap_or(DEFAULT_RESTART_WINDOW_SEC),
+            ),
+            _child_rlimit_as_mb: parse_var(vars, KEY_CHILD_RLIMIT_AS_MB),
+            _child_rlimit_cpu: parse_var(vars, KEY_CHILD_RLIMIT_CPU),
             _static_path: static_path,
             _buffer_http_body: buffer_http_body,
-            _metrics_port: METRICS_PORT,
+            _metrics_port: metrics_port,
+            _function_name: parse_var(vars, KEY_FUNCTION_NAME),
+            _metrics_prefix: parse_var(vars, KEY_METRICS_PREFIX),
+            _duration_buckets: duration_buckets,
             _max_inflight: max_inflight,
             _prefix_logs: prefix_logs,
             _log_buffer_size: log_buffer_size,
+            _error_format: error_format,
+            _body_channel_capacity: body_channel_capacity,
+            _max_body_pumps: max_body_pumps,
+            _require_content_length: parse_var(vars, KEY_REQUIRE_CONTENT_LENGTH)
+                .unwrap_or(DEFAULT_REQUIRE_CONTENT_LENGTH),
+            _decompress_request: decompress_request,
+            _decompress_max_size: decompress_max_size,
+            _shutdown_reject_status: shutdown_reject_status,
+            _cors_max_age: parse_var(vars, KEY_CORS_MAX_AGE),
+            _options_passthrough: parse_var(vars, KEY_OPTIONS_PASSTHROUGH)
+                .unwrap_or(DEFAULT_OPTIONS_PASSTHROUGH),
+            _admin_port: admin_port,
+            _enable_reset_counters: parse_var(vars, KEY_ENABLE_RESET_COUNTERS)
+                .unwrap_or(DEFAULT_ENABLE_RESET_COUNTERS),
+            _listen_address: listen_address,
+            _root_page_file: parse_var(vars, KEY_ROOT_PAGE_FILE),
+            _get_default_response: parse_var(vars, KEY_GET_DEFAULT_RESPONSE),
+            _timeout_response_body: parse_var(vars, KEY_TIMEOUT_RESPONSE_BODY),
             _min_scale: parse_var(vars, KEY_MIN_SCALE),
             _max_scale: parse_var(vars, KEY_MAX_SCALE),
+            #[cfg(feature = "wasm")]
+            _burst_autoscale: parse_var(vars, KEY_BURST_AUTOSCALE)
+                .unwrap_or(DEFAULT_BURST_AUTOSCALE),
+            #[cfg(feature = "wasm")]
+            _burst_queue_threshold: parse_var(vars, KEY_BURST_QUEUE_THRESHOLD)
+                .unwrap_or(DEFAULT_BURST_QUEUE_THRESHOLD),
 
+            #[cfg(feature = "otel")]
+            _otel_endpoint: parse_var(vars, KEY_OTEL_ENDPOINT),
+
+            // the content type set by operator always wins
+            #[cfg(feature = "wasm")]
+            _sniff_content_type: !vars.contains_key(KEY_CONTENT_TYPE)
+                && parse_var(vars, KEY_SNIFF_CONTENT_TYPE).unwrap_or(DEFAULT_SNIFF_CONTENT_TYPE),
+            #[cfg(feature = "wasm")]
+            _parse_status_line: parse_var(vars, KEY_PARSE_STATUS_LINE)
+                .unwrap_or(DEFAULT_PARSE_STATUS_LINE),
+            #[cfg(feature = "wasm")]
+            _response_stream_threshold: response_stream_threshold,
+            #[cfg(feature = "wasm")]
+            _wasm_stream_stdout: parse_var(vars, KEY_WASM_STREAM_STDOUT)
+                .unwrap_or(DEFAULT_WASM_STREAM_STDOUT),
+            #[cfg(feature = "wasm")]
+            _stdin_eof_on_empty: parse_var(vars, KEY_STDIN_EOF_ON_EMPTY)
+                .unwrap_or(DEFAULT_STDIN_EOF_ON_EMPTY),
+            #[cfg(feature = "wasm")]
+            _stdin_max_chunk: stdin_max_chunk,
             #[cfg(feature = "wasm")]
             _wasm_root: parse_var(vars, KEY_WASM_ROOT),
             #[cfg(feature = "wasm")]
@@ -183,7 +551,62 @@ impl WatchdogConfig {
             #[cfg(feature = "wasm")]
             _wasm_c_cpu_features: parse_var(vars, KEY_WASM_C_CPU_FEATURES),
             #[cfg(feature = "wasm")]
+            _wasm_compiler: parse_var(vars, KEY_WASM_COMPILER),
+            #[cfg(feature = "wasm")]
+            _artifact_max_age_days: parse_var(vars, KEY_ARTIFACT_MAX_AGE_DAYS),
+            #[cfg(feature = "wasm")]
+            _wasm_capabilities: parse_var(vars, KEY_WASM_CAPABILITIES),
+            #[cfg(feature = "wasm")]
             _use_cuda: parse_var(vars, KEY_USE_CUDA),
+            #[cfg(feature = "wasm")]
+            _cuda_required: parse_var(vars, KEY_CUDA_REQUIRED),
+            #[cfg(feature = "wasm")]
+            _parse_query_params: parse_var(vars, KEY_PARSE_QUERY_PARAMS)
+                .unwrap_or(DEFAULT_PARSE_QUERY_PARAMS),
+            #[cfg(feature = "wasm")]
+            _max_env_value_bytes: max_env_value_bytes,
+            #[cfg(feature = "wasm")]
+            _start_return_as_status: parse_var(vars, KEY_START_RETURN_AS_STATUS)
+                .unwrap_or(DEFAULT_START_RETURN_AS_STATUS),
+            #[cfg(feature = "wasm")]
+            _wasm_entrypoint: parse_var(vars, KEY_WASM_ENTRYPOINT)
+                .unwrap_or_else(|| DEFAULT_WASM_ENTRYPOINT.to_string()),
+            #[cfg(feature = "wasm")]
+            _function_single_threaded: parse_var(vars, KEY_FUNCTION_SINGLE_THREADED)
+                .unwrap_or(DEFAULT_FUNCTION_SINGLE_THREADED),
+            #[cfg(feature = "wasm")]
+            _large_response_warn_bytes: large_response_warn_bytes,
+            #[cfg(feature = "wasm")]
+            _max_stderr_bytes: max_stderr_bytes,
+            #[cfg(feature = "wasm")]
+            _log_ring_size: parse_var(vars, KEY_LOG_RING_SIZE),
+            #[cfg(feature = "wasm")]
+            _log_stdout: parse_var(vars, KEY_LOG_STDOUT).unwrap_or(DEFAULT_LOG_STDOUT),
+            #[cfg(feature = "wasm")]
+            _warn_unread_body: parse_var(vars, KEY_WARN_UNREAD_BODY)
+                .unwrap_or(DEFAULT_WARN_UNREAD_BODY),
+            #[cfg(feature = "wasm")]
+            _wasm_per_request_tmp: wasm_per_request_tmp,
+            #[cfg(feature = "wasm")]
+            _wasm_tmp_path: wasm_tmp_path,
+            #[cfg(feature = "wasm")]
+            _wasm_map_dirs: wasm_map_dirs,
+            #[cfg(feature = "wasm")]
+            _wasm_worker_stack_size: wasm_worker_stack_size,
+            #[cfg(feature = "wasm")]
+            _wasm_warmup: parse_var(vars, KEY_WASM_WARMUP).unwrap_or(DEFAULT_WASM_WARMUP),
+            #[cfg(feature = "wasm")]
+            _wasm_max_queue: parse_var(vars, KEY_WASM_MAX_QUEUE),
+            #[cfg(feature = "wasm")]
+            _wasm_max_recent_panics: parse_var(vars, KEY_WASM_MAX_RECENT_PANICS),
+            #[cfg(feature = "wasm")]
+            _wasm_import_memory_pages: parse_var(vars, KEY_WASM_IMPORT_MEMORY_PAGES),
+            #[cfg(feature = "wasm")]
+            _wasm_import_memory_max_pages: parse_var(vars, KEY_WASM_IMPORT_MEMORY_MAX_PAGES),
+            #[cfg(feature = "wasm")]
+            _wasm_max_memory_pages: parse_var(vars, KEY_WASM_MAX_MEMORY_PAGES),
+            #[cfg(feature = "wasm")]
+            _wasm_fuel_limit: parse_var(vars, KEY_WASM_FUEL_LIMIT),
         })
     }
 }
@@ -202,6 +625,114 @@ where
     }
 }
 
+/// parse the header names separated by comma
+fn parse_header_names(s: &str, key: &'static str) -> Result<Vec<HeaderName>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|n| {
+            HeaderName::from_str(n)
+                .map_err(|_| anyhow!("\"{}\" in `{}` is not a valid header name", n, key))
+        })
+        .collect()
+}
+
+/// parse the increasing seconds separated by comma
+fn parse_buckets(s: &str) -> Option<Vec<f64>> {
+    let buckets = s
+        .split(',')
+        .map(|b| b.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return None;
+    }
+    Some(buckets)
+}
+
+/// parse the `guest_path:host_path[:ro|rw]` pairs separated by semicolon, at most `max_dirs` pairs,
+/// the guest paths must be absolute without `..` and not overlap each other or the temp directory
+#[cfg(feature = "wasm")]
+fn parse_map_dirs(s: &str, max_dirs: usize, tmp_path: Option<&str>) -> Result<Vec<MapDir>> {
+    let mut dirs: Vec<MapDir> = Vec::new();
+    for pair in s.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+        let (guest, host) = pair.split_once(':').ok_or_else(|| {
+            anyhow!(
+                "\"{}\" in `{}` is not a `guest_path:host_path` pair",
+                pair,
+                KEY_WASM_MAP_DIR
+            )
+        })?;
+        // the writable mode is still limited by the `fs_write` capability
+        let (host, writable) = match host.rsplit_once(':') {
+            Some((host, "ro")) => (host, false),
+            Some((host, "rw")) => (host, true),
+            _ => (host, true),
+        };
+        if !guest.starts_with('/') {
+            return Err(anyhow!(
+                "The guest path \"{}\" in `{}` must start with `/`",
+                guest,
+                KEY_WASM_MAP_DIR
+            ));
+        }
+        let guest_path = Path::new(guest);
+        if guest_path.components().any(|c| c == Component::ParentDir) {
+            return Err(anyhow!(
+                "The guest path \"{}\" in `{}` must not contain `..`",
+                guest,
+                KEY_WASM_MAP_DIR
+            ));
+        }
+        let overlapped = dirs
+            .iter()
+            .map(|d| d._guest.as_str())
+            .chain(tmp_path)
+            .find(|g| guest_path.starts_with(g) || Path::new(g).starts_with(guest_path));
+        if let Some(overlapped) = overlapped {
+            return Err(anyhow!(
+                "The guest path \"{}\" in `{}` overlaps \"{}\"",
+                guest,
+                KEY_WASM_MAP_DIR,
+                overlapped
+            ));
+        }
+        let host = PathBuf::from(host);
+        if !host.is_dir() {
+            return Err(anyhow!(
+                "The host path \"{}\" in `{}` is not an existing directory",
+                host.display(),
+                KEY_WASM_MAP_DIR
+            ));
+        }
+        dirs.push(MapDir {
+            _guest: guest.to_string(),
+            _host: host,
+            _writable: writable,
+        });
+        if dirs.len() > max_dirs {
+            return Err(anyhow!(
+                "`{}` has more than {} directories set by `{}`",
+                KEY_WASM_MAP_DIR,
+                max_dirs,
+                KEY_MAX_MAP_DIRS
+            ));
+        }
+    }
+    Ok(dirs)
+}
+
+impl FromStr for ErrorFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(anyhow!("unknown error format: {}", s)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::WatchdogConfig;
@@ -220,9 +751,19 @@ mod test {
             let cfg = WatchdogConfig::new(&env).expect("create default watchdog config error");
 
             assert_eq!(cfg._tcp_port, DEFAULT_PORT);
+            assert_eq!(cfg._tcp_nodelay, DEFAULT_TCP_NODELAY);
             assert_eq!(cfg._http_read_timeout.as_secs(), DEFAULT_READ_TIMEOUT_SEC);
             assert_eq!(cfg._http_write_timeout.as_secs(), DEFAULT_WRITE_TIMEOUT_SEC);
             assert_eq!(cfg._exec_timeout.as_secs(), DEFAULT_EXEC_TIMEOUT_SEC);
+            assert_eq!(
+                cfg._handshake_timeout.as_secs(),
+                DEFAULT_HANDSHAKE_TIMEOUT_SEC
+            );
+            assert_eq!(cfg._request_timeout.as_secs(), DEFAULT_REQUEST_TIMEOUT_SEC);
+            assert_eq!(cfg._queue_timeout.as_secs(), DEFAULT_QUEUE_TIMEOUT_SEC);
+            assert_eq!(cfg._join_timeout.as_secs(), DEFAULT_JOIN_TIMEOUT_SEC);
+            assert_eq!(cfg._max_header_bytes, None);
+            assert_eq!(cfg._max_uri_bytes, DEFAULT_MAX_URI_BYTES);
             assert_eq!(
                 cfg._health_check_interval.as_secs(),
                 DEFAULT_WRITE_TIMEOUT_SEC
@@ -230,25 +771,138 @@ mod test {
             assert_eq!(cfg._function_process, f_process);
             assert_eq!(cfg._content_type, DEFAULT_CONTENT_TYPE);
             assert_eq!(cfg._inject_cgi_headers, INJECT_CGI_HEADERS);
+            assert!(cfg._env_header_filter._allow.is_none());
+            assert!(cfg._env_header_filter._deny.is_empty());
             assert_eq!(cfg._operational_mode, DEFAULT_MODE);
             assert_eq!(cfg._suppress_lock, DEFAULT_SUPPRESS_LOCK);
+            assert_eq!(cfg._ready_file_path, None);
             assert_eq!(cfg._upstream_url, None);
+            assert_eq!(cfg._http_upstream_allowlist, None);
+            assert_eq!(cfg._http_fork_process, DEFAULT_HTTP_FORK_PROCESS);
+            assert_eq!(
+                cfg._http_fork_ready_timeout,
+                Duration::from_secs(DEFAULT_HTTP_FORK_READY_TIMEOUT_SEC)
+            );
+            assert_eq!(cfg._http_healthcheck_path, None);
+            assert_eq!(
+                cfg._http_healthcheck_interval,
+                Duration::from_secs_f64(DEFAULT_HTTP_HEALTHCHECK_INTERVAL_SEC)
+            );
+            assert_eq!(cfg._max_process_restarts, DEFAULT_MAX_PROCESS_RESTARTS);
+            assert_eq!(
+                cfg._restart_window,
+                Duration::from_secs(DEFAULT_RESTART_WINDOW_SEC)
+            );
+            assert_eq!(cfg._child_rlimit_as_mb, None);
+            assert_eq!(cfg._child_rlimit_cpu, None);
             assert_eq!(cfg._static_path, DEFAULT_STATIC_PATH);
             assert_eq!(cfg._buffer_http_body, DEFAULT_BUFFER_HTTP);
-            assert_eq!(cfg._metrics_port, METRICS_PORT);
+            assert_eq!(cfg._metrics_port, DEFAULT_METRICS_PORT);
+            assert_eq!(cfg._function_name, None);
+            #[cfg(feature = "otel")]
+            assert_eq!(cfg._otel_endpoint, None);
+            assert_eq!(cfg._metrics_prefix, None);
+            assert_eq!(cfg._duration_buckets, prometheus::DEFAULT_BUCKETS.to_vec());
             assert_eq!(cfg._max_inflight, DEFAULT_MAX_INFLIGHT);
             assert_eq!(cfg._prefix_logs, DEFAULT_PREFIX_LOGS);
             assert_eq!(cfg._log_buffer_size, DEFAULT_LOG_BUFFER_SIZE);
+            assert_eq!(cfg._error_format, DEFAULT_ERROR_FORMAT);
+            assert_eq!(cfg._body_channel_capacity, None);
+            assert_eq!(cfg._max_body_pumps, None);
+            assert_eq!(cfg._require_content_length, DEFAULT_REQUIRE_CONTENT_LENGTH);
+            assert_eq!(cfg._decompress_request, DEFAULT_DECOMPRESS_REQUEST);
+            assert_eq!(cfg._decompress_max_size, DEFAULT_DECOMPRESS_MAX_SIZE);
+            assert_eq!(cfg._shutdown_reject_status, DEFAULT_SHUTDOWN_REJECT_STATUS);
+            assert_eq!(cfg._cors_max_age, None);
+            assert_eq!(cfg._options_passthrough, DEFAULT_OPTIONS_PASSTHROUGH);
+            assert_eq!(cfg._admin_port, None);
+            assert_eq!(cfg._enable_reset_counters, DEFAULT_ENABLE_RESET_COUNTERS);
+            assert_eq!(
+                cfg._listen_address,
+                DEFAULT_LISTEN_ADDRESS.parse::<IpAddr>().unwrap()
+            );
+            assert_eq!(cfg._root_page_file, None);
+            assert_eq!(cfg._get_default_response, None);
+            assert_eq!(cfg._timeout_response_body, None);
             assert_eq!(cfg._min_scale, None);
             assert_eq!(cfg._max_scale, None);
             #[cfg(feature = "wasm")]
+            assert_eq!(cfg._burst_autoscale, DEFAULT_BURST_AUTOSCALE);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._burst_queue_threshold, DEFAULT_BURST_QUEUE_THRESHOLD);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._sniff_content_type, DEFAULT_SNIFF_CONTENT_TYPE);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._parse_status_line, DEFAULT_PARSE_STATUS_LINE);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._response_stream_threshold, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_stream_stdout, DEFAULT_WASM_STREAM_STDOUT);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._stdin_eof_on_empty, DEFAULT_STDIN_EOF_ON_EMPTY);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._stdin_max_chunk, None);
+            #[cfg(feature = "wasm")]
             assert_eq!(cfg._wasm_root, None);
             #[cfg(feature = "wasm")]
+            assert_eq!(cfg._artifact_max_age_days, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_capabilities, None);
+            #[cfg(feature = "wasm")]
             assert_eq!(cfg._use_cuda, None);
             #[cfg(feature = "wasm")]
+            assert_eq!(cfg._cuda_required, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._parse_query_params, DEFAULT_PARSE_QUERY_PARAMS);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._max_env_value_bytes, DEFAULT_MAX_ENV_VALUE_BYTES);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._start_return_as_status, DEFAULT_START_RETURN_AS_STATUS);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_entrypoint, DEFAULT_WASM_ENTRYPOINT);
+            #[cfg(feature = "wasm")]
+            assert_eq!(
+                cfg._function_single_threaded,
+                DEFAULT_FUNCTION_SINGLE_THREADED
+            );
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._large_response_warn_bytes, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._max_stderr_bytes, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._log_ring_size, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._log_stdout, DEFAULT_LOG_STDOUT);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._warn_unread_body, DEFAULT_WARN_UNREAD_BODY);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_per_request_tmp, DEFAULT_WASM_PER_REQUEST_TMP);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_tmp_path, DEFAULT_WASM_TMP_PATH);
+            #[cfg(feature = "wasm")]
+            assert!(cfg._wasm_map_dirs.is_empty());
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_worker_stack_size, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_warmup, DEFAULT_WASM_WARMUP);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_max_queue, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_max_recent_panics, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_import_memory_pages, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_import_memory_max_pages, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_max_memory_pages, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_fuel_limit, None);
+            #[cfg(feature = "wasm")]
             assert_eq!(cfg._wasm_c_target_triple, None);
             #[cfg(feature = "wasm")]
             assert_eq!(cfg._wasm_c_cpu_features, None);
+            #[cfg(feature = "wasm")]
+            assert_eq!(cfg._wasm_compiler, None);
         }
     }
 
@@ -268,6 +922,253 @@ mod test {
         assert_eq!(cfg._operational_mode, WatchdogMode::ModeStatic);
     }
 
+    #[test]
+    fn test_error_format() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_ERROR_FORMAT.to_string(), "json".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create json error format config error");
+        assert_eq!(cfg._error_format, ErrorFormat::Json);
+    }
+
+    #[test]
+    fn test_body_channel_capacity() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_BODY_CHANNEL_CAPACITY.to_string(), "2".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create body channel config error");
+        assert_eq!(cfg._body_channel_capacity, Some(2));
+
+        env.insert(KEY_BODY_CHANNEL_CAPACITY.to_string(), "0".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
+    #[test]
+    fn test_admin_port() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_ADMIN_PORT.to_string(), "8082".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create admin port config error");
+        assert_eq!(cfg._admin_port, Some(8082));
+
+        env.insert(KEY_ADMIN_PORT.to_string(), DEFAULT_METRICS_PORT.to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+        env.insert(KEY_ADMIN_PORT.to_string(), DEFAULT_PORT.to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
+    #[test]
+    fn test_http_healthcheck() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(
+            KEY_HTTP_HEALTHCHECK_PATH.to_string(),
+            "/healthz".to_string(),
+        );
+        env.insert(KEY_HTTP_HEALTHCHECK_INTERVAL.to_string(), "0.2".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create healthcheck config error");
+        assert_eq!(cfg._http_healthcheck_path.as_deref(), Some("/healthz"));
+        assert_eq!(cfg._http_healthcheck_interval, Duration::from_millis(200));
+
+        env.insert(KEY_HTTP_HEALTHCHECK_INTERVAL.to_string(), "0".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+        env.remove(KEY_HTTP_HEALTHCHECK_INTERVAL);
+        env.insert(KEY_HTTP_HEALTHCHECK_PATH.to_string(), "healthz".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
+    #[test]
+    fn test_metrics_port() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_METRICS_PORT.to_string(), "9091".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create metrics port config error");
+        assert_eq!(cfg._metrics_port, 9091);
+
+        // the admin port cannot take the custom metrics port
+        env.insert(KEY_ADMIN_PORT.to_string(), "9091".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+        env.remove(KEY_ADMIN_PORT);
+
+        env.insert(KEY_METRICS_PORT.to_string(), DEFAULT_PORT.to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+        env.insert(KET_PORT.to_string(), "9090".to_string());
+        env.insert(KEY_METRICS_PORT.to_string(), "9090".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
+    #[test]
+    fn test_listen_address() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_LISTEN_ADDRESS.to_string(), "127.0.0.1".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create listen address config error");
+        assert_eq!(cfg._listen_address, IpAddr::from([127, 0, 0, 1]));
+
+        env.insert(KEY_LISTEN_ADDRESS.to_string(), "::1".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create ipv6 listen address config error");
+        assert_eq!(cfg._listen_address, "::1".parse::<IpAddr>().unwrap());
+
+        env.insert(KEY_LISTEN_ADDRESS.to_string(), "localhost".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+        env.insert(KEY_LISTEN_ADDRESS.to_string(), "127.0.0.1:8080".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
+    #[test]
+    fn test_max_header_bytes() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_MAX_HEADER_BYTES.to_string(), "16k".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create max header bytes config error");
+        assert_eq!(cfg._max_header_bytes, Some(16 << 10));
+
+        // hyper cannot use the smaller buffer
+        env.insert(KEY_MAX_HEADER_BYTES.to_string(), "4k".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
+    #[test]
+    fn test_duration_buckets() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(
+            KEY_DURATION_BUCKETS.to_string(),
+            "0.0005, 0.001,10".to_string(),
+        );
+        let cfg = WatchdogConfig::new(&env).expect("create duration buckets config error");
+        assert_eq!(cfg._duration_buckets, vec![0.0005, 0.001, 10.0]);
+
+        for invalid in ["", "0.1,x", "1,0.5", "1,1"] {
+            env.insert(KEY_DURATION_BUCKETS.to_string(), invalid.to_string());
+            assert!(WatchdogConfig::new(&env).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "wasm")]
+    fn test_sniff_content_type() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_SNIFF_CONTENT_TYPE.to_string(), "true".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create sniff config error");
+        assert!(cfg._sniff_content_type);
+
+        env.insert(KEY_CONTENT_TYPE.to_string(), "text/plain".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create sniff config error");
+        assert!(!cfg._sniff_content_type);
+    }
+
+    #[test]
+    #[cfg(feature = "wasm")]
+    fn test_wasm_worker_stack_size() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(KEY_WASM_WORKER_STACK_SIZE.to_string(), "8M".to_string());
+        let cfg = WatchdogConfig::new(&env).expect("create stack size config error");
+        assert_eq!(cfg._wasm_worker_stack_size, Some(8 << 20));
+
+        env.insert(KEY_WASM_WORKER_STACK_SIZE.to_string(), "8X".to_string());
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "wasm")]
+    fn test_wasm_map_dir() {
+        let host = std::env::temp_dir().display().to_string();
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        let dirs = format!("/assets:{};/scratch:{};", host, host);
+        env.insert(KEY_WASM_MAP_DIR.to_string(), dirs);
+        let cfg = WatchdogConfig::new(&env).expect("create map dir config error");
+        assert_eq!(cfg._wasm_map_dirs.len(), 2);
+        assert_eq!(cfg._wasm_map_dirs[0]._guest, "/assets");
+        assert_eq!(cfg._wasm_map_dirs[1]._host, PathBuf::from(&host));
+        assert!(cfg._wasm_map_dirs[0]._writable);
+
+        // the read-only asset dir and the writable scratch dir
+        let dirs = format!("/assets:{}:ro;/scratch:{}:rw", host, host);
+        env.insert(KEY_WASM_MAP_DIR.to_string(), dirs);
+        let cfg = WatchdogConfig::new(&env).expect("create map dir config error");
+        assert_eq!(cfg._wasm_map_dirs[0]._host, PathBuf::from(&host));
+        assert!(!cfg._wasm_map_dirs[0]._writable);
+        assert_eq!(cfg._wasm_map_dirs[1]._host, PathBuf::from(&host));
+        assert!(cfg._wasm_map_dirs[1]._writable);
+
+        for dirs in [
+            format!("assets:{}", host),
+            format!("./assets:{}", host),
+            format!("/assets/../etc:{}", host),
+            "/assets:/nonexistent/dir".to_string(),
+            format!("/assets:{}:wo", host),
+            host.clone(),
+        ] {
+            env.insert(KEY_WASM_MAP_DIR.to_string(), dirs);
+            assert!(WatchdogConfig::new(&env).is_err());
+        }
+
+        // the overlapping guest paths are rejected
+        for dirs in [
+            format!("/assets:{};/assets/:{}", host, host),
+            format!("/assets:{};/assets/img:{}", host, host),
+            format!("/assets/img:{};/assets:{}", host, host),
+            format!("/:{};/assets:{}", host, host),
+        ] {
+            env.insert(KEY_WASM_MAP_DIR.to_string(), dirs);
+            let err = WatchdogConfig::new(&env).err().unwrap().to_string();
+            assert!(err.contains("overlaps"));
+        }
+        // not overlapping only by the common prefix
+        env.insert(
+            KEY_WASM_MAP_DIR.to_string(),
+            format!("/assets:{};/assets2:{}", host, host),
+        );
+        assert!(WatchdogConfig::new(&env).is_ok());
+
+        // nor the temp directory of every invocation
+        env.insert(KEY_WASM_MAP_DIR.to_string(), format!("/tmp:{}", host));
+        assert!(WatchdogConfig::new(&env).is_ok());
+        env.insert(KEY_WASM_PER_REQUEST_TMP.to_string(), "true".to_string());
+        let err = WatchdogConfig::new(&env).err().unwrap().to_string();
+        assert!(err.contains("overlaps \"/tmp\""));
+        env.remove(KEY_WASM_PER_REQUEST_TMP);
+
+        // at most `max_map_dirs` directories
+        env.insert(KEY_MAX_MAP_DIRS.to_string(), "1".to_string());
+        env.insert(
+            KEY_WASM_MAP_DIR.to_string(),
+            format!("/assets:{};/scratch:{}", host, host),
+        );
+        let err = WatchdogConfig::new(&env).err().unwrap().to_string();
+        assert!(err.contains(KEY_MAX_MAP_DIRS));
+    }
+
+    #[test]
+    fn test_env_header_filter() {
+        let mut env = HashMap::new();
+        env.insert(KEY_FUNC_NAME_1.to_string(), "process".to_string());
+        env.insert(
+            KEY_ENV_HEADER_ALLOWLIST.to_string(),
+            "X-Call-Id, Content-Type,".to_string(),
+        );
+        env.insert(
+            KEY_ENV_HEADER_DENYLIST.to_string(),
+            "Authorization".to_string(),
+        );
+        let cfg = WatchdogConfig::new(&env).expect("create header filter config error");
+        let filter = cfg._env_header_filter;
+        assert_eq!(filter._allow.as_ref().map(Vec::len), Some(2));
+        assert!(filter.allows(&HeaderName::from_static("x-call-id")));
+        assert!(!filter.allows(&HeaderName::from_static("authorization")));
+        assert!(!filter.allows(&HeaderName::from_static("cookie")));
+
+        env.insert(
+            KEY_ENV_HEADER_DENYLIST.to_string(),
+            "Bad Header".to_string(),
+        );
+        assert!(WatchdogConfig::new(&env).is_err());
+    }
+
     #[test]
     fn test_write_timeout_error() {
         let mut env = HashMap::new();
@@ -127,6 +127,49 @@ impl ReplicaFuncStatus {
     }
 }
 
+/// the error body of provider api
+pub(crate) struct ErrorResponse {
+    pub(crate) _error: String,
+    pub(crate) _request_id: Option<String>,
+}
+
+impl ErrorResponse {
+    const ERROR_KEY: &'static str = r#""error""#;
+    const REQUEST_ID_KEY: &'static str = r#""request_id""#;
+
+    pub(crate) fn new(error: String, request_id: Option<String>) -> Self {
+        Self {
+            _error: error,
+            _request_id: request_id,
+        }
+    }
+
+    #[allow(unused_assignments)]
+    pub(crate) fn into_json(self) -> String {
+        let mut json = String::new();
+        json.push_str(ReplicaFuncStatus::OBJECT_LEFT);
+        let mut is_first = true;
+
+        push_string!(
+            ReplicaFuncStatus,
+            json,
+            is_first,
+            Self::ERROR_KEY,
+            self._error.as_str()
+        );
+        push_option_string!(
+            ReplicaFuncStatus,
+            json,
+            is_first,
+            Self::REQUEST_ID_KEY,
+            self._request_id
+        );
+
+        json.push_str(ReplicaFuncStatus::OBJECT_RIGHT);
+        json
+    }
+}
+
 pub(crate) struct ScaleServiceRequest {
     pub(crate) _service_name: Option<String>,
     pub(crate) _replicas: u64,
@@ -192,6 +235,7 @@ impl ScaleServiceRequest {
 
 #[cfg(test)]
 mod test {
+    use super::ErrorResponse;
     use super::ReplicaFuncStatus;
     use super::ScaleServiceRequest;
     use anyhow::anyhow;
@@ -242,6 +286,15 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_error_to_json() {
+        let e = ErrorResponse::new("function \"f\" error".to_string(), None);
+        assert_eq!(e.into_json(), r#"{"error":"function \"f\" error"}"#);
+
+        let e = ErrorResponse::new("error".to_string(), Some("id".to_string()));
+        assert_eq!(e.into_json(), r#"{"error":"error","request_id":"id"}"#);
+    }
+
     #[test]
     fn test_scale_service_request() {
         assert!(ScaleServiceRequest::from_json(Err(anyhow!(""))).is_err());
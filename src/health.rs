@@ -65,5 +65,33 @@ pub(crate) fn check_healthy() -> bool {
 pub(crate) fn mark_unhealthy() -> Result<(), std::io::Error> {
     ACCEPTING_CONNECTIONS.store(false, Ordering::Release);
 
-    std::fs::remove_file(temp_dir().join(LOCK_FILE_NAME))
+    // every server marks it when the shutdown signal comes, the lock file is removed by the first
+    match std::fs::remove_file(temp_dir().join(LOCK_FILE_NAME)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// [```ReadyFile```]
+/// the file signaling the watchdog is ready to serve, which is removed when dropped
+pub(crate) struct ReadyFile {
+    _path: String,
+}
+
+impl ReadyFile {
+    pub(crate) fn create(path: &str) -> Result<Self> {
+        File::create(path).map_err(|e| anyhow!("Cannot write the ready file `{}`: {}", path, e))?;
+        info!("Writing ready-file to: {}", path);
+        Ok(Self {
+            _path: path.to_string(),
+        })
+    }
+}
+
+impl Drop for ReadyFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self._path) {
+            warn!("Cannot remove the ready file `{}`: {}", self._path, e);
+        }
+    }
 }
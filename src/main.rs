@@ -47,8 +47,11 @@ pub(crate) use contrib::*;
 pub(crate) use health::*;
 pub(crate) use utils::*;
 
-#[cfg(feature = "compiler")]
-use crate::runner::wasm_runner::{Compiler, KEY_WASM_C_CPU_FEATURES, KEY_WASM_C_TARGET_TRIPLE};
+#[cfg(feature = "wasm")]
+use crate::runner::wasm_runner::{
+    validate_module, Compiler, DEFAULT_WASM_ENTRYPOINT, KEY_USE_CUDA, KEY_WASM_COMPILER,
+    KEY_WASM_C_CPU_FEATURES, KEY_WASM_C_TARGET_TRIPLE, KEY_WASM_ENTRYPOINT, KEY_WASM_FUEL_LIMIT,
+};
 
 /// main function for watchdog
 fn main() {
@@ -93,7 +96,7 @@ fn run(args: &Vec<String>, env: &HashMap<String, String>) -> Result<()> {
         .ok_or(anyhow!("Cannot resolve the first argument"))?;
 
     match args.get(1).unwrap_or(&"".to_string()).as_str() {
-        #[cfg(feature = "compiler")]
+        #[cfg(feature = "compiler-base")]
         "-c" | "--compile" => {
             let in_file = args.get(2);
             let out_opt = args.get(3);
@@ -120,10 +123,54 @@ fn run(args: &Vec<String>, env: &HashMap<String, String>) -> Result<()> {
             }
             let triple = env.get(KEY_WASM_C_TARGET_TRIPLE).cloned();
             let cpu_features = env.get(KEY_WASM_C_CPU_FEATURES).cloned();
-            return Compiler::new(triple, cpu_features)?
+            let compiler = env.get(KEY_WASM_COMPILER).cloned();
+            let fuel_limit =
+                match env.get(KEY_WASM_FUEL_LIMIT) {
+                    Some(limit) => Some(limit.parse().map_err(|_| {
+                        anyhow!("\"{}\" is not a valid number", KEY_WASM_FUEL_LIMIT)
+                    })?),
+                    None => None,
+                };
+            return Compiler::new(triple, cpu_features, compiler, fuel_limit)?
                 .compile_to_file(in_file.unwrap(), out_file.unwrap());
         }
 
+        #[cfg(feature = "wasm")]
+        "--validate-wasm" => {
+            let in_file = match args.get(2) {
+                Some(f) => f,
+                None => {
+                    print_helper(bin_path);
+                    return Err(anyhow!(
+                        "The following required arguments were not provided:\n\
+                      <FILE>\n"
+                    ));
+                }
+            };
+            let triple = env.get(KEY_WASM_C_TARGET_TRIPLE).cloned();
+            let cpu_features = env.get(KEY_WASM_C_CPU_FEATURES).cloned();
+            let compiler = env.get(KEY_WASM_COMPILER).cloned();
+            let use_cuda = env
+                .get(KEY_USE_CUDA)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(false);
+
+            let entrypoint = env
+                .get(KEY_WASM_ENTRYPOINT)
+                .map(String::as_str)
+                .unwrap_or(DEFAULT_WASM_ENTRYPOINT);
+
+            let module = Compiler::new(triple, cpu_features, compiler, None)?
+                .load_without_cache(std::path::Path::new(in_file))?;
+            let wasi_version = validate_module(&module, use_cuda, entrypoint)
+                .map_err(|e| anyhow!("Validate `{}` fail: {}", in_file, e))?;
+            info!(
+                "Validate `{}` success, WASI version is `{}`",
+                in_file,
+                wasi_version.get_namespace_str()
+            );
+        }
+
         "-v" | "--version" => {
             print_version();
         }
@@ -182,10 +229,16 @@ fn print_version() {
 /// print the help message
 #[inline(always)]
 fn print_helper(bin_path: &String) {
-    #[cfg(feature = "compiler")]
-    println!("usage: {} [-c, --compile <IN_FILE> -o <OUT_FILE> ] [-v, --version] [-h, --help] [--run-healthcheck]", bin_path);
+    #[cfg(feature = "compiler-base")]
+    println!("usage: {} [-c, --compile <IN_FILE> -o <OUT_FILE> ] [--validate-wasm <FILE>] [-v, --version] [-h, --help] [--run-healthcheck]", bin_path);
 
-    #[cfg(not(feature = "compiler"))]
+    #[cfg(all(feature = "wasm", not(feature = "compiler-base")))]
+    println!(
+        "usage: {} [--validate-wasm <FILE>] [-v, --version] [-h, --help] [--run-healthcheck]",
+        bin_path
+    );
+
+    #[cfg(not(feature = "wasm"))]
     println!(
         "usage: {} [-v, --version] [-h, --help] [--run-healthcheck]",
         bin_path
@@ -193,11 +246,16 @@ fn print_helper(bin_path: &String) {
 
     println!("optional arguments:");
 
-    #[cfg(feature = "compiler")]
+    #[cfg(feature = "compiler-base")]
     println!(
         "  -c, --compile <IN_FILE> -o <OUT_FILE>    Compile the wasm module to dylib and exit."
     );
 
+    #[cfg(feature = "wasm")]
+    println!(
+        "      --validate-wasm <FILE>               Check the wasm module or compiled module without running it."
+    );
+
     println!("  -v, --version                            Print the version and exit.");
     println!("  -h, --help                               Print the help information and exit.");
     // for watchdog
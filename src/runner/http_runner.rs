@@ -1,15 +1,898 @@
-use crate::runner::Runner;
-use crate::WatchdogConfig;
-use anyhow::Result;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{Child, Command};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use anyhow::{anyhow, Result};
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderName, HOST};
+use hyper::http::{request, response, HeaderMap, HeaderValue};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use log::{debug, error, info, warn};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::runner::{Runner, RunnerError};
+use crate::{inject_environment, mark_unhealthy, parse_command, HeaderFilter, WatchdogConfig};
+
+/// the hop-by-hop headers which are not forwarded by proxies
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// the interval to check if the forked server accepts connections
+const READY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// the interval to check if the forked server has crashed
+const SUPERVISE_INTERVAL: Duration = Duration::from_millis(100);
+
+struct HttpRunnerEntry {
+    _client: Client<HttpConnector>,
+
+    /// the upstream url without the trailing slash
+    _upstream_url: Uri,
+
+    /// the max time to wait for the upstream response, zero means no limit
+    _read_timeout: Duration,
+
+    /// the max time to send a request body chunk to the upstream, zero means no limit
+    _write_timeout: Duration,
+
+    /// if forward the injected environment as headers
+    _inject_cgi_headers: bool,
+
+    /// the request headers forwarded in the injected environment
+    _env_header_filter: HeaderFilter,
+
+    /// the forked function process serving the upstream url
+    _forked: Option<Mutex<Child>>,
+
+    /// if the healthcheck path of upstream has responded 200, always true without the path
+    _healthy: AtomicBool,
+}
+
+impl Drop for HttpRunnerEntry {
+    fn drop(&mut self) {
+        if let Some(ref child) = self._forked {
+            let mut child = child.lock().unwrap();
+            if let Ok(None) = child.try_wait() {
+                info!("Kill the forked function process {}", child.id());
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+/// proxy the function request to the upstream http server
 #[derive(Clone)]
-pub(crate) struct HttpRunner;
+pub(crate) struct HttpRunner {
+    _inner: Arc<HttpRunnerEntry>,
+}
+
+impl Runner for HttpRunner {
+    fn run(
+        &self,
+        req_head: request::Parts,
+        req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+        _res_head: &mut response::Parts,
+    ) -> oneshot::Receiver<Result<Response<Body>>> {
+        let (sender, receiver) = oneshot::channel();
 
-impl Runner for HttpRunner {}
+        let runner = self.clone();
+        tokio::spawn(async move {
+            let result = runner.forward(req_head, req_body).await;
+            if sender.send(result).is_err() {
+                debug!("Client has disconnected, drop the upstream response");
+            }
+        });
+
+        receiver
+    }
+
+    fn is_ready(&self) -> bool {
+        if !self._inner._healthy.load(Ordering::Acquire) {
+            return false;
+        }
+        match self._inner._forked {
+            // not ready once the forked server exits
+            Some(ref child) => matches!(child.lock().unwrap().try_wait(), Ok(None)),
+            None => true,
+        }
+    }
+}
 
 impl HttpRunner {
     pub(crate) fn new(config: WatchdogConfig) -> Result<Self> {
-        eprintln!("{:?}", config);
-        todo!()
+        let url = config
+            ._upstream_url
+            .ok_or_else(|| anyhow!("The upstream url is not set"))?;
+        let upstream_url = Uri::from_str(url.trim_end_matches('/'))
+            .map_err(|e| anyhow!("Invalid upstream url `{}`: {}", url, e))?;
+        if upstream_url.scheme_str() != Some("http") || upstream_url.host().is_none() {
+            return Err(anyhow!(
+                "The upstream url `{}` must be an `http://host` url",
+                url
+            ));
+        }
+        info!("Http runner proxies to upstream `{}`", upstream_url);
+
+        let allowlist = match config._http_upstream_allowlist {
+            Some(ref list) => parse_allowlist(list),
+            // only the configured upstream
+            None => vec![authority_of(&upstream_url)],
+        };
+        if !is_allowed(&allowlist, &upstream_url) {
+            return Err(anyhow!(
+                "The upstream url `{}` is not in the allowlist {:?}",
+                upstream_url,
+                allowlist
+            ));
+        }
+
+        // the healthcheck decides the readiness instead of the port
+        let ready_timeout = match config._http_healthcheck_path {
+            Some(_) => None,
+            None => Some(config._http_fork_ready_timeout),
+        };
+        let forked = match config._http_fork_process {
+            true => Some(Mutex::new(fork_server(
+                &config._function_process,
+                &upstream_url,
+                ready_timeout,
+            )?)),
+            false => None,
+        };
+
+        let inner = Arc::new(HttpRunnerEntry {
+            _client: Client::new(),
+            _upstream_url: upstream_url,
+            _read_timeout: config._http_read_timeout,
+            _write_timeout: config._http_write_timeout,
+            _inject_cgi_headers: config._inject_cgi_headers,
+            _env_header_filter: config._env_header_filter.clone(),
+            _forked: forked,
+            _healthy: AtomicBool::new(config._http_healthcheck_path.is_none()),
+        });
+        let healthcheck = config
+            ._http_healthcheck_path
+            .map(|path| (path, config._http_healthcheck_interval));
+        if let Some((ref path, interval)) = healthcheck {
+            spawn_health_check(&inner, path.clone(), interval)?;
+        }
+        if config._http_fork_process {
+            let weak = Arc::downgrade(&inner);
+            let func_process = config._function_process.clone();
+            let history = CrashHistory::new(config._max_process_restarts, config._restart_window);
+            thread::Builder::new()
+                .name("http-supervisor".to_string())
+                .spawn(move || {
+                    if let Err(e) =
+                        supervise(weak, func_process, ready_timeout, healthcheck, history)
+                    {
+                        error!("{}", e);
+                        if let Err(e) = mark_unhealthy() {
+                            warn!("Cannot mark unhealthy: {}", e);
+                        }
+                        std::process::exit(1);
+                    }
+                })?;
+        }
+
+        Ok(Self { _inner: inner })
+    }
+
+    /// the upstream uri with the request path and query
+    fn upstream_uri(&self, req_uri: &Uri) -> Result<Uri> {
+        let base = self._inner._upstream_url.to_string();
+        let path_and_query = req_uri.path_and_query().map_or("/", |p| p.as_str());
+        Ok(Uri::from_str(&format!(
+            "{}{}",
+            base.trim_end_matches('/'),
+            path_and_query
+        ))?)
+    }
+
+    /// send the request to the upstream and return its response
+    async fn forward(
+        &self,
+        req_head: request::Parts,
+        req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+    ) -> Result<Response<Body>> {
+        if !self._inner._healthy.load(Ordering::Acquire) {
+            return Err(RunnerError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "The upstream is not healthy yet".to_string(),
+            )
+            .into());
+        }
+        let uri = self.upstream_uri(&req_head.uri)?;
+
+        let mut builder = Request::builder().method(req_head.method.clone()).uri(uri);
+        if let Some(headers) = builder.headers_mut() {
+            *headers = forwarded_headers(&req_head.headers);
+            if self._inner._inject_cgi_headers {
+                let filter = &self._inner._env_header_filter;
+                let env = inject_environment(false, &req_head, usize::MAX, filter);
+                for (k, v) in env {
+                    if let (Ok(k), Ok(v)) = (HeaderName::from_str(&k), HeaderValue::from_str(&v)) {
+                        headers.insert(k, v);
+                    }
+                }
+            }
+        }
+
+        let (body_sender, body) = Body::channel();
+        let write_timed_out = Arc::new(AtomicBool::new(false));
+        tokio::spawn(pump_body(
+            req_body,
+            body_sender,
+            self._inner._write_timeout,
+            write_timed_out.clone(),
+        ));
+
+        let read_timeout = self._inner._read_timeout;
+        let request = self._inner._client.request(builder.body(body)?);
+        let result = match read_timeout.is_zero() {
+            true => Ok(request.await),
+            false => tokio::time::timeout(read_timeout, request).await,
+        };
+        let response = match result {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) if write_timed_out.load(Ordering::Acquire) => {
+                return Err(RunnerError::new(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!(
+                        "Sending the request body to upstream timed out after {:?}",
+                        self._inner._write_timeout
+                    ),
+                )
+                .into());
+            }
+            Ok(Err(e)) => return Err(anyhow!("Cannot reach the upstream: {}", e)),
+            Err(_) => {
+                return Err(RunnerError::new(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!("The upstream did not respond in {:?}", read_timeout),
+                )
+                .into());
+            }
+        };
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers = forwarded_headers(&parts.headers);
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+/// fork the function process and wait until the upstream port accepts connections, `None` means no waiting
+fn fork_server(
+    func_process: &String,
+    upstream_url: &Uri,
+    ready_timeout: Option<Duration>,
+) -> Result<Child> {
+    let command = parse_command(func_process)?;
+    // the forked server inherits the environment and the stdio of watchdog
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .spawn()
+        .map_err(|e| anyhow!("Cannot fork the process `{}`: {}", command[0], e))?;
+    info!(
+        "Forked the function server `{}` with pid {}",
+        func_process,
+        child.id()
+    );
+    let ready_timeout = match ready_timeout {
+        Some(timeout) => timeout,
+        None => return Ok(child),
+    };
+
+    let host = upstream_url.host().unwrap_or_default();
+    let port = upstream_url.port_u16().unwrap_or(80);
+    let start_time = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!(
+                "The forked function server exited with {} before ready",
+                status
+            ));
+        }
+        let connected = (host, port)
+            .to_socket_addrs()?
+            .any(|addr| TcpStream::connect_timeout(&addr, READY_INTERVAL).is_ok());
+        if connected {
+            info!(
+                "The forked function server is ready in {} ms",
+                start_time.elapsed().as_millis()
+            );
+            return Ok(child);
+        }
+        if start_time.elapsed() >= ready_timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!(
+                "The forked function server does not listen on {}:{} in {:?}",
+                host,
+                port,
+                ready_timeout
+            ));
+        }
+        thread::sleep(READY_INTERVAL);
+    }
+}
+
+/// the crashes of the forked server in the restart window
+struct CrashHistory {
+    _max_restarts: usize,
+    _window: Duration,
+    /// the time and the exit status of every crash
+    _crashes: VecDeque<(Instant, String)>,
+}
+
+impl CrashHistory {
+    fn new(max_restarts: usize, window: Duration) -> Self {
+        Self {
+            _max_restarts: max_restarts,
+            _window: window,
+            _crashes: VecDeque::new(),
+        }
+    }
+
+    /// record the crash, return false if the crashes in the window are more than the max restarts
+    fn record(&mut self, status: String) -> bool {
+        let now = Instant::now();
+        while let Some((time, _)) = self._crashes.front() {
+            if now.duration_since(*time) <= self._window {
+                break;
+            }
+            self._crashes.pop_front();
+        }
+        self._crashes.push_back((now, status));
+        self._crashes.len() <= self._max_restarts
+    }
+
+    /// the crashes with their age, such as `exit status: 1 (2.1s ago)`
+    fn summary(&self) -> String {
+        self._crashes
+            .iter()
+            .map(|(time, status)| format!("{} ({:.1?} ago)", status, time.elapsed()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// restart the crashed forked server until the runner is dropped,
+/// fail once it crashes more than the max restarts in the window
+fn supervise(
+    inner: Weak<HttpRunnerEntry>,
+    func_process: String,
+    ready_timeout: Option<Duration>,
+    healthcheck: Option<(String, Duration)>,
+    mut history: CrashHistory,
+) -> Result<()> {
+    loop {
+        thread::sleep(SUPERVISE_INTERVAL);
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return Ok(()),
+        };
+        let child = match inner._forked {
+            Some(ref child) => child,
+            None => return Ok(()),
+        };
+        let status = match child.lock().unwrap().try_wait()? {
+            Some(status) => status,
+            None => continue,
+        };
+
+        warn!("The forked function server exited with {}", status);
+        if !history.record(status.to_string()) {
+            inner._healthy.store(false, Ordering::Release);
+            return Err(anyhow!(
+                "The forked function server crashed more than {} times in {:?}, give up! crash history: {}",
+                history._max_restarts,
+                history._window,
+                history.summary()
+            ));
+        }
+        inner
+            ._healthy
+            .store(healthcheck.is_none(), Ordering::Release);
+        // the failed restart is counted as another crash in the next check
+        match fork_server(&func_process, &inner._upstream_url, ready_timeout) {
+            Ok(restarted) => *child.lock().unwrap() = restarted,
+            Err(e) => {
+                warn!("Cannot restart the forked function server: {}", e);
+                continue;
+            }
+        }
+        if let Some((ref path, interval)) = healthcheck {
+            spawn_health_check(&inner, path.clone(), interval)?;
+        }
+    }
+}
+
+/// poll the healthcheck path in another thread
+fn spawn_health_check(
+    inner: &Arc<HttpRunnerEntry>,
+    path: String,
+    interval: Duration,
+) -> Result<()> {
+    let weak = Arc::downgrade(inner);
+    thread::Builder::new()
+        .name("http-healthcheck".to_string())
+        .spawn(move || poll_health(weak, path, interval))?;
+    Ok(())
+}
+
+/// poll the healthcheck path of upstream until it responds 200 or the runner is dropped
+fn poll_health(inner: Weak<HttpRunnerEntry>, path: String, interval: Duration) {
+    let start_time = Instant::now();
+    while let Some(inner) = inner.upgrade() {
+        let url = &inner._upstream_url;
+        let host = url.host().unwrap_or_default();
+        let port = url.port_u16().unwrap_or(80);
+        match probe_health(host, port, &path, interval) {
+            Ok(true) => {
+                info!(
+                    "The upstream `{}{}` is healthy in {} ms",
+                    url,
+                    path,
+                    start_time.elapsed().as_millis()
+                );
+                inner._healthy.store(true, Ordering::Release);
+                return;
+            }
+            Ok(false) => debug!("The upstream `{}{}` is not healthy yet", url, path),
+            Err(e) => debug!("Cannot check the upstream `{}{}`: {}", url, path, e),
+        }
+        drop(inner);
+        thread::sleep(interval);
+    }
+}
+
+/// send `GET path` to the upstream and check if the status is 200
+fn probe_health(host: &str, port: u16, path: &str, timeout: Duration) -> Result<bool> {
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Cannot resolve the host `{}`", host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}:{}\r\nConnection: close\r\n\r\n",
+        path, host, port
+    )?;
+
+    // only the status line `HTTP/1.x 200 OK` is needed
+    let mut head = [0u8; 16];
+    let mut len = 0;
+    while len < head.len() {
+        match stream.read(&mut head[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let status_line = String::from_utf8_lossy(&head[..len]);
+    Ok(status_line.split_whitespace().nth(1) == Some("200"))
+}
+
+/// parse the comma separated `host` or `host:port` list
+fn parse_allowlist(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|s| s.trim().to_ascii_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// the `host:port` of the uri, the http default port is 80
+fn authority_of(uri: &Uri) -> String {
+    format!(
+        "{}:{}",
+        uri.host().unwrap_or_default().to_ascii_lowercase(),
+        uri.port_u16().unwrap_or(80)
+    )
+}
+
+/// if the host of the uri is in the list, the entry without port allows any port
+fn is_allowed(allowlist: &[String], uri: &Uri) -> bool {
+    let authority = authority_of(uri);
+    let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+    allowlist.iter().any(|a| *a == authority || *a == host)
+}
+
+/// copy the headers except the hop-by-hop ones and the host of the watchdog
+fn forwarded_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut res = headers.clone();
+    res.remove(HOST);
+    for name in HOP_BY_HOP_HEADERS {
+        res.remove(name);
+    }
+    res
+}
+
+/// feed the request body to the upstream, abort it if a chunk cannot be sent in the timeout
+async fn pump_body(
+    mut req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+    mut sender: hyper::body::Sender,
+    write_timeout: Duration,
+    timed_out: Arc<AtomicBool>,
+) {
+    while let Some(chunk) = req_body.recv().await {
+        let data = match chunk {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Read the request body error: {}", e);
+                sender.abort();
+                return;
+            }
+        };
+        let sent = match write_timeout.is_zero() {
+            true => Ok(sender.send_data(data).await),
+            false => tokio::time::timeout(write_timeout, sender.send_data(data)).await,
+        };
+        match sent {
+            Ok(Ok(_)) => {}
+            // the upstream has closed the request
+            Ok(Err(_)) => return,
+            Err(_) => {
+                timed_out.store(true, Ordering::Release);
+                sender.abort();
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_allowed, parse_allowlist, CrashHistory, HttpRunner};
+    use crate::runner::{Runner, RunnerError};
+    use crate::WatchdogConfig;
+    use hyper::body::{to_bytes, Bytes};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// the upstream which echoes the request, and sleeps for the path `/slow`
+    fn mock_upstream(rt: &tokio::runtime::Runtime) -> SocketAddr {
+        // bind the listener in the runtime
+        let _guard = rt.enter();
+        let server =
+            Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service_fn(|_| async {
+                Ok::<_, hyper::Error>(service_fn(|req: Request<Body>| async move {
+                    if req.uri().path() == "/slow" {
+                        tokio::time::sleep(Duration::from_secs(3)).await;
+                    }
+                    let target = req.uri().to_string();
+                    let method = req.method().to_string();
+                    let call_id = req.headers().get("Http_X_Call_Id").cloned();
+                    let body = to_bytes(req.into_body()).await?;
+                    let mut res = Response::builder()
+                        .status(StatusCode::CREATED)
+                        .header("X-Target", target)
+                        .header("X-Method", method);
+                    if let Some(call_id) = call_id {
+                        res = res.header("X-Cgi-Call-Id", call_id);
+                    }
+                    Ok::<_, hyper::Error>(res.body(Body::from(body)).unwrap())
+                }))
+            }));
+        let addr = server.local_addr();
+        rt.spawn(server);
+        addr
+    }
+
+    fn new_runner(addr: SocketAddr, vars: &[(&str, &str)]) -> HttpRunner {
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "http".to_string());
+        env.insert("fprocess".to_string(), "server".to_string());
+        env.insert("upstream_url".to_string(), format!("http://{}/", addr));
+        for (k, v) in vars {
+            env.insert(k.to_string(), v.to_string());
+        }
+        HttpRunner::new(WatchdogConfig::new(&env).unwrap()).unwrap()
+    }
+
+    /// run the request with the body chunks through the runner
+    async fn run(
+        runner: &HttpRunner,
+        uri: &str,
+        chunks: &[&'static str],
+    ) -> anyhow::Result<Response<Body>> {
+        let (sender, receiver) = mpsc::channel(chunks.len().max(1));
+        for chunk in chunks {
+            sender.send(Ok(Bytes::from(*chunk))).await.unwrap();
+        }
+        drop(sender);
+        let req = Request::post(uri)
+            .header("X-Call-Id", "call-1")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        let mut res_head = Response::new(()).into_parts().0;
+        runner.run(req, receiver, &mut res_head).await.unwrap()
+    }
+
+    #[test]
+    fn test_proxy() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = mock_upstream(&rt);
+        let runner = new_runner(addr, &[]);
+
+        let res = rt
+            .block_on(run(&runner, "/path?a=1", &["hello ", "world"]))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()["X-Target"], "/path?a=1");
+        assert_eq!(res.headers()["X-Method"], "POST");
+        assert_eq!(res.headers()["X-Cgi-Call-Id"], "call-1");
+        let body = rt.block_on(to_bytes(res.into_body())).unwrap();
+        assert_eq!(body, "hello world");
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = mock_upstream(&rt);
+        let runner = new_runner(addr, &[("read_timeout", "1")]);
+
+        let err = rt.block_on(run(&runner, "/slow", &[])).unwrap_err();
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert_eq!(err._status, StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn test_upstream_allowlist() {
+        let allowlist = parse_allowlist(" Example.com, 127.0.0.1:3000 ,");
+        assert_eq!(allowlist, ["example.com", "127.0.0.1:3000"]);
+        let allowed = |uri: &str| is_allowed(&allowlist, &uri.parse().unwrap());
+        assert!(allowed("http://example.com:8080/"));
+        assert!(allowed("http://127.0.0.1:3000/path"));
+        assert!(!allowed("http://127.0.0.1:3001/path"));
+        assert!(!allowed("http://169.254.169.254/"));
+
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "http".to_string());
+        env.insert("fprocess".to_string(), "server".to_string());
+        env.insert(
+            "upstream_url".to_string(),
+            "http://127.0.0.1:3001/".to_string(),
+        );
+        // only the configured upstream by default
+        assert!(HttpRunner::new(WatchdogConfig::new(&env).unwrap()).is_ok());
+
+        env.insert(
+            "http_upstream_allowlist".to_string(),
+            "example.com".to_string(),
+        );
+        assert!(HttpRunner::new(WatchdogConfig::new(&env).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_upstream() {
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "http".to_string());
+        env.insert("fprocess".to_string(), "server".to_string());
+        env.insert(
+            "upstream_url".to_string(),
+            "https://example.com".to_string(),
+        );
+        assert!(HttpRunner::new(WatchdogConfig::new(&env).unwrap()).is_err());
+    }
+
+    /// the python echo server listening on the port of its first argument,
+    /// `/healthz` responds 200 after the seconds of the optional second argument
+    const ECHO_SERVER: &str = r#"
+import http.server, sys, time
+
+START = time.time()
+HEALTHY_AFTER = float(sys.argv[2]) if len(sys.argv) > 2 else 0
+
+class Handler(http.server.BaseHTTPRequestHandler):
+    def do_GET(self):
+        healthy = time.time() - START >= HEALTHY_AFTER
+        self.send_response(200 if healthy else 503)
+        self.send_header("Content-Length", "0")
+        self.end_headers()
+
+    def do_POST(self):
+        if self.headers.get("Transfer-Encoding") == "chunked":
+            body = b""
+            while True:
+                size = int(self.rfile.readline().strip(), 16)
+                if size == 0:
+                    self.rfile.readline()
+                    break
+                body += self.rfile.read(size)
+                self.rfile.readline()
+        else:
+            body = self.rfile.read(int(self.headers.get("Content-Length", 0)))
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body) + len(self.path)))
+        self.end_headers()
+        self.wfile.write(self.path.encode() + body)
+
+http.server.HTTPServer(("127.0.0.1", int(sys.argv[1])), Handler).serve_forever()
+"#;
+
+    /// write the echo server script and take a free port for it, none if python3 is unavailable
+    fn echo_server() -> Option<(std::path::PathBuf, SocketAddr)> {
+        let python = std::process::Command::new("python3")
+            .arg("--version")
+            .output();
+        if !python.map_or(false, |o| o.status.success()) {
+            eprintln!("Skip the test which forks the python3 echo server");
+            return None;
+        }
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // one script for every test, so the concurrent tests do not rewrite each other's
+        let script = std::env::temp_dir().join(format!("watchdog-echo-{}.py", addr.port()));
+        std::fs::write(&script, ECHO_SERVER).unwrap();
+        Some((script, addr))
+    }
+
+    #[test]
+    fn test_fork_process() {
+        let (script, addr) = match echo_server() {
+            Some(server) => server,
+            None => return,
+        };
+        let fprocess = format!("python3 {} {}", script.display(), addr.port());
+        let runner = new_runner(
+            addr,
+            &[("fprocess", &fprocess), ("http_fork_process", "true")],
+        );
+        assert!(runner.is_ready());
+
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let res = rt
+            .block_on(run(&runner, "/echo", &["hello ", "world"]))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = rt.block_on(to_bytes(res.into_body())).unwrap();
+        assert_eq!(body, "/echohello world");
+
+        // the forked server is killed with the runner
+        drop(runner);
+        assert!(std::net::TcpStream::connect(addr).is_err());
+        std::fs::remove_file(&script).unwrap();
+    }
+
+    #[test]
+    fn test_fork_process_exit() {
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "http".to_string());
+        env.insert("fprocess".to_string(), "false".to_string());
+        env.insert("upstream_url".to_string(), "http://127.0.0.1:1".to_string());
+        env.insert("http_fork_process".to_string(), "true".to_string());
+        let err = HttpRunner::new(WatchdogConfig::new(&env).unwrap())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("before ready"));
+    }
+
+    #[test]
+    fn test_healthcheck() {
+        let (script, addr) = match echo_server() {
+            Some(server) => server,
+            None => return,
+        };
+        let fprocess = format!("python3 {} {} 1", script.display(), addr.port());
+        let runner = new_runner(
+            addr,
+            &[
+                ("fprocess", &fprocess),
+                ("http_fork_process", "true"),
+                ("http_healthcheck_path", "/healthz"),
+                ("http_healthcheck_interval", "0.1"),
+            ],
+        );
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // the child is not healthy in the first second
+        assert!(!runner.is_ready());
+        let err = rt.block_on(run(&runner, "/echo", &[])).unwrap_err();
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert_eq!(err._status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let start = std::time::Instant::now();
+        while !runner.is_ready() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let res = rt.block_on(run(&runner, "/echo", &["ok"])).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = rt.block_on(to_bytes(res.into_body())).unwrap();
+        assert_eq!(body, "/echook");
+        std::fs::remove_file(&script).unwrap();
+    }
+
+    #[test]
+    fn test_crash_history() {
+        let mut history = CrashHistory::new(2, Duration::from_millis(200));
+        assert!(history.record("exit status: 1".to_string()));
+        assert!(history.record("exit status: 1".to_string()));
+        assert!(!history.record("exit status: 2".to_string()));
+        let summary = history.summary();
+        assert!(summary.starts_with("exit status: 1 ("));
+        assert!(summary.contains("exit status: 2 ("));
+
+        // the old crashes leave the window
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(history.record("exit status: 1".to_string()));
+    }
+
+    /// the watchdog exits when the forked server keeps crashing,
+    /// so the runner is created in a child test process
+    #[test]
+    fn test_crash_loop() {
+        if std::env::var("WATCHDOG_TEST_CRASH_LOOP").is_ok() {
+            let mut env = HashMap::new();
+            env.insert("mode".to_string(), "http".to_string());
+            env.insert("fprocess".to_string(), "false".to_string());
+            env.insert("upstream_url".to_string(), "http://127.0.0.1:1".to_string());
+            env.insert("http_fork_process".to_string(), "true".to_string());
+            // no waiting for the port of the crashing server
+            env.insert("http_healthcheck_path".to_string(), "/healthz".to_string());
+            env.insert("max_process_restarts".to_string(), "2".to_string());
+            let _runner = HttpRunner::new(WatchdogConfig::new(&env).unwrap()).unwrap();
+            // exiting with 0 fails the parent test
+            std::thread::sleep(Duration::from_secs(10));
+            return;
+        }
+
+        // the lock file removed when marking unhealthy is in its own temp dir
+        let dir =
+            std::env::temp_dir().join(format!("watchdog_test_crash_loop_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let start = std::time::Instant::now();
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "runner::http_runner::test::test_crash_loop"])
+            .env("WATCHDOG_TEST_CRASH_LOOP", "1")
+            .env("TMPDIR", &dir)
+            .status()
+            .unwrap();
+        assert_eq!(status.code(), Some(1));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -14,24 +14,64 @@ mod static_file_processor;
 /// for serial mode
 mod serializing_fork_runner;
 
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
 use anyhow::Result;
 use hyper::body::Bytes;
 use hyper::http::{request, response};
-use hyper::Body;
+use hyper::{Body, Response, StatusCode};
 use tokio::sync::{mpsc, oneshot};
 
+/// [```RunnerError```]
+/// the error of running function which responds the given status instead of 500
+#[derive(Debug)]
+pub(crate) struct RunnerError {
+    pub(crate) _status: StatusCode,
+    pub(crate) _message: String,
+}
+
+impl RunnerError {
+    pub(crate) fn new(status: StatusCode, message: String) -> Self {
+        Self {
+            _status: status,
+            _message: message,
+        }
+    }
+}
+
+impl Display for RunnerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self._message)
+    }
+}
+
+impl std::error::Error for RunnerError {}
+
 /// parse the request and run function and generate the response
 pub(crate) trait Runner {
-    /// run function request
+    /// run function request,
+    /// the status and headers of the result response are merged into the response head
     fn run(
         &self,
         _req_head: request::Parts,
         _req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
         _res_head: &mut response::Parts,
-    ) -> oneshot::Receiver<Result<Body>> {
+    ) -> oneshot::Receiver<Result<Response<Body>>> {
         todo!()
     }
 
+    /// if the runner is ready to serve requests
+    fn is_ready(&self) -> bool {
+        // default is always ready
+        true
+    }
+
+    /// get the recent log lines of function, `None` means not kept
+    fn recent_logs(&self) -> Option<Vec<String>> {
+        None
+    }
+
     /// get the scale number tuple: (now replicas, available replicas, invoke count)
     fn get_scale(&self) -> (usize, usize, usize) {
         // default is return zero
@@ -43,6 +83,18 @@ pub(crate) trait Runner {
         // default is do nothing
         Ok(())
     }
+
+    /// reset the counters such as the invoke count
+    fn reset_counters(&self) {
+        // default has no counters
+    }
+
+    /// wait for the running functions at most the timeout when shutting down, zero waits without limit,
+    /// return the number of workers still busy which are abandoned
+    fn join(&self, _timeout: Duration) -> usize {
+        // default has no workers to wait
+        0
+    }
 }
 
 pub(crate) use forking_runner::*;
@@ -1,15 +1,357 @@
-use crate::runner::Runner;
-use crate::WatchdogConfig;
-use anyhow::Result;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use anyhow::{anyhow, Result};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::http::{request, response, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use log::{debug, info, warn};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::runner::{Runner, RunnerError};
+use crate::{inject_environment, parse_command, HeaderFilter, WatchdogConfig};
+
+/// the interval to check if the forked process has exited
+const WAIT_INTERVAL: Duration = Duration::from_millis(5);
+
+struct SerializingForkRunnerEntry {
+    /// the command and arguments of function process
+    _func_process: Vec<String>,
+
+    /// response content type
+    _response_content_type: HeaderValue,
+
+    /// the max time of the process, zero means no limit
+    _exec_timeout: Duration,
+
+    /// if inject the environment
+    _inject_cgi_headers: bool,
+
+    /// the request headers injected as environment
+    _env_header_filter: HeaderFilter,
+
+    /// the max address space in MB of the process
+    #[cfg(unix)]
+    _rlimit_as_mb: Option<u64>,
+
+    /// the max cpu seconds of the process
+    #[cfg(unix)]
+    _rlimit_cpu: Option<u64>,
+}
+
+/// fork the function process for every request, the whole body is its stdin and the whole stdout is the response
 #[derive(Clone)]
-pub(crate) struct SerializingForkRunner;
+pub(crate) struct SerializingForkRunner {
+    _inner: Arc<SerializingForkRunnerEntry>,
+}
+
+impl Runner for SerializingForkRunner {
+    fn run(
+        &self,
+        req_head: request::Parts,
+        mut req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+        _res_head: &mut response::Parts,
+    ) -> oneshot::Receiver<Result<Response<Body>>> {
+        let (sender, receiver) = oneshot::channel();
 
-impl Runner for SerializingForkRunner {}
+        let runner = self.clone();
+        tokio::spawn(async move {
+            // buffer the entire request body before forking
+            let mut body = Vec::new();
+            while let Some(chunk) = req_body.recv().await {
+                match chunk {
+                    Ok(data) => body.extend_from_slice(&data),
+                    Err(e) => {
+                        let _ = sender.send(Err(e.into()));
+                        return;
+                    }
+                }
+            }
+
+            let result = tokio::task::spawn_blocking(move || runner.run_process(&req_head, body))
+                .await
+                .unwrap_or_else(|e| Err(anyhow!(e)));
+            if sender.send(result).is_err() {
+                debug!("Client has disconnected, drop the function result");
+            }
+        });
+
+        receiver
+    }
+}
 
 impl SerializingForkRunner {
     pub(crate) fn new(config: WatchdogConfig) -> Result<Self> {
-        eprintln!("{:?}", config);
-        todo!()
+        let func_process = parse_command(&config._function_process)?;
+        info!(
+            "Serializing fork runner runs `{}`",
+            config._function_process
+        );
+        #[cfg(not(unix))]
+        if config._child_rlimit_as_mb.is_some() || config._child_rlimit_cpu.is_some() {
+            warn!("The child resource limits are only supported on unix, ignore them");
+        }
+
+        Ok(Self {
+            _inner: Arc::new(SerializingForkRunnerEntry {
+                _func_process: func_process,
+                _response_content_type: config._content_type.parse()?,
+                _exec_timeout: config._exec_timeout,
+                _inject_cgi_headers: config._inject_cgi_headers,
+                _env_header_filter: config._env_header_filter,
+                #[cfg(unix)]
+                _rlimit_as_mb: config._child_rlimit_as_mb,
+                #[cfg(unix)]
+                _rlimit_cpu: config._child_rlimit_cpu,
+            }),
+        })
+    }
+
+    /// fork the process, write the body to its stdin and wait for the whole stdout
+    fn run_process(&self, req_head: &request::Parts, body: Vec<u8>) -> Result<Response<Body>> {
+        let start_time = Instant::now();
+        let func_process = &self._inner._func_process;
+
+        let mut command = Command::new(&func_process[0]);
+        command
+            .args(&func_process[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        // the forked process inherits the environment of watchdog
+        if self._inner._inject_cgi_headers {
+            let filter = &self._inner._env_header_filter;
+            command.envs(inject_environment(false, req_head, usize::MAX, filter));
+        }
+        #[cfg(unix)]
+        set_rlimits(
+            &mut command,
+            self._inner._rlimit_as_mb,
+            self._inner._rlimit_cpu,
+        );
+        let mut child = command
+            .spawn()
+            .map_err(|e| anyhow!("Cannot fork the process `{}`: {}", func_process[0], e))?;
+
+        // write and read in threads, so a large input or output does not block the process
+        let mut stdin = child.stdin.take().unwrap();
+        let writer = thread::spawn(move || stdin.write_all(&body));
+        let stdout = read_all(child.stdout.take().unwrap());
+        let stderr = read_all(child.stderr.take().unwrap());
+
+        let status = wait_timeout(&mut child, self._inner._exec_timeout);
+        if let Err(e) = writer.join().unwrap() {
+            debug!("The process does not read the whole stdin: {}", e);
+        }
+        let stdout = stdout.join().unwrap();
+        let stderr = String::from_utf8_lossy(&stderr.join().unwrap()).into_owned();
+
+        let status = match status? {
+            Some(status) => status,
+            None => {
+                warn!(
+                    "Kill the process `{}` after the exec timeout {:?}",
+                    func_process[0], self._inner._exec_timeout
+                );
+                return Err(RunnerError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "The function exceeded the exec timeout {:?}, stderr: {}",
+                        self._inner._exec_timeout, stderr
+                    ),
+                )
+                .into());
+            }
+        };
+        if !status.success() {
+            return Err(RunnerError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("The function exited with {}, stderr: {}", status, stderr),
+            )
+            .into());
+        }
+        if !stderr.is_empty() {
+            info!(
+                "Process `{}` stderr: {}",
+                func_process[0],
+                stderr.trim_end()
+            );
+        }
+        info!(
+            "Process `{}` took {} ms",
+            func_process[0],
+            start_time.elapsed().as_millis()
+        );
+
+        // the whole output is collected, so the length is known
+        let mut response = Response::new(Body::empty());
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, self._inner._response_content_type.clone());
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(stdout.len()));
+        *response.body_mut() = Body::from(stdout);
+        Ok(response)
+    }
+}
+
+/// limit the address space and the cpu time of the forked process before it execs the function
+#[cfg(unix)]
+fn set_rlimits(command: &mut Command, as_mb: Option<u64>, cpu_secs: Option<u64>) {
+    use std::os::unix::process::CommandExt;
+
+    if as_mb.is_none() && cpu_secs.is_none() {
+        return;
+    }
+    let limits = [
+        (
+            libc::RLIMIT_AS,
+            as_mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+        ),
+        (libc::RLIMIT_CPU, cpu_secs),
+    ];
+    // only the async-signal-safe `setrlimit` runs in the forked child
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in limits {
+                if let Some(limit) = limit {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(resource, &rlimit) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// read the pipe to the end in a thread
+fn read_all<R: Read + Send + 'static>(mut pipe: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+/// wait for the process to exit, kill it and return `None` after the timeout
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<std::process::ExitStatus>> {
+    if timeout.is_zero() {
+        return Ok(Some(child.wait()?));
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(WAIT_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SerializingForkRunner;
+    use crate::runner::{Runner, RunnerError};
+    use crate::WatchdogConfig;
+    use hyper::body::{to_bytes, Bytes};
+    use hyper::http::response;
+    use hyper::{Request, Response, StatusCode};
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+
+    fn new_runner(fprocess: &str, vars: &[(&str, &str)]) -> SerializingForkRunner {
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "serializing".to_string());
+        env.insert("fprocess".to_string(), fprocess.to_string());
+        for (k, v) in vars {
+            env.insert(k.to_string(), v.to_string());
+        }
+        SerializingForkRunner::new(WatchdogConfig::new(&env).unwrap()).unwrap()
+    }
+
+    /// run the request with the body chunks through the runner
+    fn run(
+        runner: &SerializingForkRunner,
+        chunks: &[&'static str],
+    ) -> anyhow::Result<(response::Parts, Bytes)> {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (sender, receiver) = mpsc::channel(chunks.len().max(1));
+            for chunk in chunks {
+                sender.send(Ok(Bytes::from(*chunk))).await.unwrap();
+            }
+            drop(sender);
+            let req = Request::new(()).into_parts().0;
+            let mut res_head = Response::new(()).into_parts().0;
+            let res = runner.run(req, receiver, &mut res_head).await.unwrap()?;
+            let (parts, body) = res.into_parts();
+            Ok((parts, to_bytes(body).await?))
+        })
+    }
+
+    #[test]
+    fn test_uppercase() {
+        let runner = new_runner("tr a-z A-Z", &[("content_type", "text/plain")]);
+        let (parts, body) = run(&runner, &["hello ", "world"]).unwrap();
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(parts.headers["Content-Type"], "text/plain");
+        assert_eq!(parts.headers["Content-Length"], "11");
+        assert_eq!(body, "HELLO WORLD");
+    }
+
+    #[test]
+    fn test_exec_timeout() {
+        let runner = new_runner("sleep 5", &[("exec_timeout", "1")]);
+        let start = std::time::Instant::now();
+        let err = run(&runner, &[]).unwrap_err();
+        assert!(start.elapsed() < std::time::Duration::from_secs(4));
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert_eq!(err._status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(err._message.contains("exec timeout"));
+    }
+
+    #[test]
+    fn test_failed_process() {
+        let runner = new_runner("ls /nonexistent-watchdog-dir", &[]);
+        let err = run(&runner, &[]).unwrap_err();
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert!(err._message.contains("nonexistent-watchdog-dir"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_rlimit_as() {
+        let python = std::process::Command::new("python3")
+            .arg("--version")
+            .output();
+        if !python.map_or(false, |o| o.status.success()) {
+            eprintln!("Skip the test which forks python3");
+            return;
+        }
+        // allocates 512 MB
+        let fprocess = "python3 -c bytearray(512*1024*1024)";
+        let runner = new_runner(fprocess, &[]);
+        assert!(run(&runner, &[]).is_ok());
+
+        let runner = new_runner(fprocess, &[("child_rlimit_as_mb", "128")]);
+        let err = run(&runner, &[]).unwrap_err();
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert_eq!(err._status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(err._message.contains("MemoryError"));
     }
 }
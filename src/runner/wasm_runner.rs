@@ -7,34 +7,105 @@ mod thread_pool;
 /// the virtual file system for stdin/stdout/stderr
 mod stdio;
 
+/// limit the memory of the instances
+mod tunables;
+
 use std::collections::HashMap;
+use std::future::{poll_fn, Future};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
 use hyper::body::Bytes;
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderMap, HeaderValue};
 use hyper::http::{request, response};
-use hyper::{Body, Error};
-use log::{debug, info};
+use hyper::{Body, Error, Response, StatusCode};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use prometheus::{exponential_buckets, Histogram, HistogramOpts};
+use tokio::runtime::Handle;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::oneshot;
-use wasmer_wasi::WasiState;
+use wasmer::{Function, ImportObject, Memory, MemoryType, Pages, WASM_PAGE_SIZE};
+use wasmer_middlewares::metering::{get_remaining_points, MeteringPoints};
+use wasmer_wasi::{get_wasi_version, WasiError, WasiState, WasiVersion};
 
-use super::Runner;
+use super::{Runner, RunnerError};
 use crate::*;
 pub(crate) use compiler::Compiler;
-use stdio::{Stderr, Stdin, Stdout};
+use stdio::{ClientGone, OutputHead, ResponseSender, Stderr, Stdin, Stdout, StdoutStream};
 use thread_pool::ThreadPool;
+pub(crate) use thread_pool::WORKER_PANICS_TOTAL;
 
 /// default use now file system as root
 pub(crate) const DEFAULT_WASM_ROOT: &str = "/";
 pub(crate) const KEY_WASM_ROOT: &str = "wasm_root";
+pub(crate) const KEY_WASM_MAP_DIR: &str = "wasm_map_dir";
+pub(crate) const KEY_MAX_MAP_DIRS: &str = "max_map_dirs";
+pub(crate) const DEFAULT_MAX_MAP_DIRS: usize = 16;
 pub(crate) const KEY_WASM_C_TARGET_TRIPLE: &str = "wasm_c_target";
 pub(crate) const KEY_WASM_C_CPU_FEATURES: &str = "wasm_c_cpu_features";
+pub(crate) const KEY_WASM_COMPILER: &str = "wasm_compiler";
+pub(crate) const KEY_ARTIFACT_MAX_AGE_DAYS: &str = "artifact_max_age_days";
+pub(crate) const KEY_SNIFF_CONTENT_TYPE: &str = "sniff_content_type";
+pub(crate) const DEFAULT_SNIFF_CONTENT_TYPE: bool = false;
+pub(crate) const KEY_PARSE_STATUS_LINE: &str = "parse_status_line";
+pub(crate) const DEFAULT_PARSE_STATUS_LINE: bool = false;
+pub(crate) const KEY_RESPONSE_STREAM_THRESHOLD: &str = "response_stream_threshold";
+pub(crate) const KEY_WASM_STREAM_STDOUT: &str = "wasm_stream_stdout";
+pub(crate) const DEFAULT_WASM_STREAM_STDOUT: bool = false;
+pub(crate) const KEY_STDIN_EOF_ON_EMPTY: &str = "stdin_eof_on_empty";
+pub(crate) const DEFAULT_STDIN_EOF_ON_EMPTY: bool = true;
+pub(crate) const KEY_STDIN_MAX_CHUNK: &str = "stdin_max_chunk";
+pub(crate) const KEY_WASM_WORKER_STACK_SIZE: &str = "wasm_worker_stack_size";
+pub(crate) const KEY_WASM_MAX_QUEUE: &str = "wasm_max_queue";
+pub(crate) const KEY_WASM_WARMUP: &str = "wasm_warmup";
+pub(crate) const DEFAULT_WASM_WARMUP: bool = false;
+pub(crate) const KEY_MAX_STDERR_BYTES: &str = "max_stderr_bytes";
+pub(crate) const KEY_LOG_RING_SIZE: &str = "log_ring_size";
+pub(crate) const KEY_LOG_STDOUT: &str = "log_stdout";
+pub(crate) const DEFAULT_LOG_STDOUT: bool = false;
+pub(crate) const KEY_WARN_UNREAD_BODY: &str = "warn_unread_body";
+pub(crate) const DEFAULT_WARN_UNREAD_BODY: bool = false;
+pub(crate) const KEY_WASM_PER_REQUEST_TMP: &str = "wasm_per_request_tmp";
+pub(crate) const DEFAULT_WASM_PER_REQUEST_TMP: bool = false;
+pub(crate) const KEY_WASM_TMP_PATH: &str = "wasm_tmp_path";
+pub(crate) const DEFAULT_WASM_TMP_PATH: &str = "/tmp";
+pub(crate) const KEY_WASM_MAX_RECENT_PANICS: &str = "wasm_max_recent_panics";
+const DEFAULT_WASM_MAX_RECENT_PANICS: usize = 10;
+pub(crate) const KEY_WASM_IMPORT_MEMORY_PAGES: &str = "wasm_import_memory_pages";
+pub(crate) const KEY_WASM_IMPORT_MEMORY_MAX_PAGES: &str = "wasm_import_memory_max_pages";
+pub(crate) const KEY_WASM_MAX_MEMORY_PAGES: &str = "wasm_max_memory_pages";
+pub(crate) const KEY_WASM_FUEL_LIMIT: &str = "wasm_fuel_limit";
+pub(crate) const KEY_WASM_CAPABILITIES: &str = "wasm_capabilities";
+pub(crate) const KEY_PARSE_QUERY_PARAMS: &str = "parse_query_params";
+pub(crate) const DEFAULT_PARSE_QUERY_PARAMS: bool = false;
+pub(crate) const KEY_START_RETURN_AS_STATUS: &str = "start_return_as_status";
+pub(crate) const DEFAULT_START_RETURN_AS_STATUS: bool = false;
+pub(crate) const KEY_WASM_ENTRYPOINT: &str = "wasm_entrypoint";
+pub(crate) const DEFAULT_WASM_ENTRYPOINT: &str = "_start";
+pub(crate) const KEY_BURST_AUTOSCALE: &str = "burst_autoscale";
+pub(crate) const DEFAULT_BURST_AUTOSCALE: bool = false;
+pub(crate) const KEY_BURST_QUEUE_THRESHOLD: &str = "burst_queue_threshold";
+pub(crate) const DEFAULT_BURST_QUEUE_THRESHOLD: usize = 0;
+pub(crate) const KEY_FUNCTION_SINGLE_THREADED: &str = "function_single_threaded";
+pub(crate) const DEFAULT_FUNCTION_SINGLE_THREADED: bool = false;
+pub(crate) const KEY_LARGE_RESPONSE_WARN_BYTES: &str = "large_response_warn_bytes";
+pub(crate) const KEY_MAX_ENV_VALUE_BYTES: &str = "max_env_value_bytes";
+pub(crate) const DEFAULT_MAX_ENV_VALUE_BYTES: usize = 64 << 10;
+/// the wasi errno returned by the functions of denied capabilities
+const ERRNO_NOTCAPABLE: i32 = 76;
+/// the time window of recent worker panics for readiness
+const PANIC_WINDOW: Duration = Duration::from_secs(60);
+/// the request header of the deadline in epoch milliseconds from the caller
+const X_DEADLINE: &str = "X-Deadline";
+/// the environment variable of the deadline in epoch milliseconds for the function
+const DEADLINE_ENV: &str = "Http_X_Deadline";
 const DEFAULT_MIN_SCALE: usize = 1;
 const DEFAULT_MAX_SCALE: usize = 4096;
 
@@ -42,6 +113,21 @@ const DEFAULT_MAX_SCALE: usize = 4096;
 #[cfg(feature = "wasm-cuda")]
 pub(crate) const DEFAULT_USE_CUDA: bool = false;
 pub(crate) const KEY_USE_CUDA: &str = "use_cuda";
+/// default is required if `use_cuda` is true
+pub(crate) const KEY_CUDA_REQUIRED: &str = "cuda_required";
+
+lazy_static! {
+    /// the linear memory size of wasm instances after running, registered by the metrics server
+    pub(crate) static ref WASM_INSTANCE_MEMORY_BYTES: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "wasm_instance_memory_bytes",
+            "the linear memory bytes of wasm instances after running"
+        )
+        // from one wasm page (64KiB) to 4GiB
+        .buckets(exponential_buckets(65536.0, 2.0, 17).unwrap())
+    )
+    .unwrap();
+}
 
 /// The data for wasm runner
 struct WasmRunnerEntry {
@@ -58,7 +144,46 @@ struct WasmRunnerEntry {
     _max_scale: usize,
 
     /// the count of invocation
-    _invoke_count: AtomicUsize,
+    _invoke_count: InvokeCount,
+
+    /// the queue depth over which the pool grows by itself, none means no burst autoscale
+    _burst_queue_threshold: Option<usize>,
+
+    /// the replicas set by scale or min scale, the pool shrinks back to it after a burst
+    _base_scale: AtomicUsize,
+
+    /// the max number of worker panics in recent time window before not ready, zero means no limit
+    _max_recent_panics: usize,
+
+    /// the execution timeout told to function as the deadline and responded 500 after, zero means no limit
+    _exec_timeout: Duration,
+
+    /// the max time of a job waiting in the queue, zero means no limit
+    _queue_timeout: Duration,
+
+    /// the (initial, max) pages of memory supplied to the module importing memory
+    _import_memory_pages: (Option<u32>, Option<u32>),
+
+    /// the cap of the memory pages of an instance, none means unlimited
+    _max_memory_pages: Option<u32>,
+
+    /// the fuel metered for an invocation, none means unlimited
+    _fuel_limit: Option<u64>,
+
+    /// the wasi capabilities allowed for the function
+    _capabilities: WasiCapabilities,
+
+    /// the response size over which a warning is logged
+    _large_response_warn_bytes: Option<usize>,
+
+    /// if the i32 returned by `_start` is the response status or exit code
+    _start_return_as_status: bool,
+
+    /// the exported function called for every invocation
+    _entrypoint: String,
+
+    /// the lock serializing the invocations if the function is not thread safe
+    _single_threaded: Option<Mutex<()>>,
 
     /// if log prefix has prefix
     _log_prefix: bool,
@@ -66,12 +191,48 @@ struct WasmRunnerEntry {
     /// log buffer size
     _log_buffer_size: usize,
 
+    /// the max stderr bytes to log for one invocation
+    _max_stderr_bytes: Option<usize>,
+
+    /// the recent stderr lines of all invocations
+    _log_ring: Option<LogRing>,
+
+    /// if also log the stdout of function
+    _log_stdout: bool,
+
+    /// if warn the request body which the function has not read
+    _warn_unread_body: bool,
+
     /// response content type
     _response_content_type: HeaderValue,
 
+    /// if guess the response content type from the function output
+    _sniff_content_type: bool,
+
+    /// if takes the leading status line of stdout as the response status
+    _parse_status_line: bool,
+
+    /// the output size over which the response is streamed, none means always buffered
+    _response_stream_threshold: Option<usize>,
+
+    /// if the empty request body reads as EOF
+    _stdin_eof_on_empty: bool,
+
+    /// the max bytes of one stdin read
+    _stdin_max_chunk: Option<usize>,
+
     /// if inject the environment
     _inject_cgi_headers: bool,
 
+    /// the request headers injected as environment
+    _env_header_filter: HeaderFilter,
+
+    /// if inject the query params as environment
+    _parse_query_params: bool,
+
+    /// the max bytes of an injected environment value
+    _max_env_value_bytes: usize,
+
     /// if use cuda
     #[cfg(feature = "wasm-cuda")]
     _use_cuda: bool,
@@ -79,8 +240,55 @@ struct WasmRunnerEntry {
     /// compiled wasm module
     _module: wasmer::Module,
 
-    /// workplace root directory
-    _wasm_root: PathBuf,
+    /// if the module imports wasi, else it cannot write the stdout
+    _is_wasi: bool,
+
+    /// the host directories preopened at the guest paths, the workplace root at `/` by default
+    _map_dirs: Vec<MapDir>,
+
+    /// the guest path of the fresh temp directory for every invocation
+    _per_request_tmp: Option<String>,
+}
+
+/// the count of invocation, which saturates at the max instead of wrapping to zero,
+/// so `/scale-reader` never sees the count going back unless it is reset
+#[derive(Default)]
+struct InvokeCount(AtomicUsize);
+
+impl InvokeCount {
+    fn increase(&self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1));
+    }
+
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn reset(&self) {
+        self.0.store(0, Ordering::Relaxed);
+    }
+}
+
+/// the fresh temp directory of one invocation, removed when dropped
+struct RequestTmpDir(PathBuf);
+
+impl RequestTmpDir {
+    fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("watchdog-tmp-{}", generate_call_id()));
+        std::fs::create_dir(&path)
+            .map_err(|e| anyhow!("Cannot create the temp dir {}: {}", path.display(), e))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for RequestTmpDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            warn!("Cannot remove the temp dir {}: {}", self.0.display(), e);
+        }
+    }
 }
 
 /// [```WasmRunner```]
@@ -96,36 +304,116 @@ impl Runner for WasmRunner {
         &self,
         req_head: request::Parts,
         req_body: Receiver<Result<Bytes, Error>>,
-        res_head: &mut response::Parts,
-    ) -> oneshot::Receiver<Result<Body>> {
-        // invoke count ++
-        self._inner._invoke_count.fetch_add(1, Ordering::Relaxed);
-
-        // set content type
-        res_head
-            .headers
-            .insert("Content-Type", self._inner._response_content_type.clone());
+        _res_head: &mut response::Parts,
+    ) -> oneshot::Receiver<Result<Response<Body>>> {
+        self._inner._invoke_count.increase();
 
         let (sender, receiver) = oneshot::channel();
 
         let runner = self.clone();
-        // run function in thread pool
-        self._inner._worker.execute(move || {
-            // send the run result
-            if sender.send(runner.run_inner(req_head, req_body)).is_err() {
-                error!("Cannot send run result because the receiver has dropped");
+        let queued_at = Instant::now();
+        // the worker sends the streamed body in the runtime
+        let runtime = Handle::try_current().ok();
+        let timeout_runtime = runtime.clone();
+        // run function in thread pool, unless too many requests are queued
+        let queued = self._inner._worker.try_execute(move || {
+            // the client has gone while the job is queued, no need to run the function
+            if sender.is_closed() {
+                debug!("Client has disconnected, skip running the function");
+                return;
+            }
+            // shed the load rather than run it too late
+            let queue_timeout = runner._inner._queue_timeout;
+            if !queue_timeout.is_zero() && queued_at.elapsed() > queue_timeout {
+                let err = RunnerError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("The request waited in queue over {:?}", queue_timeout),
+                );
+                let _ = sender.send(Err(err.into()));
+                return;
+            }
+
+            let sender: ResponseSender = Arc::new(Mutex::new(Some(sender)));
+            let stream = match (runner._inner._response_stream_threshold, runtime) {
+                (Some(threshold), Some(runtime)) => Some(
+                    StdoutStream::new(threshold, runner.output_head(), sender.clone(), runtime)
+                        .trap_on_client_gone(),
+                ),
+                _ => None,
+            };
+            // one invocation at a time for the function which is not thread safe
+            let _guard = runner
+                ._inner
+                ._single_threaded
+                .as_ref()
+                .map(|l| l.lock().unwrap_or_else(|e| e.into_inner()));
+            let result = runner.run_inner(req_head, req_body, stream);
+            if runner._inner._burst_queue_threshold.is_some() {
+                // keep at least one worker, else every invocation spawns a worker from zero
+                let base = runner._inner._base_scale.load(Ordering::Relaxed).max(1);
+                runner._inner._worker.shrink_after_burst(base);
+            }
+
+            // send the run result, unless the stdout has responded by streaming
+            let sender = sender.lock().unwrap().take();
+            match (sender, result) {
+                (Some(sender), result) => {
+                    if sender.send(result.map(Option::unwrap_or_default)).is_err() {
+                        debug!("Client has disconnected, drop the function result");
+                    }
+                }
+                // the function has been stopped quietly
+                (None, Err(e)) if e.is::<ClientGone>() => {}
+                (None, Err(e)) => error!("The streamed response is aborted: {}", e),
+                (None, Ok(_)) => {}
             }
         });
+        if queued.is_err() {
+            let (sender, receiver) = oneshot::channel();
+            let err = RunnerError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests are queued for the function".to_string(),
+            );
+            let _ = sender.send(Err(err.into()));
+            return receiver;
+        }
+
+        if let Some(threshold) = self._inner._burst_queue_threshold {
+            self._inner
+                ._worker
+                .grow_for_burst(threshold, self._inner._max_scale);
+        }
+
+        // return the result from thread pool, or the error once the execution timeout passes
+        let exec_timeout = self._inner._exec_timeout;
+        match timeout_runtime {
+            Some(runtime) if !exec_timeout.is_zero() => {
+                let (timeout_sender, timeout_receiver) = oneshot::channel();
+                runtime.spawn(forward_in_time(receiver, timeout_sender, exec_timeout));
+                timeout_receiver
+            }
+            _ => receiver,
+        }
+    }
 
-        // return the result from thread pool
-        receiver
+    /// not ready if the function panics the workers frequently
+    fn is_ready(&self) -> bool {
+        let max_recent_panics = self._inner._max_recent_panics;
+        max_recent_panics == 0
+            || self._inner._worker.recent_panic_num(PANIC_WINDOW) < max_recent_panics
     }
 
-    /// get the scale number tuple: (now replicas, available replicas, invoke count)
+    fn recent_logs(&self) -> Option<Vec<String>> {
+        self._inner._log_ring.as_ref().map(|r| r.lines())
+    }
+
+    /// get the scale number tuple: (now replicas, available replicas, invoke count),
+    /// the workers shrunk by scale are counted until their running invocations finish
     fn get_scale(&self) -> (usize, usize, usize) {
-        let replicas = self._inner._worker.thread_num();
-        let available_replicas = self._inner._max_scale - replicas;
-        let invocation_count = self._inner._invoke_count.load(Ordering::Relaxed);
+        let worker = &self._inner._worker;
+        let replicas = worker.thread_num().max(worker.live_thread_num());
+        let available_replicas = self._inner._max_scale.saturating_sub(replicas);
+        let invocation_count = self._inner._invoke_count.get();
 
         info!(
             "Read scale: Replicas=`{}`, Available Replicas=`{}`, Invocation Count=`{}`",
@@ -148,30 +436,261 @@ impl Runner for WasmRunner {
             ))
         } else {
             self._inner._worker.set_thread_num(replicas);
+            self._inner._base_scale.store(replicas, Ordering::Relaxed);
             info!("Wasm runner set the replicas to `{}`", replicas);
             Ok(())
         }
     }
+
+    fn reset_counters(&self) {
+        self._inner._invoke_count.reset();
+    }
+
+    fn join(&self, timeout: Duration) -> usize {
+        if !timeout.is_zero() {
+            let abandoned = self._inner._worker.join_timeout(timeout);
+            // the busy workers are abandoned with the process
+            if abandoned > 0 {
+                return abandoned;
+            }
+        }
+        // finish the queued jobs and stop the workers
+        self._inner._worker.shutdown();
+        0
+    }
+}
+
+/// create the memories which the module imports and add them to the import object,
+/// the pages default to the limits declared by the module.
+/// (wasi functions still need the module to export the memory as `memory`)
+fn supply_imported_memory(
+    module: &wasmer::Module,
+    import_object: &mut ImportObject,
+    initial_pages: Option<u32>,
+    max_pages: Option<u32>,
+) -> Result<()> {
+    for import in module.imports().memories() {
+        let ty = import.ty();
+        let minimum = initial_pages.map_or(ty.minimum, |p| Pages(p).max(ty.minimum));
+        let maximum = max_pages.map(Pages).or(ty.maximum);
+        let memory = Memory::new(module.store(), MemoryType::new(minimum, maximum, ty.shared))
+            .map_err(|e| {
+                anyhow!(
+                    "Cannot create the imported memory `{}`.`{}`: {}",
+                    import.module(),
+                    import.name(),
+                    e
+                )
+            })?;
+        debug!(
+            "Supply the imported memory `{}`.`{}` with {:?}",
+            import.module(),
+            import.name(),
+            memory.ty()
+        );
+
+        // keep the other imports in the same namespace
+        let mut exports = import_object
+            .get_namespace_exports(import.module())
+            .unwrap_or_default();
+        exports.insert(import.name(), memory);
+        import_object.register(import.module(), exports);
+    }
+    Ok(())
+}
+
+/// the classes of wasi capabilities which can be allowed for the function
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WasiCapabilities {
+    _fs_read: bool,
+    _fs_write: bool,
+    _env: bool,
+    _clock: bool,
+    _random: bool,
+}
+
+impl WasiCapabilities {
+    /// the access without `wasm_capabilities`, everything but writing the files
+    const DEFAULT: Self = Self {
+        _fs_read: true,
+        _fs_write: false,
+        _env: true,
+        _clock: true,
+        _random: true,
+    };
+
+    /// parse the allowlist separated by comma
+    fn parse(s: &str) -> Result<Self> {
+        let mut capabilities = Self {
+            _fs_read: false,
+            _fs_write: false,
+            _env: false,
+            _clock: false,
+            _random: false,
+        };
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            match name {
+                "fs_read" => capabilities._fs_read = true,
+                "fs_write" => capabilities._fs_write = true,
+                "env" => capabilities._env = true,
+                "clock" => capabilities._clock = true,
+                "random" => capabilities._random = true,
+                _ => {
+                    return Err(anyhow!(
+                        "Unknown capability `{}` in `{}`, the capabilities are \
+                            `fs_read`, `fs_write`, `env`, `clock` and `random`",
+                        name,
+                        KEY_WASM_CAPABILITIES
+                    ))
+                }
+            }
+        }
+        Ok(capabilities)
+    }
+}
+
+/// replace the wasi functions of the denied clock and random capabilities by the ones failing with `ENOTCAPABLE`
+fn deny_capabilities(
+    module: &wasmer::Module,
+    import_object: &mut ImportObject,
+    capabilities: WasiCapabilities,
+) {
+    let store = module.store();
+    for import in module.imports().functions() {
+        if !import.module().starts_with("wasi_") {
+            continue;
+        }
+        let function = match import.name() {
+            "clock_time_get" if !capabilities._clock => {
+                Function::new_native(store, |_: i32, _: i64, _: i32| ERRNO_NOTCAPABLE)
+            }
+            "clock_res_get" if !capabilities._clock => {
+                Function::new_native(store, |_: i32, _: i32| ERRNO_NOTCAPABLE)
+            }
+            "random_get" if !capabilities._random => {
+                Function::new_native(store, |_: i32, _: i32| ERRNO_NOTCAPABLE)
+            }
+            _ => continue,
+        };
+
+        let mut exports = import_object
+            .get_namespace_exports(import.module())
+            .unwrap_or_default();
+        exports.insert(import.name(), function);
+        import_object.register(import.module(), exports);
+    }
+}
+
+/// check if all the imports of module can be resolved by the runner and the entrypoint function exists,
+/// return the wasi version of module
+pub(crate) fn validate_module(
+    module: &wasmer::Module,
+    use_cuda: bool,
+    entrypoint: &str,
+) -> Result<WasiVersion> {
+    let wasi_version = get_wasi_version(module, false)
+        .ok_or_else(|| anyhow!("The module does not import a known WASI version"))?;
+
+    let mut wasi_env = WasiState::new("validate").finalize()?;
+    let mut import_object = wasi_env.import_object(module)?;
+    supply_imported_memory(module, &mut import_object, None, None)?;
+    #[cfg(feature = "wasm-cuda")]
+    if use_cuda {
+        wasmer_cuda::CudaEnv::default().add_to_import_object(module, &mut import_object);
+    }
+    #[cfg(not(feature = "wasm-cuda"))]
+    if use_cuda {
+        return Err(anyhow!(
+            "Cannot resolve the cuda imports! please enable `wasm-cuda` features"
+        ));
+    }
+
+    let unresolved = module
+        .imports()
+        .filter(|i| import_object.get_export(i.module(), i.name()).is_none())
+        .map(|i| format!("`{}`.`{}`", i.module(), i.name()))
+        .collect::<Vec<String>>();
+    if !unresolved.is_empty() {
+        return Err(anyhow!("Unresolved imports: {}", unresolved.join(", ")));
+    }
+
+    match module.exports().functions().any(|f| f.name() == entrypoint) {
+        true => Ok(wasi_version),
+        false => Err(missing_entrypoint(module, entrypoint)),
+    }
 }
 
 impl WasmRunner {
     /// create a new wasm runner
     pub(crate) fn new(config: WatchdogConfig) -> Result<Self> {
-        let wasm_root = PathBuf::from(env_get_or_warn!(
-            config._wasm_root,
-            KEY_WASM_ROOT,
-            DEFAULT_WASM_ROOT.to_string()
-        ));
+        let map_dirs = if config._wasm_map_dirs.is_empty() {
+            let wasm_root = PathBuf::from(env_get_or_warn!(
+                config._wasm_root,
+                KEY_WASM_ROOT,
+                DEFAULT_WASM_ROOT.to_string()
+            ));
+            vec![MapDir {
+                _guest: "/".to_string(),
+                _host: wasm_root,
+                _writable: true,
+            }]
+        } else {
+            if config._wasm_root.is_some() {
+                warn!(
+                    "The environment variable `{}` is set but not used with `{}`",
+                    KEY_WASM_ROOT, KEY_WASM_MAP_DIR
+                );
+            }
+            for dir in &config._wasm_map_dirs {
+                info!(
+                    "Map the directory {} to `{}`{}",
+                    dir._host.display(),
+                    dir._guest,
+                    if dir._writable { "" } else { " read-only" }
+                );
+            }
+            config._wasm_map_dirs
+        };
         let min_scale = env_get_or_warn!(config._min_scale, KEY_MIN_SCALE, DEFAULT_MIN_SCALE);
         let max_scale = env_get_or_warn!(config._max_scale, KEY_MAX_SCALE, DEFAULT_MAX_SCALE);
+        if max_scale == 0 || min_scale > max_scale {
+            return Err(anyhow!(
+                "`{}` must be over 0 and not less than `{}`",
+                KEY_MAX_SCALE,
+                KEY_MIN_SCALE
+            ));
+        }
+        if min_scale == 0 {
+            info!("No worker is started until the first invocation");
+        }
+        let max_recent_panics = env_get_or_warn!(
+            config._wasm_max_recent_panics,
+            KEY_WASM_MAX_RECENT_PANICS,
+            DEFAULT_WASM_MAX_RECENT_PANICS
+        );
 
         #[cfg(feature = "wasm-cuda")]
         let use_cuda = env_get_or_warn!(config._use_cuda, KEY_USE_CUDA, DEFAULT_USE_CUDA);
         #[cfg(feature = "wasm-cuda")]
         info!("Running Webassembly with cuda support = `{}`", use_cuda);
+        #[cfg(feature = "wasm-cuda")]
+        if config._cuda_required.is_some() && !use_cuda {
+            warn!(
+                "The environment variable `{}` is set but not used",
+                KEY_CUDA_REQUIRED
+            );
+        }
         #[cfg(not(feature = "wasm-cuda"))]
         if let Some(use_cuda) = config._use_cuda {
-            if use_cuda {
+            if use_cuda && config._cuda_required.unwrap_or(true) {
+                // fail the deployment rather than every request
+                return Err(anyhow!(
+                    "The environment variable `{}` is `true`, but this version cannot \
+                        support cuda! please enable `wasm-cuda` features or set `{}=false`",
+                    KEY_USE_CUDA,
+                    KEY_CUDA_REQUIRED
+                ));
+            } else if use_cuda {
                 log::error!(
                     "The environment variable `{}` is `true`, but this version cannot \
                         support cuda! please enable `wasm-cuda` features",
@@ -193,14 +712,59 @@ impl WasmRunner {
 
         let func_process = parse_command(&config._function_process)?;
 
+        let capabilities = match config._wasm_capabilities.as_deref() {
+            Some(s) => {
+                let capabilities = WasiCapabilities::parse(s)?;
+                info!("Set {} = `{:?}`", KEY_WASM_CAPABILITIES, capabilities);
+                capabilities
+            }
+            None => WasiCapabilities::DEFAULT,
+        };
+        if config._wasm_per_request_tmp && !capabilities._fs_write {
+            return Err(anyhow!(
+                "`{}` needs the `fs_write` capability in `{}`",
+                KEY_WASM_PER_REQUEST_TMP,
+                KEY_WASM_CAPABILITIES
+            ));
+        }
+
+        let response_content_type = config._content_type.parse().map_err(|_| {
+            anyhow!(
+                "\"{}\" is not a valid value of `content_type`",
+                config._content_type.escape_debug()
+            )
+        })?;
+
         let module_path = PathBuf::from(func_process[0].as_str());
         debug!("Webassembly module path is `{}`", module_path.display());
 
         let start_time = SystemTime::now();
-        let compiler = Compiler::new(config._wasm_c_target_triple, config._wasm_c_cpu_features)?;
+        let artifact_max_age = config
+            ._artifact_max_age_days
+            .map(|days| Duration::from_secs(days * 24 * 3600));
+        let compiler = Compiler::new(
+            config._wasm_c_target_triple,
+            config._wasm_c_cpu_features,
+            config._wasm_compiler,
+            config._wasm_fuel_limit,
+        )?
+        .artifact_max_age(artifact_max_age)
+        .max_memory_pages(config._wasm_max_memory_pages);
         let module = compiler.try_load_compiled(module_path)?;
+        match compiler.last_artifact() {
+            Some(artifact) => info!("Use the compiled artifact `{}`", artifact.display()),
+            None => info!("No compiled artifact is cached for this module"),
+        }
 
-        let thread_pool = ThreadPool::new(min_scale, Some(func_process[0].clone()), None);
+        if let Some(stack_size) = config._wasm_worker_stack_size {
+            info!("Set {} = `{}`", KEY_WASM_WORKER_STACK_SIZE, stack_size);
+        }
+        let thread_pool = ThreadPool::new(
+            min_scale,
+            Some(func_process[0].clone()),
+            config._wasm_worker_stack_size,
+            config._wasm_max_queue.filter(|n| *n > 0),
+        );
 
         let duration = SystemTime::now().duration_since(start_time).unwrap();
         info!(
@@ -210,72 +774,245 @@ impl WasmRunner {
             duration.as_millis()
         );
 
-        Ok(Self {
+        let warmup = config._wasm_warmup;
+        // stream every write of the function from the first byte
+        let response_stream_threshold = match config._wasm_stream_stdout {
+            true => {
+                if config._response_stream_threshold.is_some() {
+                    warn!(
+                        "The environment variable `{}` is set but not used with `{}`",
+                        KEY_RESPONSE_STREAM_THRESHOLD, KEY_WASM_STREAM_STDOUT
+                    );
+                }
+                Some(0)
+            }
+            false => config._response_stream_threshold,
+        };
+        let runner = Self {
             _inner: Arc::new(WasmRunnerEntry {
                 _worker: thread_pool,
                 _log_prefix: config._prefix_logs,
                 _log_buffer_size: log_buffer_size,
+                _max_stderr_bytes: config._max_stderr_bytes,
+                _log_ring: config._log_ring_size.filter(|n| *n > 0).map(LogRing::new),
+                _log_stdout: config._log_stdout,
+                _warn_unread_body: config._warn_unread_body,
                 _min_scale: min_scale,
                 _max_scale: max_scale,
-                _invoke_count: AtomicUsize::new(0),
+                _invoke_count: InvokeCount::default(),
+                _burst_queue_threshold: config
+                    ._burst_autoscale
+                    .then_some(config._burst_queue_threshold),
+                _base_scale: AtomicUsize::new(min_scale),
+                _max_recent_panics: max_recent_panics,
+                _exec_timeout: config._exec_timeout,
+                _queue_timeout: config._queue_timeout,
+                _import_memory_pages: (
+                    config._wasm_import_memory_pages,
+                    config._wasm_import_memory_max_pages,
+                ),
+                _max_memory_pages: config._wasm_max_memory_pages,
+                _fuel_limit: config._wasm_fuel_limit,
+                _capabilities: capabilities,
+                _large_response_warn_bytes: config._large_response_warn_bytes,
+                _parse_query_params: config._parse_query_params,
+                _max_env_value_bytes: config._max_env_value_bytes,
+                _start_return_as_status: config._start_return_as_status,
+                _entrypoint: config._wasm_entrypoint,
+                _single_threaded: config._function_single_threaded.then(|| Mutex::new(())),
                 _func_process: func_process,
-                _response_content_type: config._content_type.parse().unwrap(),
+                _response_content_type: response_content_type,
+                _sniff_content_type: config._sniff_content_type,
+                _parse_status_line: config._parse_status_line,
+                _response_stream_threshold: response_stream_threshold,
+                _stdin_eof_on_empty: config._stdin_eof_on_empty,
+                _stdin_max_chunk: config._stdin_max_chunk,
                 _inject_cgi_headers: config._inject_cgi_headers,
+                _env_header_filter: config._env_header_filter,
                 #[cfg(feature = "wasm-cuda")]
                 _use_cuda: use_cuda,
+                _is_wasi: get_wasi_version(&module, false).is_some(),
                 _module: module,
-                _wasm_root: wasm_root,
+                _map_dirs: map_dirs,
+                _per_request_tmp: config
+                    ._wasm_per_request_tmp
+                    .then(|| config._wasm_tmp_path.clone()),
             }),
-        })
+        };
+
+        if warmup {
+            let start_time = Instant::now();
+            let r = runner.clone();
+            runner._inner._worker.warmup(move || {
+                if let Err(e) = r.warm_up() {
+                    warn!(
+                        "Cannot warm up the worker {:?}: {}",
+                        thread::current().id(),
+                        e
+                    );
+                }
+            });
+            info!(
+                "Warm up {} workers took {} ms",
+                runner._inner._worker.thread_num(),
+                start_time.elapsed().as_millis()
+            );
+        }
+        Ok(runner)
+    }
+
+    /// instantiate the module without calling it, so the worker thread is initialized before serving
+    fn warm_up(&self) -> Result<()> {
+        let module = &self._inner._module;
+        if !self._inner._is_wasi {
+            return Err(stdout_error(&self._inner._func_process[0], false));
+        }
+
+        let mut wasi_env = WasiState::new(self._inner._func_process[0].as_str()).finalize()?;
+        let mut import_object = wasi_env.import_object(module)?;
+        let (initial_pages, max_pages) = self._inner._import_memory_pages;
+        supply_imported_memory(module, &mut import_object, initial_pages, max_pages)?;
+        #[cfg(feature = "wasm-cuda")]
+        if self._inner._use_cuda {
+            let cuda_env = wasmer_cuda::CudaEnv::default();
+            cuda_env.add_to_import_object(module, &mut import_object);
+        }
+        wasmer::Instance::new(module, &import_object)?;
+        Ok(())
+    }
+
+    /// how to make the response head from the output
+    fn output_head(&self) -> OutputHead {
+        OutputHead {
+            _content_type: self._inner._response_content_type.clone(),
+            _sniff_content_type: self._inner._sniff_content_type,
+            _parse_status_line: self._inner._parse_status_line,
+        }
     }
 
     /// run the function in thread pool
-    /// return the stdout as response body
+    /// return the stdout as response body, or none if the stdout has streamed it
     #[allow(unused_mut)]
-    pub(crate) fn run_inner(
+    fn run_inner(
         &self,
         req_head: request::Parts,
         req_body: Receiver<Result<Bytes, Error>>,
-    ) -> Result<Body> {
+        stream: Option<StdoutStream>,
+    ) -> Result<Option<Response<Body>>> {
         let start_time = SystemTime::now();
         let thread_id = thread::current().id();
         let func_process = &self._inner._func_process;
+        let call_id = req_head
+            .headers
+            .get(X_CALL_ID)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
 
         // get the environment from heads (wasm mode does not inherit the environment)
-        let environment = if self._inner._inject_cgi_headers {
-            inject_environment(false, &req_head)
+        let mut environment = if self._inner._inject_cgi_headers {
+            inject_environment(
+                false,
+                &req_head,
+                self._inner._max_env_value_bytes,
+                &self._inner._env_header_filter,
+            )
         } else {
             HashMap::new()
         };
+        if self._inner._parse_query_params {
+            if let Some(query) = req_head.uri.query() {
+                inject_query_params(&mut environment, query, self._inner._max_env_value_bytes);
+            }
+        }
+        // let the function limit its running time
+        if let Some(deadline) =
+            function_deadline(&req_head.headers, self._inner._exec_timeout, start_time)
+        {
+            environment.insert(DEADLINE_ENV.to_string(), deadline.to_string());
+        }
 
         // init the stdio for function
-        let stdin = Box::new(Stdin::new(req_body));
-        let stdout = Box::new(Stdout::new());
+        let stdin = Box::new(Stdin::new(
+            req_body,
+            self._inner._stdin_eof_on_empty,
+            self._inner._stdin_max_chunk,
+        ));
+        let logger_name = format!("{:?}-`{}`", thread_id, func_process[0]);
+        let stdout_log = self._inner._log_stdout.then(|| {
+            Stderr::new(
+                logger_name.clone(),
+                self._inner._log_prefix,
+                self._inner._log_buffer_size,
+                None,
+                self._inner._log_ring.clone(),
+            )
+            .with_line_prefix("stdout: ")
+        });
+        let stdout = Box::new(Stdout::new().with_stream(stream).with_log(stdout_log));
 
         let stderr = Box::new(Stderr::new(
-            format!("{:?}-`{}`", thread_id, func_process[0]),
+            logger_name,
             self._inner._log_prefix,
             self._inner._log_buffer_size,
+            self._inner._max_stderr_bytes,
+            self._inner._log_ring.clone(),
         ));
 
+        // the module without wasi has no stdout to read as the response
+        if !self._inner._is_wasi {
+            return Err(stdout_error(&func_process[0], false));
+        }
+
         // build the wasi environment
-        let mut wasi_env = WasiState::new(func_process[0].as_str())
+        let capabilities = self._inner._capabilities;
+        let mut wasi_state = WasiState::new(func_process[0].as_str());
+        wasi_state
             .args(&func_process[1..func_process.len()])
             .stdin(stdin)
             .stdout(stdout)
-            .stderr(stderr)
-            .envs(environment)
-            .env("PWD", "/")
-            .preopen(|p| {
-                p.directory(self._inner._wasm_root.as_path())
-                    .alias("/")
-                    .read(true)
-                    .write(false)
-                    .create(false)
-            })?
-            .finalize()?;
+            .stderr(stderr);
+        if capabilities._env {
+            wasi_state.envs(environment).env("PWD", "/");
+        }
+        if capabilities._fs_read || capabilities._fs_write {
+            for dir in &self._inner._map_dirs {
+                let writable = dir._writable && capabilities._fs_write;
+                wasi_state.preopen(|p| {
+                    p.directory(dir._host.as_path())
+                        .alias(&dir._guest)
+                        .read(capabilities._fs_read)
+                        .write(writable)
+                        .create(writable)
+                })?;
+            }
+        }
+        // the temp directory outlives the wasi environment, `fs_write` is checked at startup
+        let _tmp_dir = match self._inner._per_request_tmp {
+            Some(ref guest_path) => {
+                let dir = RequestTmpDir::new()?;
+                wasi_state.preopen(|p| {
+                    p.directory(dir.0.as_path())
+                        .alias(guest_path)
+                        .read(capabilities._fs_read)
+                        .write(capabilities._fs_write)
+                        .create(capabilities._fs_write)
+                })?;
+                Some(dir)
+            }
+            None => None,
+        };
+        let mut wasi_env = wasi_state.finalize()?;
 
         let mut import_object = wasi_env.import_object(&self._inner._module)?;
+        let (initial_pages, max_pages) = self._inner._import_memory_pages;
+        supply_imported_memory(
+            &self._inner._module,
+            &mut import_object,
+            initial_pages,
+            max_pages,
+        )?;
+        deny_capabilities(&self._inner._module, &mut import_object, capabilities);
 
         // init a cuda environment
         #[cfg(feature = "wasm-cuda")]
@@ -288,17 +1025,105 @@ impl WasmRunner {
         // instate the wasm
         let instance = wasmer::Instance::new(&self._inner._module, &import_object)?;
 
-        // get start function
-        let m = instance.exports.get_function("_start")?;
+        // get the entrypoint function
+        let entrypoint = self._inner._entrypoint.as_str();
+        let m = instance
+            .exports
+            .get_function(entrypoint)
+            .map_err(|_| missing_entrypoint(&self._inner._module, entrypoint))?;
 
         // call the start function
-        m.call(&[])?;
+        let result = m.call(&[]);
+        // the trap may leave the wasi state locked by the write, so it is not used any more
+        if let Err(ref e) = result {
+            if e.downcast_ref::<ClientGone>().is_some() {
+                debug!(
+                    "Call id `{}`: the client has gone, stop the function `{}`",
+                    call_id, func_process[0]
+                );
+                return Err(ClientGone.into());
+            }
+        }
+        if let (Err(_), Some(fuel_limit)) = (&result, self._inner._fuel_limit) {
+            if get_remaining_points(&instance) == MeteringPoints::Exhausted {
+                return Err(RunnerError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "The function ran out of fuel, the limit is {} set by `{}`",
+                        fuel_limit, KEY_WASM_FUEL_LIMIT
+                    ),
+                )
+                .into());
+            }
+        }
+
+        // free the body which the function does not read, rather than keep it until responded
+        if let Some(wasi_stdin) = wasi_env.state().fs.stdin_mut()? {
+            if let Some(wasi_stdin) = wasi_stdin.downcast_mut::<Stdin>() {
+                let unread = wasi_stdin.close();
+                if unread > 0 && self._inner._warn_unread_body {
+                    warn!(
+                        "Call id `{}`: the function `{}` does not read {} bytes of the request body",
+                        call_id, func_process[0], unread
+                    );
+                }
+            }
+        }
+
+        // the memory only grows, so its size is the high water mark
+        let memory_bytes: u64 = instance
+            .exports
+            .iter()
+            .memories()
+            .map(|(_, memory)| memory.data_size())
+            .sum();
+        WASM_INSTANCE_MEMORY_BYTES.observe(memory_bytes as f64);
+        // `proc_exit` unwinds the call with the exit code, exiting with zero is a normal return
+        let result = match result {
+            Err(e) => match e.downcast_ref::<WasiError>() {
+                Some(WasiError::Exit(0)) => Ok(Box::default()),
+                Some(WasiError::Exit(code)) => {
+                    let stderr = match wasi_env.state().fs.stderr_mut()? {
+                        Some(stderr) => stderr.downcast_mut::<Stderr>().map(|s| s.tail()),
+                        None => None,
+                    };
+                    Err(exit_error(*code, stderr.unwrap_or_default()))
+                }
+                // the function may trap since it cannot grow the memory over the cap
+                _ => Err(match self._inner._max_memory_pages {
+                    Some(max_pages) => anyhow!(
+                        "{} (the memory is capped at {} pages by `{}`, {} pages are used)",
+                        e,
+                        max_pages,
+                        KEY_WASM_MAX_MEMORY_PAGES,
+                        memory_bytes / WASM_PAGE_SIZE as u64
+                    ),
+                    None => e.into(),
+                }),
+            },
+            Ok(returned) => Ok(returned),
+        };
+        if result.is_err() {
+            if let Some(wasi_stdout) = wasi_env.state().fs.stdout_mut()? {
+                if let Some(wasi_stdout) = wasi_stdout.downcast_mut::<Stdout>() {
+                    wasi_stdout.abort();
+                }
+            }
+        }
+        let returned = result?;
+        // the non-standard `_start` may return a code
+        let status = match (self._inner._start_return_as_status, returned.first()) {
+            (true, Some(wasmer::Value::I32(code))) => status_of_return(*code)
+                .map_err(|e| RunnerError::new(StatusCode::INTERNAL_SERVER_ERROR, e))?,
+            _ => None,
+        };
 
         let duration = SystemTime::now().duration_since(start_time).unwrap();
         info!(
-            "{:?} run function `{}` took {} us  ({} ms)",
+            "{:?} run function `{}` (call id `{}`) took {} us  ({} ms)",
             thread_id,
             func_process[0],
+            call_id,
             duration.as_micros(),
             duration.as_millis()
         );
@@ -306,9 +1131,909 @@ impl WasmRunner {
         // read stdout to response body
         if let Some(wasi_stdout_box) = wasi_env.state().fs.stdout_mut()? {
             if let Some(wasi_stdout) = wasi_stdout_box.downcast_mut::<Stdout>() {
-                return Ok(Body::from(wasi_stdout.take_buffer()));
+                if let Some(warning) = large_response_warning(
+                    &func_process[0],
+                    wasi_stdout.written(),
+                    self._inner._large_response_warn_bytes,
+                ) {
+                    warn!("Call id `{}`: {}", call_id, warning);
+                }
+                let mut response = wasi_stdout.take_response(&self.output_head());
+                match (status, response.as_mut()) {
+                    (Some(status), Some(response)) => *response.status_mut() = status,
+                    (Some(status), None) => warn!(
+                        "Call id `{}`: the response is streamed, the returned status {} is ignored",
+                        call_id, status
+                    ),
+                    _ => {}
+                }
+                return Ok(response);
             }
         }
-        Err(anyhow!("Cannot find the wasi `stdout` handler"))
+        Err(stdout_error(&func_process[0], true))
+    }
+}
+
+/// the status for the code returned by `_start`,
+/// zero keeps the response, an http status code is the status, the others are failed exit codes
+fn status_of_return(code: i32) -> std::result::Result<Option<StatusCode>, String> {
+    match code {
+        0 => Ok(None),
+        100..=599 => Ok(StatusCode::from_u16(code as u16).ok()),
+        _ => Err(format!("The function exited with code {}", code)),
+    }
+}
+
+/// the error for the function exiting with the non-zero code, with its last stderr output
+fn exit_error(code: u32, stderr: String) -> anyhow::Error {
+    let message = match stderr.is_empty() {
+        true => format!("The function exited with code {}", code),
+        false => format!(
+            "The function exited with code {}, stderr:\n{}",
+            code, stderr
+        ),
+    };
+    RunnerError::new(StatusCode::INTERNAL_SERVER_ERROR, message).into()
+}
+
+/// the error for the entrypoint which is not exported, with the exported functions to choose
+fn missing_entrypoint(module: &wasmer::Module, entrypoint: &str) -> anyhow::Error {
+    let functions = module
+        .exports()
+        .functions()
+        .map(|f| format!("`{}`", f.name()))
+        .collect::<Vec<String>>();
+    anyhow!(
+        "Cannot find the exported function `{}`, please set `{}` to one of the exported functions: [{}]",
+        entrypoint,
+        KEY_WASM_ENTRYPOINT,
+        functions.join(", ")
+    )
+}
+
+/// the error for the function output which cannot be read
+fn stdout_error(function: &str, is_wasi: bool) -> anyhow::Error {
+    match is_wasi {
+        true => anyhow!(
+            "Cannot find the wasi `stdout` handler of `{}`, the function may close or renumber the fd 1",
+            function
+        ),
+        false => anyhow!(
+            "The module `{}` does not import WASI, so its stdout cannot be read as the response, \
+            please build it for a wasi target such as `wasm32-wasi`",
+            function
+        ),
+    }
+}
+
+/// the warning for the response larger than the threshold
+fn large_response_warning(function: &str, size: usize, threshold: Option<usize>) -> Option<String> {
+    match threshold {
+        Some(threshold) if size > threshold => Some(format!(
+            "The function `{}` responds {} bytes, more than {} bytes",
+            function, size, threshold
+        )),
+        _ => None,
+    }
+}
+
+/// forward the function result, or respond 500 if it does not come in the timeout.
+/// the running wasm instance cannot be interrupted, so its worker stays busy until it returns
+async fn forward_in_time(
+    mut receiver: oneshot::Receiver<Result<Response<Body>>>,
+    mut sender: oneshot::Sender<Result<Response<Body>>>,
+    timeout: Duration,
+) {
+    // stop waiting once the client has gone, so the queued job is skipped
+    let result = poll_fn(|cx| {
+        if sender.poll_closed(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        Pin::new(&mut receiver).poll(cx).map(Some)
+    });
+    let result = match tokio::time::timeout(timeout, result).await {
+        Ok(Some(Ok(result))) => result,
+        // the client has gone or the worker drops the result
+        Ok(_) => return,
+        Err(_) => {
+            warn!(
+                "The function does not finish in the exec timeout {:?}, its worker keeps busy until it returns",
+                timeout
+            );
+            Err(RunnerError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("The function timed out after {:?}", timeout),
+            )
+            .into())
+        }
+    };
+    if sender.send(result).is_err() {
+        debug!("Client has disconnected, drop the function result");
+    }
+}
+
+/// get the deadline in epoch milliseconds from the execution timeout and the caller's deadline, the earlier wins
+fn function_deadline(
+    headers: &HeaderMap,
+    exec_timeout: Duration,
+    start: SystemTime,
+) -> Option<u64> {
+    let timeout_deadline = match exec_timeout.is_zero() {
+        true => None,
+        false => Some(
+            (start + exec_timeout)
+                .duration_since(UNIX_EPOCH)
+                .ok()?
+                .as_millis() as u64,
+        ),
+    };
+    let header_deadline = headers
+        .get(X_DEADLINE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    match (timeout_deadline, header_deadline) {
+        (Some(t), Some(h)) => Some(t.min(h)),
+        (t, h) => t.or(h),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        function_deadline, large_response_warning, status_of_return, stdout_error, InvokeCount,
+        RequestTmpDir, WasiCapabilities,
+    };
+    #[cfg(feature = "compiler-base")]
+    use super::{validate_module, Compiler, WasmRunner, WASM_INSTANCE_MEMORY_BYTES};
+    #[cfg(feature = "compiler-base")]
+    use crate::WatchdogConfig;
+    use hyper::HeaderMap;
+    #[cfg(feature = "compiler-base")]
+    use hyper::Request;
+    #[cfg(feature = "compiler-base")]
+    use std::collections::HashMap;
+    #[cfg(feature = "compiler-base")]
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    #[cfg(not(feature = "wasm-cuda"))]
+    fn test_cuda_required() {
+        use super::WasmRunner;
+        use crate::WatchdogConfig;
+        use std::collections::HashMap;
+
+        let new_runner = |vars: &[(&str, &str)]| {
+            let mut env = HashMap::new();
+            env.insert("mode".to_string(), "wasm".to_string());
+            env.insert("fprocess".to_string(), "/nonexistent/func.wasm".to_string());
+            for (k, v) in vars {
+                env.insert(k.to_string(), v.to_string());
+            }
+            WasmRunner::new(WatchdogConfig::new(&env).unwrap())
+                .err()
+                .unwrap()
+                .to_string()
+        };
+
+        assert!(new_runner(&[("use_cuda", "true")]).contains("wasm-cuda"));
+        let vars = [("use_cuda", "true"), ("cuda_required", "true")];
+        assert!(new_runner(&vars).contains("wasm-cuda"));
+        // go on without cuda, and fail for the missing module instead
+        let vars = [("use_cuda", "true"), ("cuda_required", "false")];
+        assert!(!new_runner(&vars).contains("wasm-cuda"));
+    }
+
+    #[test]
+    fn test_invalid_content_type() {
+        use super::WasmRunner;
+        use crate::WatchdogConfig;
+        use std::collections::HashMap;
+
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "wasm".to_string());
+        env.insert("fprocess".to_string(), "/nonexistent/func.wasm".to_string());
+        env.insert("content_type".to_string(), "text/\nplain".to_string());
+
+        // fail before loading the module instead of panicking
+        let err = WasmRunner::new(WatchdogConfig::new(&env).unwrap())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("content_type"));
+        assert!(err.contains("text/\\nplain"));
+    }
+
+    #[test]
+    fn test_function_deadline() {
+        let start = UNIX_EPOCH + Duration::from_millis(1_000_000);
+        let timeout = Duration::from_secs(10);
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(function_deadline(&headers, Duration::ZERO, start), None);
+        assert_eq!(function_deadline(&headers, timeout, start), Some(1_010_000));
+
+        // the earlier deadline wins
+        headers.insert("X-Deadline", "1005000".parse().unwrap());
+        assert_eq!(function_deadline(&headers, timeout, start), Some(1_005_000));
+        headers.insert("X-Deadline", "1020000".parse().unwrap());
+        assert_eq!(function_deadline(&headers, timeout, start), Some(1_010_000));
+        assert_eq!(
+            function_deadline(&headers, Duration::ZERO, start),
+            Some(1_020_000)
+        );
+
+        // ignore the invalid header
+        headers.insert("X-Deadline", "soon".parse().unwrap());
+        assert_eq!(function_deadline(&headers, timeout, start), Some(1_010_000));
+    }
+
+    #[cfg(feature = "compiler-base")]
+    /// build a wasm module which exports `_start` and imports the given functions with type `() -> ()`
+    fn build_module(imports: &[(&str, &str)]) -> Vec<u8> {
+        fn section(id: u8, contents: Vec<u8>) -> Vec<u8> {
+            let mut s = vec![id, contents.len() as u8];
+            s.extend(contents);
+            s
+        }
+        fn name(n: &str) -> Vec<u8> {
+            let mut s = vec![n.len() as u8];
+            s.extend(n.as_bytes());
+            s
+        }
+
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        // type 0: () -> ()
+        module.extend(section(1, vec![1, 0x60, 0, 0]));
+        let mut import_section = vec![imports.len() as u8];
+        for (m, n) in imports {
+            import_section.extend(name(m));
+            import_section.extend(name(n));
+            import_section.extend([0, 0]);
+        }
+        module.extend(section(2, import_section));
+        module.extend(section(3, vec![1, 0]));
+        let mut export_section = vec![1];
+        export_section.extend(name("_start"));
+        export_section.extend([0, imports.len() as u8]);
+        module.extend(section(7, export_section));
+        module.extend(section(10, vec![1, 2, 0, 0x0b]));
+        module
+    }
+
+    /// write the module to a new temp dir and create the runner for it
+    #[cfg(feature = "compiler-base")]
+    fn new_runner(name: &str, module: Vec<u8>, vars: &[(&str, &str)]) -> (WasmRunner, PathBuf) {
+        // the stale compiled file would be loaded instead
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("func.wasm");
+        std::fs::write(&path, module).unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "wasm".to_string());
+        env.insert("fprocess".to_string(), path.display().to_string());
+        for (k, v) in vars {
+            env.insert(k.to_string(), v.to_string());
+        }
+        let runner = WasmRunner::new(WatchdogConfig::new(&env).unwrap()).unwrap();
+        (runner, dir)
+    }
+
+    /// run the function with empty request
+    #[cfg(feature = "compiler-base")]
+    fn run_empty(runner: &WasmRunner) -> anyhow::Result<()> {
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        drop(sender);
+        let req = Request::new(()).into_parts().0;
+        runner.run_inner(req, receiver, None).map(|_| ())
+    }
+
+    /// imports `sched_yield` as the function 0, exports one page memory and the function 1 with
+    /// the type 0 `() -> ()` or 1 `() -> i32`, whose code has no locals
+    #[cfg(feature = "compiler-base")]
+    fn wasi_module(export_name: &str, func_type: u8, code: &[u8]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend([1, 8, 2, 0x60, 0, 0, 0x60, 0, 1, 0x7f]);
+        module.extend([2, 38, 1, 22]);
+        module.extend(b"wasi_snapshot_preview1\x0bsched_yield\0\x01");
+        module.extend([3, 2, 1, func_type]);
+        module.extend([5, 3, 1, 0, 1]);
+        module.extend([7, 13 + export_name.len() as u8, 2, 6]);
+        module.extend(b"memory\x02\0");
+        module.push(export_name.len() as u8);
+        module.extend(export_name.as_bytes());
+        module.extend([0, 1]);
+        module.extend([10, code.len() as u8 + 3, 1, code.len() as u8 + 1, 0]);
+        module.extend(code);
+        module
+    }
+
+    /// imports a wasi function, exports one page memory, and `_start` grows it by 3 pages
+    #[cfg(feature = "compiler-base")]
+    fn grow_module() -> Vec<u8> {
+        wasi_module("_start", 0, &[0x41, 3, 0x40, 0, 0x1a, 0x0b])
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_queue_timeout() {
+        use crate::runner::{Runner, RunnerError};
+        use hyper::{Response, StatusCode};
+        use std::time::Duration;
+
+        let vars = [("queue_timeout", "1")];
+        let (runner, dir) = new_runner("watchdog_test_queue_timeout", grow_module(), &vars);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let run = || {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            drop(sender);
+            let req = Request::new(()).into_parts().0;
+            let mut res_head = Response::new(()).into_parts().0;
+            rt.block_on(runner.run(req, receiver, &mut res_head))
+                .unwrap()
+        };
+
+        // the only worker is busy longer than the queue timeout
+        runner
+            ._inner
+            ._worker
+            .execute(|| std::thread::sleep(Duration::from_millis(1500)));
+        let err = run().unwrap_err();
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert_eq!(err._status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // the free worker runs it in time
+        assert!(run().is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_exec_timeout() {
+        use crate::runner::{Runner, RunnerError};
+        use hyper::{Response, StatusCode};
+        use std::time::{Duration, Instant};
+
+        // `_start` reads 8 bytes from stdin, which blocks until the body ends
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend([
+            1, 12, 2, 0x60, 4, 0x7f, 0x7f, 0x7f, 0x7f, 1, 0x7f, 0x60, 0, 0,
+        ]);
+        module.extend([2, 34, 1, 22]);
+        module.extend(b"wasi_snapshot_preview1\x07fd_read\0\0");
+        module.extend([3, 2, 1, 1]);
+        module.extend([5, 3, 1, 0, 1]);
+        module.extend([7, 19, 2, 6]);
+        module.extend(b"memory\x02\0\x06_start\0\x01");
+        // the iovec {8, 8} at 0, then fd_read(0, 0, 1, 16)
+        module.extend([10, 29, 1, 27, 0, 0x41, 0, 0x41, 8, 0x36, 2, 0]);
+        module.extend([0x41, 4, 0x41, 8, 0x36, 2, 0]);
+        module.extend([0x41, 0, 0x41, 0, 0x41, 1, 0x41, 16, 0x10, 0, 0x1a, 0x0b]);
+
+        let vars = [("exec_timeout", "1")];
+        let (runner, dir) = new_runner("watchdog_test_exec_timeout", module, &vars);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let run = |sender_kept: bool| {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            let sender = sender_kept.then_some(sender);
+            let req = Request::new(()).into_parts().0;
+            let mut res_head = Response::new(()).into_parts().0;
+            let result = rt.block_on(runner.run(req, receiver, &mut res_head));
+            (result.unwrap(), sender)
+        };
+
+        // the client gets the error instead of hanging with the blocked function
+        let start = Instant::now();
+        let (result, sender) = run(true);
+        let err = result.unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(3));
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert_eq!(err._status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(err._message.contains("timed out"));
+
+        // ending the body releases the only worker for the next invocation
+        drop(sender);
+        assert!(run(false).0.is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_client_gone() {
+        use crate::runner::Runner;
+        use hyper::body::HttpBody;
+        use hyper::Response;
+        use std::time::Duration;
+
+        // `_start` writes to stdout in a loop which ignores the errors
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend([
+            1, 12, 2, 0x60, 4, 0x7f, 0x7f, 0x7f, 0x7f, 1, 0x7f, 0x60, 0, 0,
+        ]);
+        module.extend([2, 35, 1, 22]);
+        module.extend(b"wasi_snapshot_preview1\x08fd_write\0\0");
+        module.extend([3, 2, 1, 1]);
+        module.extend([5, 3, 1, 0, 1]);
+        module.extend([7, 19, 2, 6]);
+        module.extend(b"memory\x02\0\x06_start\0\x01");
+        // the iovec {8, 4} at 0, then fd_write(1, 0, 1, 16) forever
+        module.extend([10, 34, 1, 32, 0, 0x41, 0, 0x41, 8, 0x36, 2, 0]);
+        module.extend([0x41, 4, 0x41, 4, 0x36, 2, 0, 0x03, 0x40]);
+        module.extend([0x41, 1, 0x41, 0, 0x41, 1, 0x41, 16, 0x10, 0, 0x1a]);
+        module.extend([0x0c, 0, 0x0b, 0x0b]);
+
+        let vars = [("wasm_stream_stdout", "true")];
+        let (runner, dir) = new_runner("watchdog_test_client_gone", module, &vars);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let res = rt.block_on(async {
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            drop(sender);
+            let req = Request::new(()).into_parts().0;
+            let mut res_head = Response::new(()).into_parts().0;
+            runner.run(req, receiver, &mut res_head).await.unwrap()
+        });
+        let mut body = res.unwrap().into_body();
+        assert!(rt.block_on(body.data()).unwrap().is_ok());
+
+        // the client drops the response mid-stream, then the function is stopped
+        drop(body);
+        assert_eq!(
+            runner._inner._worker.join_timeout(Duration::from_secs(5)),
+            0
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_fuel_limit() {
+        // `_start` calls `sched_yield` in a loop which only ends when the fuel runs out
+        let module = wasi_module(
+            "_start",
+            0,
+            &[0x03, 0x40, 0x10, 0, 0x1a, 0x0c, 0, 0x0b, 0x0b],
+        );
+
+        let vars = [("wasm_fuel_limit", "100000")];
+        let (runner, dir) = new_runner("watchdog_test_fuel_limit", module, &vars);
+        for _ in 0..2 {
+            let err = run_empty(&runner).unwrap_err();
+            let err = err.downcast_ref::<crate::runner::RunnerError>().unwrap();
+            assert_eq!(err._status, hyper::StatusCode::INTERNAL_SERVER_ERROR);
+            assert!(err._message.contains("ran out of fuel"));
+        }
+        // the metered module is cached apart from the unmetered one
+        assert!(std::fs::read_dir(&dir).unwrap().any(|f| f
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .contains("fuel-100000")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_wasm_map_dir() {
+        // `_start` opens and reads `data` under every fd in 3..10, and traps unless two succeed
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend([1, 30, 4, 0x60, 0, 0, 0x60, 9, 0x7f, 0x7f, 0x7f, 0x7f, 0x7f]);
+        module.extend([0x7e, 0x7e, 0x7f, 0x7f, 1, 0x7f, 0x60, 4, 0x7f, 0x7f, 0x7f]);
+        module.extend([0x7f, 1, 0x7f, 0x60, 1, 0x7f, 1, 0x7f]);
+        module.extend([2, 69, 2, 22]);
+        module.extend(b"wasi_snapshot_preview1\x09path_open\0\x01\x16");
+        module.extend(b"wasi_snapshot_preview1\x07fd_read\0\x02");
+        module.extend([3, 3, 2, 3, 0]);
+        module.extend([5, 3, 1, 0, 1]);
+        module.extend([7, 19, 2, 6]);
+        module.extend(b"memory\x02\0\x06_start\0\x03");
+        // check(fd): path_open(fd, 0, "data", 0, FD_READ, 0, 0, 32) then fd_read(*32, 16, 1, 40)
+        module.extend([
+            10, 99, 2, 55, 0, 0x20, 0, 0x41, 0, 0x41, 0, 0x41, 4, 0x41, 0,
+        ]);
+        module.extend([0x42, 2, 0x42, 0, 0x41, 0, 0x41, 32, 0x10, 0]);
+        module.extend([0x04, 0x40, 0x41, 0, 0x0f, 0x0b]);
+        module.extend([0x41, 32, 0x28, 2, 0, 0x41, 16, 0x41, 1, 0x41, 40, 0x10, 1]);
+        module.extend([0x04, 0x40, 0x41, 0, 0x0f, 0x0b]);
+        module.extend([0x41, 40, 0x28, 2, 0, 0x41, 0, 0x47, 0x0b]);
+        // _start: sum check(fd) for fd in 3..10, trap if not 2
+        module.extend([41, 1, 2, 0x7f, 0x41, 3, 0x21, 0, 0x03, 0x40]);
+        module.extend([0x20, 0, 0x10, 2, 0x20, 1, 0x6a, 0x21, 1]);
+        module.extend([
+            0x20, 0, 0x41, 1, 0x6a, 0x22, 0, 0x41, 10, 0x49, 0x0d, 0, 0x0b,
+        ]);
+        module.extend([0x20, 1, 0x41, 2, 0x47, 0x04, 0x40, 0x00, 0x0b, 0x0b]);
+        // "data" at 0, the iovec {48, 16} at 16
+        module.extend([11, 30, 1, 0, 0x41, 0, 0x0b, 24]);
+        module.extend(b"data\0\0\0\0\0\0\0\0\0\0\0\0\x30\0\0\0\x10\0\0\0");
+
+        let host = std::env::temp_dir().join("watchdog_test_wasm_map_dir_host");
+        let _ = std::fs::remove_dir_all(&host);
+        let (assets, scratch) = (host.join("assets"), host.join("scratch"));
+        std::fs::create_dir_all(&assets).unwrap();
+        std::fs::create_dir_all(&scratch).unwrap();
+
+        // only one of the directories has the file
+        std::fs::write(assets.join("data"), "asset").unwrap();
+        let dirs = format!(
+            "/assets:{}:ro;/scratch:{}:rw",
+            assets.display(),
+            scratch.display()
+        );
+        let vars = [("wasm_map_dir", dirs.as_str())];
+        let (runner, dir) = new_runner("watchdog_test_wasm_map_dir", module.clone(), &vars);
+        assert!(run_empty(&runner).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the function reads from both
+        std::fs::write(scratch.join("data"), "scratch").unwrap();
+        let (runner, dir) = new_runner("watchdog_test_wasm_map_dir", module, &vars);
+        run_empty(&runner).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&host).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_wasm_warmup() {
+        let vars = [("wasm_warmup", "true"), ("min_scale", "2")];
+        let (runner, dir) = new_runner("watchdog_test_wasm_warmup", grow_module(), &vars);
+        // the warmup is not counted as invocation
+        assert_eq!(runner._inner._invoke_count.get(), 0);
+        assert_eq!(runner._inner._worker.thread_num(), 2);
+        assert!(runner.warm_up().is_ok());
+        run_empty(&runner).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_function_single_threaded() {
+        use crate::runner::Runner;
+        use hyper::Response;
+        use std::time::Duration;
+
+        let vars = [("function_single_threaded", "true"), ("min_scale", "2")];
+        let (runner, dir) = new_runner("watchdog_test_single_threaded", grow_module(), &vars);
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        // another invocation is running
+        let guard = runner._inner._single_threaded.as_ref().unwrap().lock();
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        drop(sender);
+        let req = Request::new(()).into_parts().0;
+        let mut res_head = Response::new(()).into_parts().0;
+        let mut result = runner.run(req, receiver, &mut res_head);
+
+        // waits even though a worker is free
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(result.try_recv().is_err());
+        drop(guard);
+        assert!(rt.block_on(result).unwrap().is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_instance_memory_metric() {
+        let (runner, dir) = new_runner("watchdog_test_memory_metric", grow_module(), &[]);
+        let (count, sum) = (
+            WASM_INSTANCE_MEMORY_BYTES.get_sample_count(),
+            WASM_INSTANCE_MEMORY_BYTES.get_sample_sum(),
+        );
+        run_empty(&runner).unwrap();
+
+        assert!(WASM_INSTANCE_MEMORY_BYTES.get_sample_count() > count);
+        assert!(WASM_INSTANCE_MEMORY_BYTES.get_sample_sum() - sum >= (4 * 65536) as f64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_imported_memory() {
+        // imports a wasi function and `env`.`memory` with one page, re-exports the memory for wasi,
+        // and `_start` grows it by 3 pages
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend([1, 8, 2, 0x60, 0, 0, 0x60, 0, 1, 0x7f]);
+        module.extend([2, 52, 2, 22]);
+        module.extend(b"wasi_snapshot_preview1\x0bsched_yield\0\x01");
+        module.extend(b"\x03env\x06memory\x02\0\x01");
+        module.extend([3, 2, 1, 0]);
+        module.extend([7, 19, 2, 6]);
+        module.extend(b"memory\x02\0\x06_start\0\x01");
+        module.extend([10, 9, 1, 7, 0, 0x41, 3, 0x40, 0, 0x1a, 0x0b]);
+
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        let (compiled, _) = compiler.do_compile(&module).unwrap();
+        assert!(validate_module(&compiled, false, "_start").is_ok());
+
+        let vars = [("wasm_import_memory_pages", "2")];
+        let (runner, dir) = new_runner("watchdog_test_imported_memory", module, &vars);
+        run_empty(&runner).unwrap();
+        run_empty(&runner).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invoke_count() {
+        use std::sync::atomic::Ordering;
+
+        let count = InvokeCount::default();
+        for _ in 0..3 {
+            count.increase();
+        }
+        assert_eq!(count.get(), 3);
+        count.reset();
+        assert_eq!(count.get(), 0);
+
+        // saturates instead of wrapping
+        count.0.store(usize::MAX, Ordering::Relaxed);
+        count.increase();
+        assert_eq!(count.get(), usize::MAX);
+    }
+
+    #[test]
+    fn test_request_tmp_dir() {
+        // two concurrent invocations write the same file name
+        let (first, second) = (RequestTmpDir::new().unwrap(), RequestTmpDir::new().unwrap());
+        assert_ne!(first.0, second.0);
+        std::fs::write(first.0.join("scratch"), "first").unwrap();
+        std::fs::write(second.0.join("scratch"), "second").unwrap();
+        assert_eq!(std::fs::read(first.0.join("scratch")).unwrap(), b"first");
+        assert_eq!(std::fs::read(second.0.join("scratch")).unwrap(), b"second");
+
+        // removed after the call
+        let (first_path, second_path) = (first.0.clone(), second.0.clone());
+        drop(first);
+        assert!(!first_path.exists());
+        assert!(second_path.exists());
+        drop(second);
+        assert!(!second_path.exists());
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_per_request_tmp_capability() {
+        let module = wasi_module("_start", 0, &[0x0b]);
+        let (runner, dir) = new_runner(
+            "watchdog_test_per_request_tmp",
+            module,
+            &[
+                ("wasm_per_request_tmp", "true"),
+                ("wasm_capabilities", "fs_read,fs_write"),
+            ],
+        );
+        run_empty(&runner).unwrap();
+
+        // the default capabilities cannot write the temp directory
+        let mut env = HashMap::new();
+        env.insert("mode".to_string(), "wasm".to_string());
+        env.insert(
+            "fprocess".to_string(),
+            dir.join("func.wasm").display().to_string(),
+        );
+        env.insert("wasm_per_request_tmp".to_string(), "true".to_string());
+        let err = WasmRunner::new(WatchdogConfig::new(&env).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("fs_write"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_large_response_warning() {
+        assert_eq!(large_response_warning("func.wasm", 2048, None), None);
+        assert_eq!(large_response_warning("func.wasm", 1024, Some(1024)), None);
+        let warning = large_response_warning("func.wasm", 2048, Some(1024)).unwrap();
+        assert!(warning.contains("`func.wasm`"));
+        assert!(warning.contains("2048 bytes"));
+    }
+
+    #[test]
+    fn test_wasi_capabilities() {
+        let capabilities = WasiCapabilities::parse("fs_read, env,random").unwrap();
+        assert!(capabilities._fs_read && capabilities._env && capabilities._random);
+        assert!(!capabilities._fs_write && !capabilities._clock);
+        assert!(WasiCapabilities::parse("network").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_deny_clock() {
+        // imports `clock_time_get` and traps in `_start` if it fails
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        module.extend([1, 11, 2, 0x60, 0, 0, 0x60, 3, 0x7f, 0x7e, 0x7f, 1, 0x7f]);
+        module.extend([2, 41, 1, 22]);
+        module.extend(b"wasi_snapshot_preview1\x0eclock_time_get\0\x01");
+        module.extend([3, 2, 1, 0]);
+        module.extend([5, 3, 1, 0, 1]);
+        module.extend([7, 19, 2, 6]);
+        module.extend(b"memory\x02\0\x06_start\0\x01");
+        module.extend([10, 16, 1, 14, 0, 0x41, 0, 0x42, 1, 0x41, 0, 0x10, 0]);
+        module.extend([0x04, 0x40, 0x00, 0x0b, 0x0b]);
+
+        let (runner, dir) = new_runner("watchdog_test_allow_clock", module.clone(), &[]);
+        run_empty(&runner).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let vars = [("wasm_capabilities", "fs_read,env,random")];
+        let (runner, dir) = new_runner("watchdog_test_deny_clock", module, &vars);
+        assert!(run_empty(&runner).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_validate_module() {
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        let wasi = ("wasi_snapshot_preview1", "sched_yield");
+
+        let (good, _) = compiler.do_compile(&build_module(&[wasi])).unwrap();
+        assert!(validate_module(&good, false, "_start").is_ok());
+
+        let (bad, _) = compiler
+            .do_compile(&build_module(&[wasi, ("env", "missing")]))
+            .unwrap();
+        let err = validate_module(&bad, false, "_start")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`env`.`missing`"));
+
+        let (no_wasi, _) = compiler.do_compile(&build_module(&[])).unwrap();
+        assert!(validate_module(&no_wasi, false, "_start").is_err());
+    }
+
+    #[test]
+    fn test_stdout_error() {
+        let err = stdout_error("func.wasm", false).to_string();
+        assert!(err.contains("`func.wasm` does not import WASI"));
+        assert!(err.contains("wasm32-wasi"));
+        let err = stdout_error("func.wasm", true).to_string();
+        assert!(err.contains("Cannot find the wasi `stdout` handler of `func.wasm`"));
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_non_wasi_module() {
+        let (runner, dir) = new_runner("watchdog_test_non_wasi_module", build_module(&[]), &[]);
+        let err = run_empty(&runner).unwrap_err().to_string();
+        assert!(err.contains("does not import WASI"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_status_of_return() {
+        assert_eq!(status_of_return(0), Ok(None));
+        assert_eq!(
+            status_of_return(404),
+            Ok(Some(hyper::StatusCode::NOT_FOUND))
+        );
+        assert!(status_of_return(1).unwrap_err().contains("code 1"));
+        assert!(status_of_return(-1).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_start_return_as_status() {
+        // `_start` returns 404
+        let module = wasi_module("_start", 1, &[0x41, 0x94, 0x03, 0x0b]);
+
+        let status = |name: &str, vars: &[(&str, &str)]| {
+            let (runner, dir) = new_runner(name, module.clone(), vars);
+            let (sender, receiver) = tokio::sync::mpsc::channel(1);
+            drop(sender);
+            let req = Request::new(()).into_parts().0;
+            let res = runner.run_inner(req, receiver, None).unwrap().unwrap();
+            std::fs::remove_dir_all(&dir).unwrap();
+            res.status()
+        };
+        assert_eq!(status("watchdog_test_start_return_ignored", &[]), 200);
+        let vars = [("start_return_as_status", "true")];
+        assert_eq!(status("watchdog_test_start_return_as_status", &vars), 404);
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_max_memory_pages() {
+        // exports one page memory, and `_start` traps if it cannot grow by 3 pages
+        let code = [
+            0x41, 3, 0x40, 0, 0x41, 0x7f, 0x46, 0x04, 0x40, 0x00, 0x0b, 0x0b,
+        ];
+        let module = wasi_module("_start", 0, &code);
+
+        let (runner, dir) = new_runner("watchdog_test_memory_unlimited", module.clone(), &[]);
+        run_empty(&runner).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let vars = [("wasm_max_memory_pages", "2")];
+        let (runner, dir) = new_runner("watchdog_test_max_memory_pages", module, &vars);
+        let err = run_empty(&runner).unwrap_err().to_string();
+        assert!(err.contains("capped at 2 pages"));
+        // the next invocation gets a new instance
+        assert!(run_empty(&runner).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_proc_exit() {
+        use crate::runner::RunnerError;
+
+        // `_start` calls `proc_exit` with the code
+        let exit_module = |code: u8| {
+            let mut module = b"\0asm\x01\0\0\0".to_vec();
+            module.extend([1, 8, 2, 0x60, 1, 0x7f, 0, 0x60, 0, 0]);
+            module.extend([2, 36, 1, 22]);
+            module.extend(b"wasi_snapshot_preview1\x09proc_exit\0\0");
+            module.extend([3, 2, 1, 1]);
+            module.extend([5, 3, 1, 0, 1]);
+            module.extend([7, 19, 2, 6]);
+            module.extend(b"memory\x02\0\x06_start\0\x01");
+            module.extend([10, 8, 1, 6, 0, 0x41, code, 0x10, 0, 0x0b]);
+            module
+        };
+
+        let (runner, dir) = new_runner("watchdog_test_exit_zero", exit_module(0), &[]);
+        run_empty(&runner).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (runner, dir) = new_runner("watchdog_test_exit_non_zero", exit_module(3), &[]);
+        let err = run_empty(&runner).unwrap_err();
+        let err = err.downcast_ref::<RunnerError>().unwrap();
+        assert_eq!(err._status, hyper::StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(err._message.contains("exited with code 3"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exit_error() {
+        let err = super::exit_error(3, String::new()).to_string();
+        assert_eq!(err, "The function exited with code 3");
+        let err = super::exit_error(3, "bad input".to_string()).to_string();
+        assert!(err.ends_with("stderr:\nbad input"));
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_wasm_entrypoint() {
+        // exports `run` which returns 404 instead of `_start`
+        let module = wasi_module("run", 1, &[0x41, 0x94, 0x03, 0x0b]);
+
+        let (runner, dir) = new_runner("watchdog_test_default_entrypoint", module.clone(), &[]);
+        let err = run_empty(&runner).unwrap_err().to_string();
+        assert!(err.contains("`_start`"));
+        assert!(err.contains("[`run`]"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the returned status tells `run` is invoked
+        let vars = [
+            ("wasm_entrypoint", "run"),
+            ("start_return_as_status", "true"),
+        ];
+        let (runner, dir) = new_runner("watchdog_test_wasm_entrypoint", module, &vars);
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        drop(sender);
+        let req = Request::new(()).into_parts().0;
+        let res = runner.run_inner(req, receiver, None).unwrap().unwrap();
+        assert_eq!(res.status(), 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // the reactor module is valid with its entrypoint
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        let (compiled, _) = compiler.do_compile(&module).unwrap();
+        assert!(validate_module(&compiled, false, "run").is_ok());
+        let err = validate_module(&compiled, false, "_start").unwrap_err();
+        assert!(err.to_string().contains("`_start`"));
     }
 }
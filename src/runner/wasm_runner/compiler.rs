@@ -1,36 +1,103 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "compiler-base")]
+use std::sync::Arc;
+use std::sync::Mutex;
 
-#[cfg(feature = "compiler")]
+#[cfg(feature = "compiler-base")]
 use std::fs;
-#[cfg(feature = "compiler")]
+#[cfg(feature = "compiler-base")]
 use std::str::FromStr;
-#[cfg(feature = "compiler")]
-use std::time::{Duration, SystemTime};
+#[cfg(feature = "compiler-base")]
+use std::sync::mpsc::{self, RecvTimeoutError};
+#[cfg(feature = "compiler-base")]
+use std::thread;
+use std::time::Duration;
+#[cfg(feature = "compiler-base")]
+use std::time::{Instant, SystemTime};
 
 use anyhow::{anyhow, Result};
 use log::{info, warn};
-use wasmer::{Dylib, DylibArtifact, Module, Store, Triple};
-
-#[cfg(feature = "compiler")]
-use wasmer::{CpuFeature, Engine, Target, LLVM};
+#[cfg(feature = "compiler-base")]
+use sha2::{Digest, Sha256};
+use wasmer::{BaseTunables, Dylib, DylibArtifact, Module, Pages, Store, Triple};
+
+use super::tunables::LimitingTunables;
+
+#[cfg(feature = "compiler-base")]
+use wasmer::wasmparser::Operator;
+#[cfg(feature = "compiler-cranelift")]
+use wasmer::Cranelift;
+#[cfg(feature = "compiler-singlepass")]
+use wasmer::Singlepass;
+#[cfg(feature = "compiler-llvm")]
+use wasmer::LLVM;
+#[cfg(feature = "compiler-base")]
+use wasmer::{CompilerConfig, CpuFeature, Engine, Target};
+#[cfg(feature = "compiler-base")]
+use wasmer_middlewares::Metering;
+
+/// the compilers which can be chosen by `wasm_compiler`
+#[cfg(feature = "compiler-base")]
+const COMPILERS: [&str; 3] = ["llvm", "cranelift", "singlepass"];
+
+/// the interval of logging the elapsed time while compiling
+#[cfg(feature = "compiler-base")]
+const COMPILE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// default compiler is the first enabled one of llvm, cranelift and singlepass
+#[cfg(feature = "compiler-llvm")]
+const DEFAULT_COMPILER: &str = "llvm";
+#[cfg(all(feature = "compiler-cranelift", not(feature = "compiler-llvm")))]
+const DEFAULT_COMPILER: &str = "cranelift";
+#[cfg(all(
+    feature = "compiler-singlepass",
+    not(any(feature = "compiler-llvm", feature = "compiler-cranelift"))
+))]
+const DEFAULT_COMPILER: &str = "singlepass";
+#[cfg(all(
+    feature = "compiler-base",
+    not(any(
+        feature = "compiler-llvm",
+        feature = "compiler-cranelift",
+        feature = "compiler-singlepass"
+    ))
+))]
+compile_error!(
+    "please enable one of `compiler-llvm`, `compiler-cranelift` and `compiler-singlepass` features"
+);
 
 pub(crate) struct Compiler {
     _store: Store,
     _out_extension: &'static str,
+    /// the fuel limit metered in the compiled code, which is also in the cached file name
+    _fuel_limit: Option<u64>,
+    /// the cached compiled file older than it is not loaded
+    _artifact_max_age: Option<Duration>,
+    /// the compiled file loaded or written by the last ```try_load_compiled```
+    _last_artifact: Mutex<Option<PathBuf>>,
 }
 
 /// The implementation for webassembly compiler wrapper
 /// default engine is Dylib
 /// default compiler is LLVM
 impl Compiler {
-    #[cfg(feature = "compiler")]
-    /// new compiler for given target triple and cpu_features
-    pub(crate) fn new(target_triple: Option<String>, cpu_features: Option<String>) -> Result<Self> {
+    #[cfg(feature = "compiler-base")]
+    /// new compiler for given target triple, cpu_features, compiler name and fuel limit
+    pub(crate) fn new(
+        target_triple: Option<String>,
+        cpu_features: Option<String>,
+        compiler: Option<String>,
+        fuel_limit: Option<u64>,
+    ) -> Result<Self> {
         // parse the target or use default native target
         let target = Self::parse_target(target_triple, cpu_features)?;
 
-        // new llvm compiler config
-        let compiler_config = LLVM::new();
+        // new the compiler config, default is llvm
+        let mut compiler_config = Self::compiler_config(compiler)?;
+        if let Some(limit) = fuel_limit {
+            info!("Meter the function with the fuel limit {}", limit);
+            compiler_config.push_middleware(Arc::new(Metering::new(limit, fuel_cost)));
+        }
 
         // new dylib engine
         let engine = Dylib::new(compiler_config).target(target).engine();
@@ -38,12 +105,21 @@ impl Compiler {
         Ok(Self {
             _store: Store::new(&engine),
             _out_extension: DylibArtifact::get_default_extension(engine.target().triple()),
+            _fuel_limit: fuel_limit,
+            _artifact_max_age: None,
+            _last_artifact: Mutex::new(None),
         })
     }
 
-    #[cfg(not(feature = "compiler"))]
-    /// Create new compiler with headless engine
-    pub(crate) fn new(target_triple: Option<String>, cpu_features: Option<String>) -> Result<Self> {
+    #[cfg(not(feature = "compiler-base"))]
+    /// Create new compiler with headless engine,
+    /// which loads the compiled file metered with the fuel limit if it is set
+    pub(crate) fn new(
+        target_triple: Option<String>,
+        cpu_features: Option<String>,
+        compiler: Option<String>,
+        fuel_limit: Option<u64>,
+    ) -> Result<Self> {
         if target_triple.is_some() {
             warn!(
                 "No Compiler! environment variable `{}` is set but not used",
@@ -56,32 +132,141 @@ impl Compiler {
                 super::KEY_WASM_C_CPU_FEATURES
             );
         }
+        if compiler.is_some() {
+            warn!(
+                "No Compiler! environment variable `{}` is set but not used",
+                super::KEY_WASM_COMPILER
+            );
+        }
 
         let engine = Dylib::headless().engine();
         Ok(Self {
             _store: Store::new(&engine),
             _out_extension: DylibArtifact::get_default_extension(&Triple::host()),
+            _fuel_limit: fuel_limit,
+            _artifact_max_age: None,
+            _last_artifact: Mutex::new(None),
         })
     }
 
+    /// ignore the cached compiled files older than max age
+    pub(crate) fn artifact_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self._artifact_max_age = max_age;
+        self
+    }
+
+    /// cap the memory of the instances of the loaded modules
+    pub(crate) fn max_memory_pages(mut self, max_pages: Option<u32>) -> Self {
+        if let Some(max_pages) = max_pages {
+            let engine = self._store.engine().clone();
+            let tunables =
+                LimitingTunables::new(BaseTunables::for_target(engine.target()), Pages(max_pages));
+            self._store = Store::new_with_tunables(engine.as_ref(), tunables);
+        }
+        self
+    }
+
+    /// the extension of the cached compiled file, such as `so` or `fuel-1000.so` with the fuel limit,
+    /// so the file compiled with another limit is not loaded
+    fn compiled_extension(&self) -> String {
+        match self._fuel_limit {
+            Some(limit) => format!("fuel-{}.{}", limit, self._out_extension),
+            None => self._out_extension.to_string(),
+        }
+    }
+
+    /// get the compiled file used by the last load, `None` if no file is loaded or written
+    pub(crate) fn last_artifact(&self) -> Option<PathBuf> {
+        self._last_artifact.lock().unwrap().clone()
+    }
+
+    /// if the cached compiled file is older than the max age
+    fn is_stale(&self, compiled_file: &Path) -> bool {
+        let max_age = match self._artifact_max_age {
+            Some(max_age) => max_age,
+            None => return false,
+        };
+        let age = compiled_file
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok());
+        matches!(age, Some(age) if age > max_age)
+    }
+
+    /// if the cached compiled file is compiled from the wasm bytes, by its sidecar hash file.
+    /// it is loaded unverified if the wasm module does not exist
+    #[cfg(feature = "compiler-base")]
+    fn is_verified(&self, compiled_file: &Path, wasm_bytes: Option<&[u8]>) -> bool {
+        let wasm_bytes = match wasm_bytes {
+            Some(wasm_bytes) => wasm_bytes,
+            None => {
+                warn!(
+                    "No Webassembly module for compiled file `{}`, load it unverified",
+                    compiled_file.display()
+                );
+                return true;
+            }
+        };
+        match fs::read_to_string(hash_file(compiled_file)) {
+            Ok(hash) => hash.trim() == wasm_hash(wasm_bytes),
+            Err(_) => false,
+        }
+    }
+
     /// if the wasm module has been compiled to native binary file, return the deserialize module
     /// else do compile and return the compiled module
-    /// todo: add safety strategy for cached file
     #[allow(unused_mut)]
     pub(crate) fn try_load_compiled(&self, mut wasm_file: PathBuf) -> Result<Module> {
-        #[cfg(feature = "compiler")]
+        #[cfg(feature = "compiler-base")]
         let mut compiled_file = wasm_file.clone();
-        #[cfg(not(feature = "compiler"))]
+        #[cfg(not(feature = "compiler-base"))]
         let mut compiled_file = wasm_file; // just move
 
-        compiled_file.set_extension(self._out_extension);
+        compiled_file.set_extension(self.compiled_extension());
+
+        // the wasm bytes are read once for the verification and compiling
+        #[cfg(feature = "compiler-base")]
+        let wasm_bytes = {
+            wasm_file.set_extension("wasm");
+            match wasm_file.is_file() {
+                true => Some(fs::read(&wasm_file)?),
+                false => None,
+            }
+        };
+        // the cached file is native code, so never load the one compiled from another wasm
+        #[cfg(feature = "compiler-base")]
+        let verified =
+            !compiled_file.is_file() || self.is_verified(&compiled_file, wasm_bytes.as_deref());
+        #[cfg(not(feature = "compiler-base"))]
+        let verified = true;
 
         // judge if cached file exists and valid
-        if compiled_file.is_file() {
+        if compiled_file.is_file() && self.is_stale(&compiled_file) {
+            warn!(
+                "Compiled wasm module file `{}` is older than {:?}, ignore it",
+                compiled_file.display(),
+                self._artifact_max_age.unwrap()
+            );
+        } else if !verified {
+            warn!(
+                "Compiled wasm module file `{}` does not match the hash of the Webassembly module, ignore it",
+                compiled_file.display()
+            );
+        } else if compiled_file.is_file() {
             // try deserialize the module from file
             match unsafe { Module::deserialize_from_file(&self._store, &compiled_file) } {
                 Ok(module) => {
-                    info!("Deserialize module from cached binary file success");
+                    info!(
+                        "Deserialize module from cached binary file `{}` success",
+                        compiled_file.display()
+                    );
+                    #[cfg(not(feature = "compiler-base"))]
+                    info!(
+                        "No Compiler! the compiled file `{}` is loaded without verifying its hash",
+                        compiled_file.display()
+                    );
+                    *self._last_artifact.lock().unwrap() = Some(compiled_file);
                     return Ok(module);
                 }
                 Err(e) => {
@@ -94,25 +279,33 @@ impl Compiler {
             }
         }
 
-        #[cfg(feature = "compiler")]
+        #[cfg(feature = "compiler-base")]
         return {
             info!("Compiling the webassembly module");
 
-            wasm_file.set_extension("wasm");
-            if !wasm_file.is_file() {
-                return Err(anyhow!(
-                    "No such Webassembly module file: `{}`",
-                    wasm_file.display()
-                ));
-            }
-            let wasm_bytes = fs::read(wasm_file)?;
+            let wasm_bytes = wasm_bytes.ok_or_else(|| {
+                anyhow!("No such Webassembly module file: `{}`", wasm_file.display())
+            })?;
             let (module, duration) = self.do_compile(&wasm_bytes)?;
             info!("Compile success, usage {} ms", duration.as_millis());
 
-            // try to serialize the module and save to cached file
-            match module.serialize_to_file(&compiled_file) {
+            // try to serialize the module and save to cached file with its hash
+            let saved = module
+                .serialize_to_file(&compiled_file)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| {
+                    Ok(fs::write(
+                        hash_file(&compiled_file),
+                        wasm_hash(&wasm_bytes),
+                    )?)
+                });
+            match saved {
                 Ok(_) => {
-                    info!("Serialize the module and save to module file success");
+                    info!(
+                        "Serialize the module and save to module file `{}` success",
+                        compiled_file.display()
+                    );
+                    *self._last_artifact.lock().unwrap() = Some(compiled_file);
                 }
                 Err(e) => {
                     warn!(
@@ -125,28 +318,64 @@ impl Compiler {
             Ok(module)
         };
 
-        // if no compiler, just return error msg
-        #[cfg(not(feature = "compiler"))]
-        return {
-            if !compiled_file.is_file() {
-                log::error!(
-                    "Cannot find the webassembly file `{}`",
-                    compiled_file.display()
-                );
-            }
-            Err(anyhow!(
-                "Deserialize module fail and no compiler feature enable"
-            ))
+        // if no compiler, tell how to get a loadable module
+        #[cfg(not(feature = "compiler-base"))]
+        return Err(self.no_compiler_error(&compiled_file));
+    }
+
+    /// the error for the compiled module which cannot be loaded without the compiler
+    #[cfg(not(feature = "compiler-base"))]
+    fn no_compiler_error(&self, compiled_file: &Path) -> anyhow::Error {
+        let reason = match compiled_file.is_file() {
+            true if self.is_stale(compiled_file) => "is older than the max artifact age",
+            true => "exists but cannot be loaded, it may be compiled for another target or wasmer version",
+            false => "does not exist",
         };
+        anyhow!(
+            "The compiled module `{}` {}. This build has no `compiler` feature and only loads \
+                the modules precompiled for target `{}` with extension `.{}`. Please precompile \
+                it by a build with `compiler` feature: `--compile <IN_FILE> -o {}`",
+            compiled_file.display(),
+            reason,
+            Triple::host(),
+            self.compiled_extension(),
+            compiled_file.display()
+        )
+    }
+
+    /// load the module from the wasm file (needs `compiler` feature) or the compiled file,
+    /// but never write the cached file
+    pub(crate) fn load_without_cache(&self, file: &Path) -> Result<Module> {
+        if !file.is_file() {
+            return Err(anyhow!("No such file: `{}`", file.display()));
+        }
+
+        #[cfg(feature = "compiler-base")]
+        if file.extension().unwrap_or_default() == "wasm" {
+            let wasm_bytes = fs::read(file)?;
+            return Ok(self.do_compile(&wasm_bytes)?.0);
+        }
+
+        unsafe { Module::deserialize_from_file(&self._store, file) }.map_err(|e| {
+            anyhow!(
+                "Cannot load the compiled module `{}`, error = {:?}",
+                file.display(),
+                e
+            )
+        })
     }
 
     /// do the compile stage, compile the wasm bytes to native code and return time duration
     #[inline(always)]
-    #[cfg(feature = "compiler")]
+    #[cfg(feature = "compiler-base")]
     pub(crate) fn do_compile(&self, bytes: &[u8]) -> Result<(Module, Duration)> {
         let start_time = SystemTime::now();
 
-        let module = Module::from_binary(&self._store, bytes)?;
+        // the compiling is opaque, so tell it is not hung
+        let heartbeat = Heartbeat::start(COMPILE_HEARTBEAT_INTERVAL);
+        let module = Module::from_binary(&self._store, bytes);
+        heartbeat.finish();
+        let module = module?;
 
         let end_time = SystemTime::now();
         Ok((module, end_time.duration_since(start_time).unwrap()))
@@ -154,7 +383,7 @@ impl Compiler {
 
     /// compile from wasm file to dylib file
     #[inline(always)]
-    #[cfg(feature = "compiler")]
+    #[cfg(feature = "compiler-base")]
     pub(crate) fn compile_to_file(&self, in_file: &String, out_file: &String) -> Result<()> {
         // load wasm module file
         let wasm_bytes = fs::read(in_file)?;
@@ -162,10 +391,11 @@ impl Compiler {
         // do compile
         let (module, duration) = self.do_compile(&wasm_bytes)?;
 
-        // serialize to file
+        // serialize to file, with the hash to verify it when loaded next to the wasm module
         let binary = module.serialize()?;
         let out_path = PathBuf::from(out_file);
         fs::write(out_file, binary)?;
+        fs::write(hash_file(&out_path), wasm_hash(&wasm_bytes))?;
 
         // check the out file extension
         let out_filename = out_path
@@ -201,7 +431,7 @@ impl Compiler {
         Ok(())
     }
 
-    #[cfg(feature = "compiler")]
+    #[cfg(feature = "compiler-base")]
     fn parse_target(
         triple_opt: Option<String>,
         cpu_features_str: Option<String>,
@@ -217,8 +447,9 @@ impl Compiler {
             None => CpuFeature::for_host(),
             Some(ref s) => {
                 let mut set = CpuFeature::set();
-                for c in s.split([',', ' ', '\n', '\t']) {
-                    set |= CpuFeature::from_str(c).map_err(|e| {
+                // such as `sse2,avx` or `sse2, avx`
+                for c in s.split([',', ' ', '\n', '\t']).filter(|c| !c.is_empty()) {
+                    set |= CpuFeature::from_str(&c.to_ascii_lowercase()).map_err(|e| {
                         anyhow!("Cannot parse the cpu features `{}`, error = {:?}", c, e)
                     })?;
                 }
@@ -228,22 +459,290 @@ impl Compiler {
 
         Ok(Target::new(triple, cpu_features))
     }
+
+    #[cfg(feature = "compiler-base")]
+    /// get the config of the compiler, which must be enabled by its feature
+    fn compiler_config(compiler: Option<String>) -> Result<Box<dyn CompilerConfig>> {
+        let name = compiler
+            .map(|c| c.trim().to_ascii_lowercase())
+            .unwrap_or_else(|| DEFAULT_COMPILER.to_string());
+
+        #[allow(unused_mut)]
+        let mut config: Option<Box<dyn CompilerConfig>> = None;
+        #[cfg(feature = "compiler-llvm")]
+        if name == "llvm" {
+            config = Some(Box::new(LLVM::new()));
+        }
+        #[cfg(feature = "compiler-cranelift")]
+        if name == "cranelift" {
+            config = Some(Box::new(Cranelift::new()));
+        }
+        #[cfg(feature = "compiler-singlepass")]
+        if name == "singlepass" {
+            config = Some(Box::new(Singlepass::new()));
+        }
+
+        match config {
+            Some(config) => {
+                info!("Use the `{}` compiler", name);
+                Ok(config)
+            }
+            None if COMPILERS.contains(&name.as_str()) => Err(anyhow!(
+                "The compiler `{}` is not enabled, please build with the `compiler-{}` feature",
+                name,
+                name
+            )),
+            None => Err(anyhow!(
+                "Unknown compiler `{}`, it must be one of {:?}",
+                name,
+                COMPILERS
+            )),
+        }
+    }
+}
+
+/// the fuel cost of every operator
+#[cfg(feature = "compiler-base")]
+fn fuel_cost(_operator: &Operator) -> u64 {
+    1
+}
+
+/// log the elapsed time periodically in a background thread until finished
+#[cfg(feature = "compiler-base")]
+struct Heartbeat {
+    _stop: mpsc::Sender<()>,
+    _thread: thread::JoinHandle<usize>,
+}
+
+#[cfg(feature = "compiler-base")]
+impl Heartbeat {
+    fn start(interval: Duration) -> Self {
+        let (stop, receiver) = mpsc::channel::<()>();
+        let start_time = Instant::now();
+        let thread = thread::spawn(move || {
+            let mut beats = 0;
+            // the sender is dropped when finished
+            while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                beats += 1;
+                info!(
+                    "Still compiling the webassembly module, {} s elapsed",
+                    start_time.elapsed().as_secs()
+                );
+            }
+            beats
+        });
+        Self {
+            _stop: stop,
+            _thread: thread,
+        }
+    }
+
+    /// stop logging and return the number of logged heartbeats
+    fn finish(self) -> usize {
+        let Self { _stop, _thread } = self;
+        drop(_stop);
+        _thread.join().unwrap_or_default()
+    }
+}
+
+/// the sidecar file of the compiled file keeping the hash of its wasm module
+#[cfg(feature = "compiler-base")]
+fn hash_file(compiled_file: &Path) -> PathBuf {
+    let mut path = compiled_file.as_os_str().to_owned();
+    path.push(".hash");
+    PathBuf::from(path)
+}
+
+/// the sha256 hex of the wasm module
+#[cfg(feature = "compiler-base")]
+fn wasm_hash(wasm_bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(wasm_bytes))
 }
 
 #[cfg(test)]
 mod test {
     use super::Compiler;
+    use std::time::{Duration, SystemTime};
     use wasmer::Target;
 
     #[test]
     fn test_default() {
-        let store = Compiler::new(None, None).unwrap()._store;
+        let store = Compiler::new(None, None, None, None).unwrap()._store;
         let engine = store.engine();
         assert_eq!(engine.target().clone(), Target::default());
     }
 
     #[test]
-    #[cfg(feature = "compiler")]
+    #[cfg(not(feature = "compiler-base"))]
+    fn test_no_compiler_error() {
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        let extension = compiler._out_extension;
+        let err = compiler
+            .try_load_compiled("/nonexistent/func.wasm".into())
+            .unwrap_err()
+            .to_string();
+        let expected = format!("/nonexistent/func.{}", extension);
+        assert!(err.contains(&format!("`{}` does not exist", expected)));
+        assert!(err.contains(&format!("extension `.{}`", extension)));
+        assert!(err.contains("--compile"));
+        assert_eq!(compiler.last_artifact(), None);
+    }
+
+    #[test]
+    fn test_artifact_max_age() {
+        let day = Duration::from_secs(24 * 3600);
+        let dir = std::env::temp_dir().join("watchdog_test_artifact_max_age");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let wasm_file = dir.join("func.wasm");
+        std::fs::write(&wasm_file, b"\0asm\x01\0\0\0").unwrap();
+
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        let artifact = wasm_file.with_extension(compiler._out_extension);
+        let file = std::fs::File::create(&artifact).unwrap();
+        assert!(!compiler.is_stale(&artifact));
+        let compiler = compiler.artifact_max_age(Some(day));
+        assert!(!compiler.is_stale(&artifact));
+
+        // backdate the artifact
+        file.set_modified(SystemTime::now() - day * 2).unwrap();
+        assert!(compiler.is_stale(&artifact));
+        drop(file);
+
+        // the stale artifact is a cache miss, recompiled and rewritten
+        #[cfg(feature = "compiler-base")]
+        {
+            compiler.try_load_compiled(wasm_file).unwrap();
+            assert_eq!(compiler.last_artifact(), Some(artifact.clone()));
+            assert!(!compiler.is_stale(&artifact));
+        }
+        // or rejected without the compiler
+        #[cfg(not(feature = "compiler-base"))]
+        {
+            let err = compiler.try_load_compiled(wasm_file).unwrap_err();
+            assert!(err.to_string().contains("older than the max artifact age"));
+            assert_eq!(compiler.last_artifact(), None);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_last_artifact() {
+        let dir = std::env::temp_dir().join("watchdog_test_last_artifact");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let wasm_file = dir.join("func.wasm");
+        std::fs::write(&wasm_file, b"\0asm\x01\0\0\0").unwrap();
+
+        // cache miss, compile and write the artifact
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        assert_eq!(compiler.last_artifact(), None);
+        compiler.try_load_compiled(wasm_file.clone()).unwrap();
+        let expected = wasm_file.with_extension(compiler._out_extension);
+        assert_eq!(compiler.last_artifact(), Some(expected.clone()));
+        assert!(expected.is_file());
+
+        // cache hit, load the written artifact
+        std::fs::remove_file(&wasm_file).unwrap();
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        compiler.try_load_compiled(wasm_file).unwrap();
+        assert_eq!(compiler.last_artifact(), Some(expected));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_artifact_hash() {
+        let dir = std::env::temp_dir().join("watchdog_test_artifact_hash");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let wasm_file = dir.join("func.wasm");
+        std::fs::write(&wasm_file, b"\0asm\x01\0\0\0").unwrap();
+
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        let module = compiler.try_load_compiled(wasm_file.clone()).unwrap();
+        assert_eq!(module.exports().count(), 0);
+        let artifact = wasm_file.with_extension(compiler._out_extension);
+        let hash = std::fs::read_to_string(super::hash_file(&artifact)).unwrap();
+
+        // the changed wasm is recompiled instead of loading the stale artifact
+        let wasm = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\x07\x0a\x01\x06_start\0\0\x0a\x04\x01\x02\0\x0b";
+        std::fs::write(&wasm_file, wasm).unwrap();
+        let module = compiler.try_load_compiled(wasm_file.clone()).unwrap();
+        assert!(module.exports().functions().any(|f| f.name() == "_start"));
+        let new_hash = std::fs::read_to_string(super::hash_file(&artifact)).unwrap();
+        assert_ne!(hash, new_hash);
+        assert_eq!(new_hash, super::wasm_hash(wasm));
+
+        // the artifact without hash is not trusted either
+        std::fs::remove_file(super::hash_file(&artifact)).unwrap();
+        compiler.try_load_compiled(wasm_file).unwrap();
+        assert!(super::hash_file(&artifact).is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compiled_extension() {
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        assert_eq!(compiler.compiled_extension(), compiler._out_extension);
+        let compiler = Compiler::new(None, None, None, Some(1000)).unwrap();
+        let extension = format!("fuel-1000.{}", compiler._out_extension);
+        assert_eq!(compiler.compiled_extension(), extension);
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_heartbeat() {
+        use super::Heartbeat;
+
+        // the fast compiling finishes before the first heartbeat
+        let compiler = Compiler::new(None, None, None, None).unwrap();
+        let heartbeat = Heartbeat::start(super::COMPILE_HEARTBEAT_INTERVAL);
+        let (_, duration) = compiler.do_compile(b"\0asm\x01\0\0\0").unwrap();
+        assert!(duration < super::COMPILE_HEARTBEAT_INTERVAL);
+        assert_eq!(heartbeat.finish(), 0);
+
+        let heartbeat = Heartbeat::start(Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(350));
+        let beats = heartbeat.finish();
+        assert!((2..=4).contains(&beats));
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-base")]
+    fn test_compilers() {
+        // `_start` of type `() -> ()` does nothing
+        let wasm = b"\0asm\x01\0\0\0\x01\x04\x01\x60\0\0\x03\x02\x01\0\x0a\x04\x01\x02\0\x0b";
+        let enabled = [
+            ("llvm", cfg!(feature = "compiler-llvm")),
+            ("cranelift", cfg!(feature = "compiler-cranelift")),
+            ("singlepass", cfg!(feature = "compiler-singlepass")),
+        ];
+        for (name, enabled) in enabled {
+            let compiler = Compiler::new(None, None, Some(name.to_uppercase()), None);
+            match compiler {
+                Ok(compiler) => {
+                    assert!(enabled, "{}", name);
+                    assert!(wasmer::Module::new(&compiler._store, &wasm[..]).is_ok());
+                }
+                Err(e) => {
+                    assert!(!enabled, "{}", name);
+                    assert!(e.to_string().contains(&format!("compiler-{}", name)));
+                }
+            }
+        }
+
+        // the default compiler
+        assert!(Compiler::new(None, None, None, None).is_ok());
+        let err = Compiler::new(None, None, Some("v8".to_string()), None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Unknown compiler `v8`"));
+    }
+
+    #[test]
+    #[cfg(feature = "compiler-llvm")]
     fn test_triples() {
         let triples = vec![
             "aarch64-apple-darwin",
@@ -253,16 +752,27 @@ mod test {
         let extensions = vec!["dylib", "so", "dll"];
 
         for i in 0..triples.len() {
-            let compiler = Compiler::new(Some(triples[i].to_string()), None);
+            let compiler = Compiler::new(Some(triples[i].to_string()), None, None, None);
             assert!(compiler.is_ok());
             assert_eq!(compiler.unwrap()._out_extension, extensions[i]);
         }
     }
 
     #[test]
-    #[cfg(feature = "compiler")]
+    #[cfg(feature = "compiler-base")]
     fn test_cpu_features() {
+        use wasmer::CpuFeature;
+
         let features = "ssse3,avx,avx2".to_string();
         assert!(Compiler::parse_target(None, Some(features)).is_ok());
+
+        let target = Compiler::parse_target(None, Some("avx2".to_string())).unwrap();
+        assert!(target.cpu_features().contains(CpuFeature::AVX2));
+        assert!(!target.cpu_features().contains(CpuFeature::SSE2));
+        let target = Compiler::parse_target(None, Some("SSE2, avx".to_string())).unwrap();
+        assert_eq!(*target.cpu_features(), CpuFeature::SSE2 | CpuFeature::AVX);
+
+        let err = Compiler::parse_target(None, Some("avx2,avx9000".to_string())).unwrap_err();
+        assert!(err.to_string().contains("`avx9000`"));
     }
 }
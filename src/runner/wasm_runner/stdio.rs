@@ -1,10 +1,21 @@
 use std::cmp;
+use std::fmt;
 use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
 
 use hyper::body::{Buf, Bytes};
+use hyper::header::CONTENT_TYPE;
+use hyper::http::HeaderValue;
+use hyper::{Body, Response, StatusCode};
+use log::debug;
+use tokio::runtime::Handle;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
+use wasmer::RuntimeError;
 use wasmer_wasi::{WasiFile, WasiFsError};
 
+use crate::LogRing;
+
 /// for impl the interface WasiFile
 macro_rules! impl_wasi_file {
     ($name:ident) => {
@@ -133,14 +144,27 @@ pub(super) struct Stdin {
     _buf_receiver: Receiver<anyhow::Result<Bytes, hyper::Error>>,
     /// is end of file
     _is_eof: bool,
+    /// if the empty body is end of file, else the reading would block
+    _eof_on_empty: bool,
+    /// if any data has been received
+    _received: bool,
+    /// the max bytes returned by one read
+    _max_chunk: Option<usize>,
 }
 
 impl Stdin {
-    pub(super) fn new(buf_receiver: Receiver<anyhow::Result<Bytes, hyper::Error>>) -> Self {
+    pub(super) fn new(
+        buf_receiver: Receiver<anyhow::Result<Bytes, hyper::Error>>,
+        eof_on_empty: bool,
+        max_chunk: Option<usize>,
+    ) -> Self {
         Self {
             _buffer: Bytes::new(),
             _buf_receiver: buf_receiver,
             _is_eof: false,
+            _eof_on_empty: eof_on_empty,
+            _received: false,
+            _max_chunk: max_chunk,
         }
     }
 
@@ -156,6 +180,7 @@ impl Stdin {
         }
         match self._buf_receiver.blocking_recv() {
             Some(Ok(chunk)) => {
+                self._received |= chunk.has_remaining();
                 self._buffer = chunk;
                 Ok(true)
             }
@@ -163,6 +188,10 @@ impl Stdin {
                 self._is_eof = true;
                 Err(Error::new(ErrorKind::Other, e.to_string()))
             }
+            None if !self._eof_on_empty && !self._received => {
+                // keep the stdin open, the function should stop reading itself
+                Err(Error::new(ErrorKind::WouldBlock, "no data in stdin"))
+            }
             None => {
                 self._is_eof = true;
                 Ok(false)
@@ -174,11 +203,32 @@ impl Stdin {
     fn bytes_available(&self) -> usize {
         self._buffer.remaining()
     }
+
+    /// stop receiving the body and drop the buffered chunks,
+    /// return the bytes which the function has not read (best-effort, the ones not sent are unknown)
+    pub(super) fn close(&mut self) -> usize {
+        if self._is_eof {
+            return 0;
+        }
+        self._is_eof = true;
+        self._buf_receiver.close();
+        let mut unread = self._buffer.remaining();
+        self._buffer = Bytes::new();
+        while let Ok(Ok(chunk)) = self._buf_receiver.try_recv() {
+            unread += chunk.remaining();
+        }
+        unread
+    }
 }
 
 impl Read for Stdin {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let mut size = 0;
+        // smooth the copy of large body frames
+        let buf = match self._max_chunk {
+            Some(max) if max < buf.len() => &mut buf[..max],
+            _ => buf,
+        };
 
         while self.poll_data()? {
             let next_offset = size + cmp::min(self._buffer.remaining(), buf.len() - size);
@@ -216,17 +266,187 @@ impl_wasi_file!(Stdin);
 impl_not_seek!(Stdin);
 impl_unwritable!(Stdin);
 
-/// stdout for wasm function, just buffer it into vector
+/// the sender of function response, taken by the stdout when it begins streaming
+pub(super) type ResponseSender =
+    Arc<Mutex<Option<oneshot::Sender<anyhow::Result<Response<Body>>>>>>;
+
+/// how to make the response head from the function output
 #[derive(Debug, Clone)]
+pub(super) struct OutputHead {
+    pub(super) _content_type: HeaderValue,
+    pub(super) _sniff_content_type: bool,
+    pub(super) _parse_status_line: bool,
+}
+
+/// the trap which stops the function once the client of the streamed response has gone
+#[derive(Debug)]
+pub(super) struct ClientGone;
+
+impl fmt::Display for ClientGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the client has gone")
+    }
+}
+
+impl std::error::Error for ClientGone {}
+
+/// the state of streaming the output in response body once it exceeds the threshold
+#[derive(Debug)]
+pub(super) struct StdoutStream {
+    _threshold: usize,
+    _head: OutputHead,
+    _response: ResponseSender,
+    _runtime: Handle,
+    /// the body sender after streaming begins
+    _body: Option<hyper::body::Sender>,
+    /// if trap the function on the first write after the client has gone
+    _trap_on_client_gone: bool,
+}
+
+impl StdoutStream {
+    pub(super) fn new(
+        threshold: usize,
+        head: OutputHead,
+        response: ResponseSender,
+        runtime: Handle,
+    ) -> Self {
+        Self {
+            _threshold: threshold,
+            _head: head,
+            _response: response,
+            _runtime: runtime,
+            _body: None,
+            _trap_on_client_gone: false,
+        }
+    }
+
+    /// trap the function instead of failing the write, only for the writes in the wasi calls of the function
+    pub(super) fn trap_on_client_gone(mut self) -> Self {
+        self._trap_on_client_gone = true;
+        self
+    }
+}
+
+/// stdout for wasm function, buffer it into vector, or stream it if the stream is set
+#[derive(Debug)]
 pub(super) struct Stdout {
     _buffer: Vec<u8>,
+    _stream: Option<StdoutStream>,
+    /// the bytes written by the function
+    _written: usize,
+    /// the log that the output is copied to
+    _log: Option<Stderr>,
 }
 
 impl Stdout {
     pub(super) fn new() -> Self {
         Self {
             _buffer: Vec::new(),
+            _stream: None,
+            _written: 0,
+            _log: None,
+        }
+    }
+
+    /// the bytes written by the function, including the streamed ones
+    #[inline(always)]
+    pub(super) fn written(&self) -> usize {
+        self._written
+    }
+
+    /// also write the output to the log
+    pub(super) fn with_log(mut self, log: Option<Stderr>) -> Self {
+        self._log = log;
+        self
+    }
+
+    /// stream the output after it exceeds the threshold of stream
+    pub(super) fn with_stream(mut self, stream: Option<StdoutStream>) -> Self {
+        self._stream = stream;
+        self
+    }
+
+    /// make the response of the buffered output, none if the output has been streamed
+    pub(super) fn take_response(&mut self, head: &OutputHead) -> Option<Response<Body>> {
+        // dropping the body sender ends the streamed body
+        if let Some(Some(_)) = self._stream.as_mut().map(|s| s._body.take()) {
+            return None;
+        }
+        let body = self.response_head(head);
+        Some(body.map(|_| Body::from(self.take_buffer())))
+    }
+
+    /// end the streamed body as failed
+    pub(super) fn abort(&mut self) {
+        if let Some(body) = self._stream.as_mut().and_then(|s| s._body.take()) {
+            body.abort();
+        }
+    }
+
+    /// make the response head from the beginning of output
+    fn response_head(&mut self, head: &OutputHead) -> Response<()> {
+        let status = match head._parse_status_line {
+            true => self.take_status_line(),
+            false => None,
+        };
+        let content_type = match head._sniff_content_type {
+            true => self.sniff_content_type().map(HeaderValue::from_static),
+            false => None,
+        };
+        let mut response = Response::new(());
+        if let Some(status) = status {
+            *response.status_mut() = status;
         }
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            content_type.unwrap_or_else(|| head._content_type.clone()),
+        );
+        response
+    }
+
+    /// respond with the body channel once the output exceeds the threshold, then send the output to it
+    fn stream(&mut self) -> Result<()> {
+        let mut stream = match self._stream.take() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let result = self.stream_to(&mut stream);
+        let trap = result.is_err() && stream._trap_on_client_gone;
+        self._stream = Some(stream);
+        if trap {
+            // the function may ignore the failed write and keep computing the output nobody reads
+            RuntimeError::raise(Box::new(ClientGone));
+        }
+        result
+    }
+
+    fn stream_to(&mut self, stream: &mut StdoutStream) -> Result<()> {
+        if stream._body.is_none() {
+            if self._buffer.len() <= stream._threshold {
+                return Ok(());
+            }
+            let (body_sender, body) = Body::channel();
+            let response = self.response_head(&stream._head).map(|_| body);
+            let sent = match stream._response.lock().unwrap().take() {
+                Some(sender) => sender.send(Ok(response)).is_ok(),
+                None => false,
+            };
+            if !sent {
+                return Err(Error::new(ErrorKind::BrokenPipe, "the client has gone"));
+            }
+            stream._body = Some(body_sender);
+        }
+
+        if let Some(ref mut body) = stream._body {
+            if !self._buffer.is_empty() {
+                let data = Bytes::from(self.take_buffer());
+                stream
+                    ._runtime
+                    .block_on(body.send_data(data))
+                    .map_err(|_| Error::new(ErrorKind::BrokenPipe, "the client has gone"))?;
+            }
+        }
+        Ok(())
     }
 
     /// take the buffer data with zero copy
@@ -234,15 +454,109 @@ impl Stdout {
         std::mem::take(&mut self._buffer)
     }
 
+    /// take the leading `HTTP/1.1 404 Not Found` or `Status: 404` line as the response status,
+    /// the output without a valid status line is kept as it is
+    pub(super) fn take_status_line(&mut self) -> Option<StatusCode> {
+        let end = self
+            ._buffer
+            .iter()
+            .position(|b| *b == b'\n')
+            .unwrap_or(self._buffer.len());
+        let line = std::str::from_utf8(&self._buffer[..end]).ok()?;
+        let status = parse_status_line(line.trim_end_matches('\r'))?;
+        self._buffer.drain(..cmp::min(end + 1, self._buffer.len()));
+        Some(status)
+    }
+
+    /// guess the content type from the first bytes of the buffered output (magic numbers or text heuristic)
+    pub(super) fn sniff_content_type(&self) -> Option<&'static str> {
+        sniff_content_type(&self._buffer[..cmp::min(self._buffer.len(), SNIFF_LEN)])
+    }
+
+    /// copy the output to the log, the binary output stops the copy instead of failing the function
+    fn tee(&mut self, buf: &[u8]) {
+        if let Some(ref mut log) = self._log {
+            if let Err(e) = log.write_all(buf) {
+                debug!("Stop logging the stdout: {}", e);
+                self._log = None;
+            }
+        }
+    }
+
     #[inline(always)]
     fn bytes_available(&self) -> usize {
         0
     }
 }
 
+/// parse the status code from `HTTP/<version> <code> [reason]` or `Status: <code> [reason]`
+fn parse_status_line(line: &str) -> Option<StatusCode> {
+    let rest = match line.strip_prefix("HTTP/") {
+        Some(rest) => {
+            let (version, rest) = rest.split_once(' ')?;
+            if !matches!(version, "1.0" | "1.1" | "2" | "2.0") {
+                return None;
+            }
+            rest
+        }
+        None => line.strip_prefix("Status:")?.trim_start(),
+    };
+    let code = rest.split(' ').next()?;
+    if code.len() != 3 {
+        return None;
+    }
+    StatusCode::from_bytes(code.as_bytes()).ok()
+}
+
+/// the max number of bytes to inspect for content type sniffing
+const SNIFF_LEN: usize = 512;
+
+/// the magic numbers of the common binary formats
+const MAGIC_NUMBERS: [(&[u8], &str); 7] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b\x08", "application/x-gzip"),
+    (b"\0asm", "application/wasm"),
+];
+
+fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    if data.is_empty() {
+        return None;
+    }
+    for (magic, content_type) in MAGIC_NUMBERS {
+        if data.starts_with(magic) {
+            return Some(content_type);
+        }
+    }
+
+    // the text may be truncated in the middle of a character
+    let text = match std::str::from_utf8(data) {
+        Ok(s) => s,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&data[..e.valid_up_to()]).unwrap(),
+        Err(_) => return None,
+    };
+    let text = text.trim_start();
+    let lower = text.to_ascii_lowercase();
+    if text.starts_with('{') || text.starts_with('[') {
+        Some("application/json")
+    } else if lower.starts_with("<!doctype html") || lower.starts_with("<html") {
+        Some("text/html; charset=utf-8")
+    } else if lower.starts_with("<?xml") {
+        Some("application/xml")
+    } else {
+        Some("text/plain; charset=utf-8")
+    }
+}
+
 impl Write for Stdout {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.tee(buf);
         self._buffer.extend(buf);
+        self._written += buf.len();
+        self.stream()?;
         Ok(buf.len())
     }
 
@@ -251,8 +565,10 @@ impl Write for Stdout {
     }
 
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.tee(buf);
         self._buffer.extend(buf);
-        Ok(())
+        self._written += buf.len();
+        self.stream()
     }
 }
 
@@ -261,6 +577,12 @@ impl_wasi_file!(Stdout);
 impl_not_seek!(Stdout);
 impl_unreadable!(Stdout);
 
+/// the marker logged once the stderr output exceeds the limit
+const STDERR_TRUNCATED_MARKER: &[u8] = b"\n[stderr truncated]\n";
+
+/// the max bytes of the last stderr output told in the error of the failed function
+const STDERR_TAIL_BYTES: usize = 4096;
+
 /// redirect stderr to watchdog log
 #[derive(Debug)]
 pub(super) struct Stderr {
@@ -268,15 +590,82 @@ pub(super) struct Stderr {
     _buffer: Vec<u8>,
     _log_prefix: bool,
     _buf_max_size: usize,
+    /// the max bytes to log for one invocation, the rest output is dropped
+    _max_bytes: Option<usize>,
+    /// the bytes has been accepted
+    _written_bytes: usize,
+    /// keep the recent lines for debugging
+    _log_ring: Option<LogRing>,
+    /// the prefix of every logged line, to tell the teed stdout from stderr
+    _line_prefix: &'static str,
+    /// the last output, kept after it is logged
+    _tail: Vec<u8>,
 }
 
 impl Stderr {
-    pub(super) fn new(logger_name: String, log_prefix: bool, log_buf_size: usize) -> Self {
+    pub(super) fn new(
+        logger_name: String,
+        log_prefix: bool,
+        log_buf_size: usize,
+        max_bytes: Option<usize>,
+        log_ring: Option<LogRing>,
+    ) -> Self {
         Self {
             _logger_name: logger_name,
             _buffer: Vec::new(),
             _log_prefix: log_prefix,
             _buf_max_size: log_buf_size,
+            _max_bytes: max_bytes,
+            _written_bytes: 0,
+            _log_ring: log_ring,
+            _line_prefix: "",
+            _tail: Vec::new(),
+        }
+    }
+
+    /// get the last output of at most `STDERR_TAIL_BYTES`
+    pub(super) fn tail(&self) -> String {
+        String::from_utf8_lossy(&self._tail).trim_end().to_string()
+    }
+
+    #[inline(always)]
+    fn keep_tail(&mut self, buf: &[u8]) {
+        self._tail
+            .extend(&buf[buf.len().saturating_sub(STDERR_TAIL_BYTES)..]);
+        let over = self._tail.len().saturating_sub(STDERR_TAIL_BYTES);
+        self._tail.drain(..over);
+    }
+
+    /// prefix every logged line
+    pub(super) fn with_line_prefix(mut self, prefix: &'static str) -> Self {
+        self._line_prefix = prefix;
+        self
+    }
+
+    /// append the output to buffer, and truncate it if over the limit
+    #[inline(always)]
+    fn append(&mut self, buf: &[u8]) {
+        let max_bytes = match self._max_bytes {
+            Some(max) => max,
+            None => {
+                self._buffer.extend(buf);
+                return;
+            }
+        };
+
+        if self._written_bytes > max_bytes {
+            return; // has been truncated
+        }
+        let mut n = cmp::min(buf.len(), max_bytes - self._written_bytes);
+        // do not cut a utf-8 character, else the whole buffer cannot be logged
+        while n < buf.len() && n > 0 && buf[n] & 0xc0 == 0x80 {
+            n -= 1;
+        }
+        self._buffer.extend(&buf[..n]);
+        self._written_bytes += n;
+        if n < buf.len() {
+            self._buffer.extend(STDERR_TRUNCATED_MARKER);
+            self._written_bytes = max_bytes + 1;
         }
     }
 
@@ -297,12 +686,25 @@ impl Stderr {
                 }
             };
 
+            if let Some(ref ring) = self._log_ring {
+                str.split('\n')
+                    .filter(|s| !s.is_empty())
+                    .for_each(|s| ring.push(&format!("{}{}", self._line_prefix, s)));
+            }
+
             if self._log_prefix {
                 str.split('\n').for_each(|s| {
                     if !s.is_empty() {
-                        eprintln!("[watchdog function] {}: {}", self._logger_name, s);
+                        eprintln!(
+                            "[watchdog function] {}: {}{}",
+                            self._logger_name, self._line_prefix, s
+                        );
                     }
                 });
+            } else if !self._line_prefix.is_empty() {
+                str.split('\n')
+                    .filter(|s| !s.is_empty())
+                    .for_each(|s| eprintln!("{}{}", self._line_prefix, s));
             } else {
                 eprint!("{}", str);
             }
@@ -315,7 +717,8 @@ impl Stderr {
 /// bind to the log
 impl Write for Stderr {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self._buffer.extend(buf);
+        self.keep_tail(buf);
+        self.append(buf);
         if self._buffer.len() >= self._buf_max_size {
             self.flush_inner()?;
         }
@@ -327,7 +730,8 @@ impl Write for Stderr {
     }
 
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
-        self._buffer.extend(buf);
+        self.keep_tail(buf);
+        self.append(buf);
         if self._buffer.len() >= self._buf_max_size {
             return self.flush_inner();
         }
@@ -347,3 +751,289 @@ impl Drop for Stderr {
 impl_wasi_file!(Stderr);
 impl_unreadable!(Stderr);
 impl_not_seek!(Stderr);
+
+#[cfg(test)]
+mod test {
+    use super::{
+        OutputHead, Stderr, Stdin, Stdout, StdoutStream, STDERR_TAIL_BYTES, STDERR_TRUNCATED_MARKER,
+    };
+    use crate::LogRing;
+    use hyper::body::{to_bytes, Bytes, HttpBody};
+    use hyper::header::CONTENT_TYPE;
+    use hyper::http::HeaderValue;
+    use hyper::StatusCode;
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::{mpsc, oneshot};
+
+    #[test]
+    fn test_stdout_stream() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let head = OutputHead {
+            _content_type: HeaderValue::from_static("text/plain"),
+            _sniff_content_type: false,
+            _parse_status_line: false,
+        };
+        let output = |data: &[u8]| {
+            let (sender, receiver) = oneshot::channel();
+            let sender = Arc::new(Mutex::new(Some(sender)));
+            let stream = StdoutStream::new(8, head.clone(), sender, rt.handle().clone());
+            let mut stdout = Stdout::new().with_stream(Some(stream));
+            stdout.write_all(data).unwrap();
+            let response = stdout.take_response(&head);
+            drop(stdout);
+            response.unwrap_or_else(|| rt.block_on(receiver).unwrap().unwrap())
+        };
+
+        // the small output is buffered with known length
+        let res = output(b"small");
+        assert_eq!(res.body().size_hint().exact(), Some(5));
+        assert_eq!(rt.block_on(to_bytes(res.into_body())).unwrap(), "small");
+
+        // the large output is streamed in chunks
+        let res = output(b"large output");
+        assert_eq!(res.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(res.body().size_hint().exact(), None);
+        assert_eq!(
+            rt.block_on(to_bytes(res.into_body())).unwrap(),
+            "large output"
+        );
+    }
+
+    #[test]
+    fn test_stdout_stream_every_write() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let head = OutputHead {
+            _content_type: HeaderValue::from_static("text/plain"),
+            _sniff_content_type: false,
+            _parse_status_line: false,
+        };
+        let chunks = ["one", "two", "three"];
+        let (sender, receiver) = oneshot::channel();
+        let sender = Arc::new(Mutex::new(Some(sender)));
+        // the zero threshold set by `wasm_stream_stdout`
+        let stream = StdoutStream::new(0, head.clone(), sender, rt.handle().clone());
+        let writer = std::thread::spawn(move || {
+            let mut stdout = Stdout::new().with_stream(Some(stream));
+            for chunk in chunks {
+                stdout.write_all(chunk.as_bytes()).unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            assert!(stdout.take_response(&head).is_none());
+        });
+
+        // every chunk arrives while the function is still writing
+        let start = Instant::now();
+        let mut body = rt.block_on(receiver).unwrap().unwrap().into_body();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let data = rt.block_on(body.data()).unwrap().unwrap();
+            assert_eq!(&data[..], chunk.as_bytes());
+            assert!(start.elapsed() < Duration::from_millis(200 * (i as u64 + 1)));
+        }
+        assert!(rt.block_on(body.data()).is_none());
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_status_line() {
+        let status_line = |out: &str| {
+            let mut stdout = Stdout::new();
+            stdout.write_all(out.as_bytes()).unwrap();
+            let status = stdout.take_status_line();
+            (status, String::from_utf8(stdout.take_buffer()).unwrap())
+        };
+
+        assert_eq!(
+            status_line("HTTP/1.1 404 Not Found\r\nmissing"),
+            (Some(StatusCode::NOT_FOUND), "missing".to_string())
+        );
+        assert_eq!(
+            status_line("Status: 201\ncreated\n"),
+            (Some(StatusCode::CREATED), "created\n".to_string())
+        );
+        assert_eq!(
+            status_line("Status: 204"),
+            (Some(StatusCode::NO_CONTENT), String::new())
+        );
+
+        // the body only starts like a status line
+        for out in [
+            "HTTP is the protocol\n",
+            "HTTP/1.1 four hundred\n",
+            "HTTP/1.1 4040 Not Found\n",
+            "Status: ok\n",
+            "",
+        ] {
+            assert_eq!(status_line(out), (None, out.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_stdin_eof_on_empty() {
+        let stdin = |chunks: &[&'static str], eof_on_empty| {
+            let (sender, receiver) = mpsc::channel(chunks.len() + 1);
+            for chunk in chunks {
+                sender.try_send(Ok(Bytes::from(*chunk))).unwrap();
+            }
+            Stdin::new(receiver, eof_on_empty, None)
+        };
+        let mut buf = [0u8; 8];
+
+        // the empty body reads as EOF
+        assert_eq!(stdin(&[], true).read(&mut buf).unwrap(), 0);
+
+        // the empty body keeps the stdin open
+        let mut open = stdin(&[], false);
+        for _ in 0..2 {
+            let err = open.read(&mut buf).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        }
+
+        // the non-empty body still ends with EOF
+        let mut data = stdin(&["hello"], false);
+        let mut out = String::new();
+        data.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello");
+    }
+
+    #[test]
+    fn test_stdin_max_chunk() {
+        let (sender, receiver) = mpsc::channel(1);
+        sender.try_send(Ok(Bytes::from(vec![1u8; 10000]))).unwrap();
+        drop(sender);
+        let mut stdin = Stdin::new(receiver, true, Some(1024));
+
+        let mut buf = vec![0u8; 1 << 16];
+        let mut total = 0;
+        loop {
+            let n = stdin.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            assert!(n <= 1024);
+            total += n;
+        }
+        assert_eq!(total, 10000);
+    }
+
+    #[test]
+    fn test_stdin_close() {
+        let (sender, receiver) = mpsc::channel(4);
+        for _ in 0..3 {
+            sender
+                .try_send(Ok(Bytes::from(vec![1u8; 1 << 20])))
+                .unwrap();
+        }
+        let mut stdin = Stdin::new(receiver, true, None);
+        let mut buf = vec![0u8; 1024];
+        assert_eq!(stdin.read(&mut buf).unwrap(), 1024);
+
+        // the function ignores the rest, which is dropped at once
+        assert_eq!(stdin.close(), (3 << 20) - 1024);
+        assert!(sender.is_closed());
+        assert!(sender.try_send(Ok(Bytes::from("more"))).is_err());
+        assert_eq!(stdin.read(&mut buf).unwrap(), 0);
+        assert_eq!(stdin.close(), 0);
+
+        // nothing left once read to the end
+        let (sender, receiver) = mpsc::channel(1);
+        sender.try_send(Ok(Bytes::from("body"))).unwrap();
+        drop(sender);
+        let mut stdin = Stdin::new(receiver, true, None);
+        let mut body = String::new();
+        stdin.read_to_string(&mut body).unwrap();
+        assert_eq!(stdin.close(), 0);
+    }
+
+    #[test]
+    fn test_sniff_content_type() {
+        let sniff = |data: &[u8]| {
+            let mut stdout = Stdout::new();
+            stdout.write_all(data).unwrap();
+            stdout.sniff_content_type()
+        };
+
+        assert_eq!(sniff(b" {\"a\": 1}\n"), Some("application/json"));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"hello"), Some("text/plain; charset=utf-8"));
+        assert_eq!(
+            sniff(&[0xe4, 0xbd, 0xa0, 0xe5]),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(sniff(&[0x00, 0xff, 0xfe]), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn test_stderr_truncated() {
+        let mut stderr = Stderr::new("test".to_string(), false, 1 << 20, Some(8), None);
+        stderr.write_all(b"12345").unwrap();
+        assert_eq!(stderr.write(b"67890").unwrap(), 5);
+        stderr.write_all(b"dropped").unwrap();
+
+        let mut expect = b"12345678".to_vec();
+        expect.extend(STDERR_TRUNCATED_MARKER);
+        assert_eq!(stderr._buffer, expect);
+        stderr._buffer.clear(); // do not print it when drop
+
+        // the multi-byte character over the limit is dropped as a whole
+        let mut stderr = Stderr::new("test".to_string(), false, 1 << 20, Some(8), None);
+        stderr.write_all("1234567\u{e9}".as_bytes()).unwrap();
+        let mut expect = b"1234567".to_vec();
+        expect.extend(STDERR_TRUNCATED_MARKER);
+        assert_eq!(stderr._buffer, expect);
+        assert!(stderr.flush_inner().is_ok());
+        assert!(stderr._buffer.is_empty());
+    }
+
+    #[test]
+    fn test_stderr_tail() {
+        // flushed at once
+        let mut stderr = Stderr::new("test".to_string(), false, 1, None, None);
+        stderr.write_all(b"first\n").unwrap();
+        assert_eq!(stderr.write(b"error: bad input\n").unwrap(), 17);
+        assert_eq!(stderr.tail(), "first\nerror: bad input");
+
+        let long = vec![b'x'; STDERR_TAIL_BYTES];
+        stderr.write_all(&long).unwrap();
+        stderr.write_all(b"end").unwrap();
+        let tail = stderr.tail();
+        assert_eq!(tail.len(), STDERR_TAIL_BYTES);
+        assert!(tail.ends_with("xend"));
+    }
+
+    #[test]
+    fn test_stderr_log_ring() {
+        let ring = LogRing::new(2);
+        let mut stderr = Stderr::new("test".to_string(), true, 1 << 20, None, Some(ring.clone()));
+        stderr.write_all(b"line 1\nline 2\n").unwrap();
+        stderr.write_all(b"line 3\n").unwrap();
+        drop(stderr);
+        assert_eq!(ring.lines(), vec!["line 2", "line 3"]);
+    }
+
+    #[test]
+    fn test_stdout_log() {
+        let ring = LogRing::new(4);
+        let log = Stderr::new("test".to_string(), true, 1 << 20, None, Some(ring.clone()))
+            .with_line_prefix("stdout: ");
+        let mut stdout = Stdout::new().with_log(Some(log));
+        stdout.write_all(b"hello\nworld\n").unwrap();
+        assert_eq!(stdout.take_buffer(), b"hello\nworld\n");
+        drop(stdout);
+        assert_eq!(ring.lines(), vec!["stdout: hello", "stdout: world"]);
+
+        // the binary output stops the log but is still the response
+        let log = Stderr::new("test".to_string(), true, 1, None, Some(ring.clone()));
+        let mut stdout = Stdout::new().with_log(Some(log));
+        stdout.write_all(b"\xff\xfe").unwrap();
+        assert!(stdout._log.is_none());
+        assert_eq!(stdout.take_buffer(), b"\xff\xfe");
+    }
+}
@@ -1,10 +1,26 @@
-use log::{debug, info};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use prometheus::Counter;
 /// This custom thread-pool implementation is study from https://crates.io/crates/threadpool
 /// But the condition variable we use implements blocking queue instead of channel
 use std::collections::VecDeque;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    /// the number of panicked worker threads of all thread pools, registered by the metrics server
+    pub(crate) static ref WORKER_PANICS_TOTAL: Counter = Counter::new(
+        "wasm_worker_panics_total",
+        "total panicked wasm worker threads"
+    )
+    .unwrap();
+}
+
+/// The max number of panic time records held by a thread pool
+const MAX_PANIC_RECORDS: usize = 4096;
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
@@ -15,6 +31,9 @@ struct ThreadPoolEntry {
     /// The size of the stack for the spawned thread in bytes  (readonly)
     _stack_size: Option<usize>,
 
+    /// The max number of queued jobs accepted by `try_execute`, none means unbounded (readonly)
+    _max_queue: Option<usize>,
+
     /// The job queue
     _job_queue: Mutex<VecDeque<Job>>,
     // The condition variable for job queue
@@ -22,10 +41,17 @@ struct ThreadPoolEntry {
 
     /// The number of threads in pool
     _thread_num: AtomicUsize,
+    /// The number of running threads, including the ones finishing their jobs before shrinking
+    _live_thread_num: AtomicUsize,
     /// The running state thread numbers
     _active_thread_num: AtomicUsize,
     /// The panicked thread numbers
     _panicked_thread_num: AtomicUsize,
+    /// The time of recent panics, the oldest is at front
+    _panic_times: Mutex<VecDeque<Instant>>,
+
+    /// If the pool stops accepting jobs, the workers exit once the queue is drained
+    _stopped: AtomicBool,
 
     /// The mutex and condition variable for join
     _join_mutex: Mutex<()>,
@@ -39,7 +65,7 @@ struct ThreadPoolEntry {
 /// ```
 /// let thread_num = 10;
 /// let job_num = 100;
-/// let pool = ThreadPool::new(thread_num, None, None);
+/// let pool = ThreadPool::new(thread_num, None, None, None);
 /// for _job in 0..job_num {
 ///     pool.execute(move || {
 ///         assert_eq!(1, 1);
@@ -57,6 +83,7 @@ impl ThreadPool {
         thread_num: usize,
         thread_name: Option<String>,
         stack_size: Option<usize>,
+        max_queue: Option<usize>,
     ) -> Self {
         info!(
             "Start thread pool `{}`, thread number is {}",
@@ -68,11 +95,15 @@ impl ThreadPool {
             _inner: Arc::new(ThreadPoolEntry {
                 _thread_name: thread_name,
                 _stack_size: stack_size,
+                _max_queue: max_queue,
                 _job_queue: Mutex::new(VecDeque::new()),
                 _job_queue_not_empty: Condvar::default(),
                 _thread_num: AtomicUsize::new(thread_num),
+                _live_thread_num: AtomicUsize::new(0),
                 _active_thread_num: AtomicUsize::new(0),
                 _panicked_thread_num: AtomicUsize::new(0),
+                _panic_times: Mutex::new(VecDeque::new()),
+                _stopped: AtomicBool::new(false),
                 _join_mutex: Mutex::default(),
                 _join_cond_var: Condvar::default(),
             }),
@@ -91,18 +122,86 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
+        if self.is_stopped() {
+            debug!("The thread pool is shut down, drop the job");
+            return;
+        }
+        self.scale_from_zero();
+
         let mut q = self._inner._job_queue.lock().unwrap();
         q.push_back(Box::new(f));
         self._inner._job_queue_not_empty.notify_one();
     }
 
+    /// queue the job unless the queue is full or the pool is shut down,
+    /// the rejected job is given back as the error
+    #[inline(always)]
+    pub(crate) fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.is_stopped() {
+            return Err(f);
+        }
+        self.scale_from_zero();
+
+        let mut q = self._inner._job_queue.lock().unwrap();
+        if let Some(max_queue) = self._inner._max_queue {
+            if q.len() >= max_queue {
+                return Err(f);
+            }
+        }
+        q.push_back(Box::new(f));
+        self._inner._job_queue_not_empty.notify_one();
+        Ok(())
+    }
+
+    /// run the job once on every worker and wait for all of them,
+    /// every worker holds on after its job so no one runs it twice
+    pub(crate) fn warmup<F>(&self, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let thread_num = self.thread_num();
+        if thread_num == 0 || self.is_stopped() {
+            return;
+        }
+
+        let f = Arc::new(f);
+        let barrier = Arc::new(Barrier::new(thread_num + 1));
+        for _ in 0..thread_num {
+            let (f, barrier) = (f.clone(), barrier.clone());
+            self.execute(move || {
+                // the barrier must be reached even if the job panics
+                if std::panic::catch_unwind(AssertUnwindSafe(|| f())).is_err() {
+                    warn!("The warmup job panicked in {:?}", thread::current().id());
+                }
+                barrier.wait();
+            });
+        }
+        barrier.wait();
+    }
+
+    /// spawn a worker on demand for the first job of the empty pool
+    #[inline(always)]
+    fn scale_from_zero(&self) {
+        if self
+            ._inner
+            ._thread_num
+            .compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            debug!("Spawn a worker on demand for the empty thread pool");
+            self.spawn_one();
+        }
+    }
+
     #[inline(always)]
     pub(crate) fn queued_job_num(&self) -> usize {
         self._inner._job_queue.lock().unwrap().len()
     }
 
     #[inline(always)]
-    #[allow(dead_code)]
     pub(crate) fn active_thread_num(&self) -> usize {
         self._inner._active_thread_num.load(Ordering::Relaxed)
     }
@@ -113,25 +212,99 @@ impl ThreadPool {
         self._inner._panicked_thread_num.load(Ordering::Relaxed)
     }
 
+    /// get the number of panics in the recent time window
+    pub(crate) fn recent_panic_num(&self, window: Duration) -> usize {
+        let times = self._inner._panic_times.lock().unwrap();
+        times
+            .iter()
+            .rev()
+            .take_while(|t| t.elapsed() <= window)
+            .count()
+    }
+
     #[inline(always)]
     pub(crate) fn thread_num(&self) -> usize {
         self._inner._thread_num.load(Ordering::Relaxed)
     }
 
+    /// get the number of threads still running, the shrunk ones are counted until their jobs finish
+    #[inline(always)]
+    pub(crate) fn live_thread_num(&self) -> usize {
+        self._inner._live_thread_num.load(Ordering::SeqCst)
+    }
+
     pub(crate) fn set_thread_num(&self, size: usize) {
-        let old_size = self._inner._thread_num.swap(size, Ordering::Release);
+        let old_size = self._inner._thread_num.swap(size, Ordering::SeqCst);
         if old_size < size {
-            // if expand, spawn the new threads
-            for _ in old_size..size {
+            // if expand, spawn the new threads, the shrinking ones which are still running are kept
+            for _ in self.live_thread_num()..size {
                 self.spawn_one();
             }
+        } else if old_size > size {
+            // wake the idle threads to exit, the busy ones exit after their jobs
+            let _q = self._inner._job_queue.lock().unwrap();
+            self._inner._job_queue_not_empty.notify_all();
+        }
+    }
+
+    /// if there are more threads than the pool size, count the thread out and return true
+    fn try_retire(&self) -> bool {
+        let live = &self._inner._live_thread_num;
+        let mut n = live.load(Ordering::SeqCst);
+        while n > self.thread_num() {
+            match live.compare_exchange(n, n - 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return true,
+                Err(now) => n = now,
+            }
+        }
+        false
+    }
+
+    /// grow a worker if the queued jobs exceed the threshold, up to the max, return if grown
+    pub(crate) fn grow_for_burst(&self, threshold: usize, max: usize) -> bool {
+        let size = self.thread_num();
+        if self.queued_job_num() > threshold && size < max {
+            self.set_thread_num(size + 1);
+            debug!("Grow the thread pool to {} for the burst", size + 1);
+            return true;
+        }
+        false
+    }
+
+    /// shrink back to the base size once no job is queued, return if shrunk
+    pub(crate) fn shrink_after_burst(&self, base: usize) -> bool {
+        if self.thread_num() > base && self.queued_job_num() == 0 {
+            self.set_thread_num(base);
+            debug!("Shrink the thread pool back to {} after the burst", base);
+            return true;
+        }
+        false
+    }
+
+    #[inline(always)]
+    fn is_stopped(&self) -> bool {
+        self._inner._stopped.load(Ordering::SeqCst)
+    }
+
+    /// if the pool is shut down, count the thread out for the shutdown waiting it and return true
+    fn try_stop(&self) -> bool {
+        if !self.is_stopped() {
+            return false;
         }
+        self._inner._live_thread_num.fetch_sub(1, Ordering::SeqCst);
+        let _lock = self._inner._join_mutex.lock().unwrap();
+        self._inner._join_cond_var.notify_all();
+        true
     }
 
-    /// get a job from job queue
+    /// get a job from job queue, `None` if the thread should exit for shrinking or shutdown
     fn get_job(&self) -> Option<Job> {
         let mut q = self._inner._job_queue.lock().unwrap();
         while q.is_empty() {
+            // exit only after the queue is drained
+            if self.try_stop() || self.try_retire() {
+                return None;
+            }
             q = self._inner._job_queue_not_empty.wait(q).unwrap();
         }
 
@@ -153,13 +326,14 @@ impl ThreadPool {
             builder = builder.stack_size(stack_size);
         }
 
+        self._inner._live_thread_num.fetch_add(1, Ordering::SeqCst);
         let pool = self.clone();
         builder
             .spawn(move || {
                 let mut sentinel = Sentinel::new(&pool);
 
                 loop {
-                    if pool._inner._active_thread_num.load(Ordering::SeqCst) > pool.thread_num() {
+                    if pool.try_retire() {
                         break; // shrink
                     }
 
@@ -188,7 +362,6 @@ impl ThreadPool {
     }
 
     #[inline(always)]
-    #[allow(dead_code)]
     fn has_work(&self) -> bool {
         self._inner._active_thread_num.load(Ordering::SeqCst) > 0 || self.queued_job_num() > 0
     }
@@ -205,6 +378,45 @@ impl ThreadPool {
             lock = self._inner._join_cond_var.wait(lock).unwrap();
         }
     }
+
+    /// stop accepting jobs, then wait for the workers to finish the running and queued jobs and exit
+    pub(crate) fn shutdown(&self) {
+        info!(
+            "Shut down thread pool `{}`",
+            self._inner._thread_name.as_deref().unwrap_or("None")
+        );
+        self._inner._stopped.store(true, Ordering::SeqCst);
+        {
+            // wake the idle threads to exit
+            let _q = self._inner._job_queue.lock().unwrap();
+            self._inner._job_queue_not_empty.notify_all();
+        }
+
+        let mut lock = self._inner._join_mutex.lock().unwrap();
+        while self.live_thread_num() > 0 {
+            lock = self._inner._join_cond_var.wait(lock).unwrap();
+        }
+    }
+
+    /// wait for no jobs in pool at most the timeout,
+    /// return the number of workers still busy which are abandoned
+    pub(crate) fn join_timeout(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut lock = self._inner._join_mutex.lock().unwrap();
+        while self.has_work() {
+            let now = Instant::now();
+            if now >= deadline {
+                return self.active_thread_num();
+            }
+            lock = self
+                ._inner
+                ._join_cond_var
+                .wait_timeout(lock, deadline - now)
+                .unwrap()
+                .0;
+        }
+        0
+    }
 }
 
 /// for fix the panicked thread in thread pool
@@ -238,6 +450,10 @@ impl<'a> Drop for Sentinel<'a> {
             if previous == 1 && self._pool.queued_job_num() == 0 {
                 self._pool._inner._join_cond_var.notify_all();
             }
+            self._pool
+                ._inner
+                ._live_thread_num
+                .fetch_sub(1, Ordering::SeqCst);
 
             if std::thread::panicking() {
                 debug!("{:?} panic", thread::current());
@@ -245,6 +461,13 @@ impl<'a> Drop for Sentinel<'a> {
                     ._inner
                     ._panicked_thread_num
                     .fetch_add(1, Ordering::SeqCst);
+                WORKER_PANICS_TOTAL.inc();
+
+                let mut times = self._pool._inner._panic_times.lock().unwrap();
+                if times.len() >= MAX_PANIC_RECORDS {
+                    times.pop_front();
+                }
+                times.push_back(Instant::now());
             }
             self._pool.spawn_one(); // spawn a new thread in pool to fix the panicked thread
         }
@@ -253,13 +476,15 @@ impl<'a> Drop for Sentinel<'a> {
 
 #[cfg(test)]
 mod test {
-    use super::ThreadPool;
+    use super::{ThreadPool, WORKER_PANICS_TOTAL};
     use std::{
+        collections::HashSet,
+        sync::atomic::AtomicBool,
         sync::atomic::{AtomicUsize, Ordering},
-        sync::{Arc, Barrier},
+        sync::{Arc, Barrier, Mutex},
         thread,
         thread::sleep,
-        time::Duration,
+        time::{Duration, Instant},
     };
 
     #[test]
@@ -268,7 +493,7 @@ mod test {
         let b_start = Arc::new(Barrier::new(thread_num + 1));
         let b_end = Arc::new(Barrier::new(thread_num + 1));
 
-        let pool = ThreadPool::new(thread_num, None, None);
+        let pool = ThreadPool::new(thread_num, None, None, None);
         for _job in 0..thread_num {
             let _b_start = b_start.clone();
             let _b_end = b_end.clone();
@@ -291,7 +516,7 @@ mod test {
     #[test]
     fn test_panic() {
         let thread_num = 5;
-        let pool = ThreadPool::new(thread_num, Some("thread_name".parse().unwrap()), None);
+        let pool = ThreadPool::new(thread_num, Some("thread_name".parse().unwrap()), None, None);
 
         let exec_num = Arc::new(AtomicUsize::new(0));
         for _job in 0..thread_num {
@@ -315,11 +540,66 @@ mod test {
         assert_eq!(thread_num, exec_num.load(Ordering::Acquire));
     }
 
+    #[test]
+    fn test_panic_metric() {
+        let thread_num = 3;
+        let before = WORKER_PANICS_TOTAL.get();
+        let pool = ThreadPool::new(thread_num, None, None, None);
+        for _job in 0..thread_num {
+            pool.execute(move || {
+                panic!("{:?} should panic\n", thread::current().id());
+            });
+        }
+
+        pool.join();
+        assert_eq!(thread_num, pool.panicked_thread_num());
+        assert_eq!(thread_num, pool.recent_panic_num(Duration::from_secs(60)));
+        // other tests may panic the workers concurrently
+        assert!(WORKER_PANICS_TOTAL.get() - before >= thread_num as f64);
+    }
+
+    #[test]
+    fn test_stack_size() {
+        // larger than the default 2MiB stack of spawned threads
+        const LOCAL_SIZE: usize = 4 << 20;
+        // unoptimized builds may copy the local array several times
+        let pool = ThreadPool::new(1, None, Some(LOCAL_SIZE * 8), None);
+        let exec_num = Arc::new(AtomicUsize::new(0));
+
+        let num = exec_num.clone();
+        pool.execute(move || {
+            let local = std::hint::black_box([1u8; LOCAL_SIZE]);
+            num.fetch_add(local[LOCAL_SIZE - 1] as usize, Ordering::Release);
+        });
+
+        pool.join();
+        assert_eq!(0, pool.panicked_thread_num());
+        assert_eq!(1, exec_num.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn test_scale_from_zero() {
+        let pool = ThreadPool::new(0, None, None, None);
+        assert_eq!(0, pool.thread_num());
+
+        let exec_num = Arc::new(AtomicUsize::new(0));
+        for _job in 0..3 {
+            let e = exec_num.clone();
+            pool.execute(move || {
+                e.fetch_add(1, Ordering::Release);
+            });
+        }
+
+        pool.join();
+        assert_eq!(1, pool.thread_num());
+        assert_eq!(3, exec_num.load(Ordering::Acquire));
+    }
+
     #[test]
     fn test_shrink() {
         let before = 10;
         let after = 2;
-        let pool = ThreadPool::new(before, None, None);
+        let pool = ThreadPool::new(before, None, None, None);
         for _job in 0..before {
             pool.execute(move || {
                 assert_eq!(1, 1);
@@ -342,7 +622,7 @@ mod test {
 
     #[test]
     fn test_expand() {
-        let pool = ThreadPool::new(1, None, None);
+        let pool = ThreadPool::new(1, None, None, None);
         for _job in 0..5 {
             pool.execute(move || {
                 sleep(Duration::from_millis(500));
@@ -367,7 +647,7 @@ mod test {
     #[test]
     fn test_empty() {
         let thread_num = 10;
-        let pool = ThreadPool::new(thread_num, None, None);
+        let pool = ThreadPool::new(thread_num, None, None, None);
         assert_eq!(thread_num, pool.thread_num());
         assert_eq!(0, pool.panicked_thread_num());
         assert_eq!(0, pool.active_thread_num());
@@ -380,7 +660,7 @@ mod test {
     fn test_join() {
         let thread_num = 10;
         let test_num: usize = 50;
-        let pool = ThreadPool::new(thread_num, None, None);
+        let pool = ThreadPool::new(thread_num, None, None, None);
         let exec_num = Arc::new(AtomicUsize::new(0));
 
         for _job in 0..test_num {
@@ -414,4 +694,174 @@ mod test {
         pool.join();
         assert_eq!(test_num * 2, exec_num.load(Ordering::Acquire));
     }
+
+    #[test]
+    fn test_join_timeout() {
+        let pool = ThreadPool::new(2, None, None, None);
+        let released = Arc::new(AtomicBool::new(false));
+
+        // a wedged worker which never finishes until released
+        let r = released.clone();
+        pool.execute(move || {
+            while !r.load(Ordering::Acquire) {
+                sleep(Duration::from_millis(10));
+            }
+        });
+        pool.execute(|| {});
+
+        let start = Instant::now();
+        assert_eq!(1, pool.join_timeout(Duration::from_millis(300)));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_secs(3));
+
+        released.store(true, Ordering::Release);
+        assert_eq!(0, pool.join_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_burst() {
+        let pool = ThreadPool::new(1, None, None, None);
+        for _job in 0..6 {
+            pool.execute(move || {
+                sleep(Duration::from_millis(200));
+            });
+            pool.grow_for_burst(0, 4);
+        }
+        // never over the max
+        assert!(pool.thread_num() > 1);
+        assert!(pool.thread_num() <= 4);
+        assert!(!pool.shrink_after_burst(1));
+
+        pool.join();
+        assert!(!pool.grow_for_burst(0, 4));
+        assert!(pool.shrink_after_burst(1));
+        assert_eq!(1, pool.thread_num());
+    }
+
+    #[test]
+    fn test_drain_before_shrink() {
+        let pool = ThreadPool::new(2, None, None, None);
+        let released = Arc::new(AtomicBool::new(false));
+        for _job in 0..2 {
+            let r = released.clone();
+            pool.execute(move || {
+                while !r.load(Ordering::Acquire) {
+                    sleep(Duration::from_millis(10));
+                }
+            });
+        }
+        sleep(Duration::from_millis(100));
+        assert_eq!(2, pool.active_thread_num());
+
+        // the busy workers are still counted
+        pool.set_thread_num(1);
+        sleep(Duration::from_millis(100));
+        assert_eq!(1, pool.thread_num());
+        assert_eq!(2, pool.live_thread_num());
+
+        released.store(true, Ordering::Release);
+        pool.join();
+        let start = Instant::now();
+        while pool.live_thread_num() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, pool.live_thread_num());
+
+        // the idle workers exit at once
+        pool.set_thread_num(3);
+        assert_eq!(3, pool.live_thread_num());
+        pool.set_thread_num(1);
+        let start = Instant::now();
+        while pool.live_thread_num() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_max_queue() {
+        let pool = ThreadPool::new(1, None, None, Some(2));
+        let released = Arc::new(AtomicBool::new(false));
+        let r = released.clone();
+        pool.execute(move || {
+            while !r.load(Ordering::Acquire) {
+                sleep(Duration::from_millis(10));
+            }
+        });
+        sleep(Duration::from_millis(100));
+        assert_eq!(1, pool.active_thread_num());
+
+        // the busy worker leaves the queued jobs waiting
+        let exec_num = Arc::new(AtomicUsize::new(0));
+        for _job in 0..2 {
+            let e = exec_num.clone();
+            assert!(pool
+                .try_execute(move || {
+                    e.fetch_add(1, Ordering::Release);
+                })
+                .is_ok());
+        }
+        assert_eq!(2, pool.queued_job_num());
+        assert!(pool.try_execute(|| {}).is_err());
+        // the unbounded execute still accepts it
+        pool.execute(|| {});
+        assert_eq!(3, pool.queued_job_num());
+
+        released.store(true, Ordering::Release);
+        pool.join();
+        assert_eq!(2, exec_num.load(Ordering::Acquire));
+        assert!(pool.try_execute(|| {}).is_ok());
+        pool.join();
+    }
+
+    #[test]
+    fn test_warmup() {
+        let thread_num = 4;
+        let pool = ThreadPool::new(thread_num, None, None, None);
+        let warm_threads = Arc::new(Mutex::new(HashSet::new()));
+
+        let w = warm_threads.clone();
+        pool.warmup(move || {
+            w.lock().unwrap().insert(thread::current().id());
+        });
+        // every worker has run the warmup job once before returning
+        assert_eq!(thread_num, warm_threads.lock().unwrap().len());
+        assert_eq!(0, pool.queued_job_num());
+
+        // the panicking warmup job does not block
+        pool.warmup(|| panic!("warmup should panic"));
+        pool.join();
+        assert_eq!(0, pool.panicked_thread_num());
+
+        // nothing to warm up without worker
+        let pool = ThreadPool::new(0, None, None, None);
+        pool.warmup(|| {});
+        assert_eq!(0, pool.thread_num());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let pool = ThreadPool::new(3, None, None, None);
+        let exec_nums: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..50).map(|_| AtomicUsize::new(0)).collect());
+        for job in 0..50 {
+            let e = exec_nums.clone();
+            pool.execute(move || {
+                sleep(Duration::from_millis(5));
+                e[job].fetch_add(1, Ordering::Release);
+            });
+        }
+
+        pool.shutdown();
+        assert!(exec_nums.iter().all(|n| n.load(Ordering::Acquire) == 1));
+        assert_eq!(0, pool.live_thread_num());
+        assert_eq!(0, pool.queued_job_num());
+
+        // no more jobs are accepted
+        assert!(pool.try_execute(|| {}).is_err());
+        pool.execute(|| {});
+        assert_eq!(0, pool.queued_job_num());
+    }
 }
@@ -0,0 +1,120 @@
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use wasmer::vm::{
+    self, MemoryError, MemoryStyle, TableStyle, VMMemoryDefinition, VMTableDefinition,
+};
+use wasmer::{MemoryType, Pages, TableType, Tunables};
+
+/// [```LimitingTunables```]
+/// cap the max pages of every memory created for the instances,
+/// so one function cannot exhaust the host memory of all the functions
+pub(super) struct LimitingTunables<T: Tunables> {
+    _limit: Pages,
+    _base: T,
+}
+
+impl<T: Tunables> LimitingTunables<T> {
+    pub(super) fn new(base: T, limit: Pages) -> Self {
+        Self {
+            _limit: limit,
+            _base: base,
+        }
+    }
+
+    /// the memory type with the max pages not over the limit
+    fn adjust_memory(&self, requested: &MemoryType) -> MemoryType {
+        let mut adjusted = *requested;
+        adjusted.maximum = Some(
+            requested
+                .maximum
+                .map_or(self._limit, |m| m.min(self._limit)),
+        );
+        adjusted
+    }
+
+    /// the memory needing more initial pages than the limit cannot be created
+    fn validate_memory(&self, ty: &MemoryType) -> Result<(), MemoryError> {
+        if ty.minimum > self._limit {
+            return Err(MemoryError::Generic(format!(
+                "The memory needs {} pages initially, over the cap of {} pages",
+                ty.minimum.0, self._limit.0
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Tunables> Tunables for LimitingTunables<T> {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self._base.memory_style(&self.adjust_memory(memory))
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self._base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self._base.create_host_memory(&adjusted, style)
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<Arc<dyn vm::Memory>, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self._base
+            .create_vm_memory(&adjusted, style, vm_definition_location)
+    }
+
+    fn create_host_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+    ) -> Result<Arc<dyn vm::Table>, String> {
+        self._base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<Arc<dyn vm::Table>, String> {
+        self._base
+            .create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LimitingTunables;
+    use wasmer::{BaseTunables, MemoryType, Pages, Target};
+
+    #[test]
+    fn test_adjust_memory() {
+        let base = BaseTunables::for_target(&Target::default());
+        let tunables = LimitingTunables::new(base, Pages(4));
+
+        let adjusted = tunables.adjust_memory(&MemoryType::new(1, None, false));
+        assert_eq!(adjusted.maximum, Some(Pages(4)));
+        let adjusted = tunables.adjust_memory(&MemoryType::new(1, Some(100), false));
+        assert_eq!(adjusted.maximum, Some(Pages(4)));
+        let adjusted = tunables.adjust_memory(&MemoryType::new(1, Some(2), false));
+        assert_eq!(adjusted.maximum, Some(Pages(2)));
+
+        let err = tunables
+            .validate_memory(&MemoryType::new(5, None, false))
+            .unwrap_err();
+        assert!(err.to_string().contains("cap of 4 pages"));
+    }
+}
@@ -0,0 +1,108 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio_io_timeout::TimeoutStream;
+
+/// [```TimeoutIncoming```]
+/// the tcp listener which applies the socket level write timeout to every accepted connection,
+/// so a client which reads the response too slowly will be disconnected.
+/// the read timeout is not applied here, because hyper keeps reading the socket to detect the
+/// closed connection while the function is running, it bounds the request headers and body instead
+pub(super) struct TimeoutIncoming {
+    _incoming: AddrIncoming,
+    _write_timeout: Option<Duration>,
+}
+
+impl TimeoutIncoming {
+    /// bind to the address, zero duration means no timeout
+    pub(super) fn bind(addr: &SocketAddr, write_timeout: Duration) -> hyper::Result<Self> {
+        Ok(Self {
+            _incoming: AddrIncoming::bind(addr)?,
+            _write_timeout: Some(write_timeout).filter(|t| !t.is_zero()),
+        })
+    }
+
+    /// set `TCP_NODELAY` on every accepted connection, so the small responses are not delayed
+    pub(super) fn set_nodelay(&mut self, enabled: bool) -> &mut Self {
+        self._incoming.set_nodelay(enabled);
+        self
+    }
+
+    #[cfg(test)]
+    pub(super) fn local_addr(&self) -> SocketAddr {
+        self._incoming.local_addr()
+    }
+}
+
+impl Accept for TimeoutIncoming {
+    type Conn = Pin<Box<TimeoutStream<AddrStream>>>;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        let write_timeout = this._write_timeout;
+
+        Pin::new(&mut this._incoming)
+            .poll_accept(cx)
+            .map_ok(|stream| {
+                let mut stream = TimeoutStream::new(stream);
+                stream.set_write_timeout(write_timeout);
+                Box::pin(stream)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TimeoutIncoming;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    #[test]
+    fn test_nodelay() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let mut incoming = rt.block_on(async {
+            TimeoutIncoming::bind(&"127.0.0.1:0".parse().unwrap(), Duration::ZERO).unwrap()
+        });
+        incoming.set_nodelay(true);
+        let addr = incoming.local_addr();
+        rt.spawn(Server::builder(incoming).serve(make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_| async {
+                Ok::<_, hyper::Error>(Response::new(Body::from("pong")))
+            }))
+        })));
+
+        // the requests on one connection are answered one by one without delay
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        for _ in 0..3 {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut buf = [0u8; 256];
+            let n = stream.read(&mut buf).unwrap();
+            let res = String::from_utf8_lossy(&buf[..n]);
+            assert!(res.starts_with("HTTP/1.1 200"));
+            assert!(res.ends_with("pong"));
+        }
+    }
+}
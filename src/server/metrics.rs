@@ -1,56 +1,152 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use hyper::header::CONTENT_TYPE;
 use hyper::http::HeaderValue;
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, StatusCode};
 use lazy_static::lazy_static;
-use prometheus::{register_counter_vec, register_gauge, register_histogram_vec};
-use prometheus::{CounterVec, Encoder, Gauge, HistogramVec, TextEncoder};
+use prometheus::core::Collector;
+use prometheus::Registry;
+use prometheus::{CounterVec, Encoder, Gauge, HistogramOpts, HistogramVec, Opts, TextEncoder};
 
 use super::shutdown_signal;
+use crate::WatchdogConfig;
 
-// global variables, register the metrics
+// global variables
 lazy_static! {
     /// text encoder for metrics result
     static ref ENCODER: TextEncoder = TextEncoder::new();
     /// content type value
     static ref CONTENT_TYPE_VALUE: HeaderValue = ENCODER.format_type().parse().unwrap();
+}
+
+/// [```Metrics```]
+/// the metrics of watchdog, registered in its own registry to prefix all the metric names
+pub(super) struct Metrics {
+    _registry: Registry,
     /// in flight: the number of functions which are running
-    pub(super) static ref IN_FLIGHT: Gauge =
-        register_gauge!("requests_in_flight", "total HTTP requests in-flight").unwrap();
+    pub(super) _in_flight: Gauge,
     /// the request count
-    pub(super) static ref REQUESTS_TOTAL: CounterVec = register_counter_vec!(
-        "requests_total",
-        "total HTTP requests processed",
-        &["code", "method"],
-    )
-    .unwrap();
+    pub(super) _requests_total: CounterVec,
     /// the running time
-    pub(super) static ref REQUEST_DURATION_HISTOGRAM: HistogramVec = register_histogram_vec!(
-        "request_duration_seconds",
-        "Seconds spent serving HTTP requests.",
-        &["code", "method"],
-    )
-    .unwrap();
+    pub(super) _request_duration: HistogramVec,
+    /// the max number of functions running at the same time
+    pub(super) _peak_in_flight: AtomicUsize,
+    /// the time the metrics are created, as the start of watchdog
+    _started_at: Instant,
+}
+
+impl Metrics {
+    /// create and register the metrics
+    pub(super) fn new(config: &WatchdogConfig) -> Result<Self> {
+        let prefix = config._metrics_prefix.clone().filter(|p| !p.is_empty());
+        if let Some(ref p) = prefix {
+            // the registry prepends the prefix when gathering without checking it
+            if !is_valid_metric_name(p) {
+                return Err(anyhow!("Invalid metrics prefix `{}`", p));
+            }
+        }
+        // the static function name keeps the cardinality of metrics
+        let labels = config
+            ._function_name
+            .clone()
+            .filter(|n| !n.is_empty())
+            .map(|n| HashMap::from([("function".to_string(), n)]));
+        let registry = Registry::new_custom(prefix, labels)?;
+
+        let in_flight = Gauge::new("requests_in_flight", "total HTTP requests in-flight")?;
+        let requests_total = CounterVec::new(
+            Opts::new("requests_total", "total HTTP requests processed"),
+            &["code", "method"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Seconds spent serving HTTP requests.",
+            )
+            .buckets(config._duration_buckets.clone()),
+            &["code", "method"],
+        )?;
+
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(requests_total.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        #[cfg(feature = "wasm")]
+        registry.register(Box::new(crate::runner::WORKER_PANICS_TOTAL.clone()))?;
+        #[cfg(feature = "wasm")]
+        registry.register(Box::new(crate::runner::WASM_INSTANCE_MEMORY_BYTES.clone()))?;
+
+        Ok(Self {
+            _registry: registry,
+            _in_flight: in_flight,
+            _requests_total: requests_total,
+            _request_duration: request_duration,
+            _peak_in_flight: AtomicUsize::new(0),
+            _started_at: Instant::now(),
+        })
+    }
+
+    /// the one line summary of the served requests, the errors are the ones responded 5xx
+    pub(super) fn summary(&self, invocations: usize) -> String {
+        let (mut requests, mut errors) = (0, 0);
+        for family in self._requests_total.collect() {
+            for metric in family.get_metric() {
+                let count = metric.get_counter().get_value() as u64;
+                requests += count;
+                let is_error = metric
+                    .get_label()
+                    .iter()
+                    .any(|l| l.get_name() == "code" && l.get_value().starts_with('5'));
+                if is_error {
+                    errors += count;
+                }
+            }
+        }
+        format!(
+            "requests={} errors={} peak_in_flight={} invocations={} uptime={}s",
+            requests,
+            errors,
+            self._peak_in_flight.load(Ordering::Relaxed),
+            invocations,
+            self._started_at.elapsed().as_secs()
+        )
+    }
+
+    /// encode all the metrics to text format
+    pub(super) fn encode(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        ENCODER.encode(&self._registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// the metric name must match `[a-zA-Z_:][a-zA-Z0-9_:]*`
+fn is_valid_metric_name(name: &str) -> bool {
+    name.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    })
 }
 
-async fn handle(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+async fn handle(metrics: Arc<Metrics>, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     let mut response = Response::default(); // default is 200 OK
     match req.uri().path() {
-        "/metrics" => {
-            let metric_families = prometheus::gather();
-            let mut buffer = vec![];
-            if let Err(e) = ENCODER.encode(&metric_families, &mut buffer) {
-                *response.body_mut() = Body::from(format!("Encode error: {:?}", e));
-            } else {
+        "/metrics" => match metrics.encode() {
+            Ok(buffer) => {
                 response
                     .headers_mut()
                     .insert(CONTENT_TYPE, CONTENT_TYPE_VALUE.clone());
                 *response.body_mut() = Body::from(buffer);
             }
-        }
+            Err(e) => {
+                *response.body_mut() = Body::from(format!("Encode error: {:?}", e));
+            }
+        },
         _ => {
             *response.status_mut() = StatusCode::NOT_FOUND;
         }
@@ -63,15 +159,97 @@ pub(super) fn build_and_serve(
     name: &'static str,
     addr: SocketAddr,
     num_threads: usize,
+    metrics: Arc<Metrics>,
 ) -> Result<()> {
-    // init the metrics value
-    IN_FLIGHT.set(0 as f64);
-
     build_and_serve!(
         name,
-        addr,
+        AddrIncoming::bind(&addr)?,
         num_threads,
-        make_service_fn(|_| { async { Ok::<_, hyper::Error>(service_fn(|req: _| handle(req))) } })
+        make_service_fn(move |_| {
+            let metrics = metrics.clone();
+            async move { Ok::<_, hyper::Error>(service_fn(move |req: _| handle(metrics.clone(), req))) }
+        })
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{handle, Metrics};
+    use crate::WatchdogConfig;
+    use hyper::body::to_bytes;
+    use hyper::{Body, Request};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn new_metrics(vars: &[(&str, &str)]) -> Result<Metrics, anyhow::Error> {
+        let mut env = HashMap::new();
+        env.insert("fprocess".to_string(), "process".to_string());
+        for (k, v) in vars {
+            env.insert(k.to_string(), v.to_string());
+        }
+        Metrics::new(&WatchdogConfig::new(&env).unwrap())
+    }
+
+    fn scrape(metrics: Metrics) -> String {
+        let req = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let res = handle(Arc::new(metrics), req).await.unwrap();
+                let body = to_bytes(res.into_body()).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            })
+    }
+
+    #[test]
+    fn test_metrics_prefix() {
+        let metrics = new_metrics(&[("metrics_prefix", "myfn")]).unwrap();
+        metrics
+            ._requests_total
+            .with_label_values(&["200", "get"])
+            .inc();
+        let text = scrape(metrics);
+        assert!(text.contains("myfn_requests_in_flight 0"));
+        assert!(text.contains("myfn_requests_total{code=\"200\",method=\"get\"} 1"));
+        assert!(!text.contains("\nrequests_total"));
+
+        let text = scrape(new_metrics(&[]).unwrap());
+        assert!(text.contains("\nrequests_in_flight 0"));
+
+        assert!(new_metrics(&[("metrics_prefix", "my-fn")]).is_err());
+        assert!(new_metrics(&[("metrics_prefix", "1fn")]).is_err());
+    }
+
+    #[test]
+    fn test_function_label() {
+        let metrics = new_metrics(&[("function_name", "myfn")]).unwrap();
+        metrics
+            ._requests_total
+            .with_label_values(&["200", "get"])
+            .inc();
+        let text = scrape(metrics);
+        assert!(text.contains("requests_in_flight{function=\"myfn\"} 0"));
+        assert!(text.contains("requests_total{code=\"200\",method=\"get\",function=\"myfn\"} 1"));
+
+        let text = scrape(new_metrics(&[]).unwrap());
+        assert!(!text.contains("function="));
+    }
+
+    #[test]
+    fn test_duration_buckets() {
+        let metrics = new_metrics(&[("duration_buckets", "0.0005,0.002")]).unwrap();
+        metrics
+            ._request_duration
+            .with_label_values(&["200", "get"])
+            .observe(0.001);
+        let text = scrape(metrics);
+        assert!(text.contains("le=\"0.0005\"} 0"));
+        assert!(text.contains("le=\"0.002\"} 1"));
+        assert!(!text.contains("le=\"0.005\""));
+    }
+}
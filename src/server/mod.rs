@@ -1,13 +1,13 @@
 /// build the server for given handler and block to listen connections
 macro_rules! build_and_serve {
-    ($name:expr,$addr:expr,$num_thread:expr,$svc:expr) => {
+    ($name:expr,$incoming:expr,$num_thread:expr,$svc:expr) => {
         tokio::runtime::Builder::new_multi_thread()
             .worker_threads($num_thread)
             .enable_all()
             .build()
             .unwrap()
             .block_on(async move {
-                hyper::Server::bind(&$addr)
+                hyper::Server::builder($incoming)
                     .serve($svc)
                     .with_graceful_shutdown(shutdown_signal($name))
                     .await
@@ -22,31 +22,41 @@ mod watchdog;
 /// metrics server
 mod metrics;
 
-use std::net::{IpAddr, SocketAddr};
+/// the listener with connection timeouts
+mod incoming;
+
+/// the OpenTelemetry spans of requests
+#[cfg(feature = "otel")]
+mod otel;
+
+use std::future::{poll_fn, Future};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::Poll;
 use std::thread;
 
 use anyhow::Result;
-use log::{error, info};
+use log::{error, info, warn};
 use tokio::signal::ctrl_c;
 
-use crate::WatchdogConfig;
-
-const DEFAULT_IP_STR: &str = "0.0.0.0";
+use crate::{mark_unhealthy, WatchdogConfig};
+use metrics::Metrics;
 
 /// start the watchdog server and metrics server
 pub(crate) fn start_server(config: WatchdogConfig) -> Result<()> {
     info!("Watchdog mode: {}", config._operational_mode);
 
-    let default_ip: IpAddr = DEFAULT_IP_STR.parse().unwrap();
+    let watchdog_addr = SocketAddr::new(config._listen_address, config._tcp_port);
+    let metrics_addr = SocketAddr::new(config._listen_address, config._metrics_port);
 
-    let watchdog_addr = SocketAddr::new(default_ip.clone(), config._tcp_port);
-    let metrics_addr = SocketAddr::new(default_ip, config._metrics_port);
+    let metrics = Arc::new(Metrics::new(&config)?);
+    let server_metrics = metrics.clone();
 
-    info!("Metrics listening on port: {}", config._metrics_port);
+    info!("Metrics listening on: {}", metrics_addr);
     // start the metrics server in another thread
     thread::Builder::new().spawn(move || {
         // metrics only use 1 threads
-        if let Err(e) = metrics::build_and_serve("metrics", metrics_addr, 1) {
+        if let Err(e) = metrics::build_and_serve("metrics", metrics_addr, 1, server_metrics) {
             error!("Metrics server error! {}", e);
             // stop process
             std::process::exit(1);
@@ -54,17 +64,83 @@ pub(crate) fn start_server(config: WatchdogConfig) -> Result<()> {
     })?;
 
     // generate the request handler
-    info!("Listening on port: {}", config._tcp_port);
+    info!("Listening on: {}", watchdog_addr);
     // block in current thread
     let num_thread = num_cpus::get();
     // default use the cpus number as thread num
-    watchdog::build_and_serve("watchdog", watchdog_addr, num_thread, config)
+    watchdog::build_and_serve("watchdog", watchdog_addr, num_thread, config, metrics)
 }
 
-/// wait for ctrl+c signal
+/// wait for ctrl+c or SIGTERM signal, then fail the health check while draining
 async fn shutdown_signal(server_name: &'static str) {
+    let signal = wait_signal().await;
+    info!("{} server shutdown by {}", server_name, signal);
+    if let Err(e) = mark_unhealthy() {
+        warn!("Cannot remove the lock file: {}", e);
+    }
+}
+
+/// wait for ctrl+c or SIGTERM (which is sent by container runtimes), return the signal name
+#[cfg(unix)]
+async fn wait_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let terminate =
+        signal(SignalKind::terminate()).expect("failed to install SIGTERM signal handler");
+    wait_terminate(terminate).await
+}
+
+/// wait for ctrl+c or the installed SIGTERM stream, return the signal name
+#[cfg(unix)]
+async fn wait_terminate(mut terminate: tokio::signal::unix::Signal) -> &'static str {
+    let mut interrupt = Box::pin(ctrl_c());
+    poll_fn(|cx| {
+        if terminate.poll_recv(cx).is_ready() {
+            return Poll::Ready("SIGTERM");
+        }
+        interrupt.as_mut().poll(cx).map(|res| {
+            res.expect("failed to install CTRL+C signal handler");
+            "CTRL+C"
+        })
+    })
+    .await
+}
+
+/// wait for ctrl+c, return the signal name
+#[cfg(not(unix))]
+async fn wait_signal() -> &'static str {
     ctrl_c()
         .await
         .expect("failed to install CTRL+C signal handler");
-    info!("{} server shutdown", server_name);
+    "CTRL+C"
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    #[cfg(unix)]
+    fn test_sigterm_signal() {
+        use std::time::Duration;
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // the handler is installed before signalling, else the signal kills the test
+        let terminate = {
+            let _guard = rt.enter();
+            signal(SignalKind::terminate()).unwrap()
+        };
+        let status = std::process::Command::new("kill")
+            .args(["-TERM", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let res = rt.block_on(async {
+            tokio::time::timeout(Duration::from_secs(5), super::wait_terminate(terminate)).await
+        });
+        assert_eq!(res.unwrap(), "SIGTERM");
+    }
 }
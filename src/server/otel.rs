@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use hyper::http::HeaderMap;
+use hyper::{Method, StatusCode};
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{Span as _, SpanKind, Status, Tracer as _, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime::TokioCurrentThread;
+use opentelemetry_sdk::trace::{Span, Tracer, TracerProvider};
+
+/// [```RequestTracer```]
+/// emits one OpenTelemetry span for every request
+pub(super) struct RequestTracer {
+    /// keep the provider alive, the pending spans are exported when it drops
+    _provider: TracerProvider,
+    _tracer: Tracer,
+    _propagator: TraceContextPropagator,
+    _function_name: Option<String>,
+}
+
+impl RequestTracer {
+    /// export the spans to the OTLP/HTTP collector at endpoint in background
+    pub(super) fn new(endpoint: &str, function_name: Option<String>) -> Result<Self> {
+        let exporter = opentelemetry_otlp::new_exporter()
+            .http()
+            .with_endpoint(endpoint)
+            .build_span_exporter()
+            .map_err(|e| anyhow!("Cannot create the OTLP exporter for `{}`: {}", endpoint, e))?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(exporter, TokioCurrentThread)
+            .build();
+        Ok(Self::with_provider(provider, function_name))
+    }
+
+    pub(super) fn with_provider(provider: TracerProvider, function_name: Option<String>) -> Self {
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        Self {
+            _provider: provider,
+            _tracer: tracer,
+            _propagator: TraceContextPropagator::new(),
+            _function_name: function_name,
+        }
+    }
+
+    /// start the span of request, as the child of the incoming `traceparent` if present
+    pub(super) fn start(&self, method: &Method, path: &str, headers: &HeaderMap) -> Span {
+        let parent = self._propagator.extract(&HeaderExtractor(headers));
+        let mut attributes = vec![
+            KeyValue::new("http.method", method.to_string()),
+            KeyValue::new("http.target", path.to_string()),
+        ];
+        if let Some(ref name) = self._function_name {
+            attributes.push(KeyValue::new("faas.name", name.clone()));
+        }
+        self._tracer
+            .span_builder(method.to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(&self._tracer, &parent)
+    }
+
+    /// end the span with the response status, none means the connection failed
+    pub(super) fn end(mut span: Span, status: Option<StatusCode>, duration: Duration) {
+        span.set_attribute(KeyValue::new(
+            "http.duration_ms",
+            duration.as_secs_f64() * 1000.0,
+        ));
+        match status {
+            Some(status) => {
+                span.set_attribute(KeyValue::new("http.status_code", status.as_u16() as i64));
+                if status.is_server_error() {
+                    span.set_status(Status::error(status.to_string()));
+                }
+            }
+            None => span.set_status(Status::error("connection error")),
+        }
+        span.end();
+    }
+}
+
+/// read the trace context from request headers
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
@@ -1,23 +1,36 @@
-use std::future::Future;
+use std::future::{poll_fn, Future};
+use std::io::Read;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::Duration;
+#[cfg(feature = "otel")]
+use std::time::Instant;
 
 use anyhow::{anyhow, Result};
+use flate2::read::{GzDecoder, ZlibDecoder};
 use hyper::body::{to_bytes, Bytes, HttpBody};
-use hyper::header::CONTENT_TYPE;
-use hyper::http::HeaderValue;
+use hyper::header::{
+    ACCEPT, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, EXPECT, TRANSFER_ENCODING,
+};
+use hyper::http::{request, response, HeaderMap, HeaderValue};
+use hyper::server::Builder;
 use hyper::service::Service;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use lazy_static::lazy_static;
-use log::error;
-use tokio::sync::mpsc;
+use log::{debug, error, info, warn};
+use prometheus::Gauge;
+use tokio::sync::{mpsc, watch, Semaphore};
 
-use super::metrics::{IN_FLIGHT, REQUESTS_TOTAL, REQUEST_DURATION_HISTOGRAM};
+use super::incoming::TimeoutIncoming;
+use super::metrics::Metrics;
+#[cfg(feature = "otel")]
+use super::otel::RequestTracer;
 use super::shutdown_signal;
 use crate::runner::{
-    ForkingRunner, HttpRunner, Runner, SerializingForkRunner, StaticFileProcessor,
+    ForkingRunner, HttpRunner, Runner, RunnerError, SerializingForkRunner, StaticFileProcessor,
 };
 use crate::*;
 
@@ -37,11 +50,115 @@ macro_rules! method_to_str {
     };
 }
 
+/// the shared state for watchdog services
+pub(super) struct WatchdogState {
+    pub(super) _config: WatchdogConfig,
+    /// if the server has begun to shut down
+    pub(super) _shutting_down: AtomicBool,
+    /// the page and its content type served at `/` instead of the function
+    pub(super) _root_page: Option<(Bytes, HeaderValue)>,
+    /// the body served for GET requests whose function output is empty
+    pub(super) _get_default_response: Option<Bytes>,
+    /// the body served with 504 when the request timeout
+    pub(super) _timeout_response_body: String,
+    /// the metrics of requests
+    pub(super) _metrics: Arc<Metrics>,
+    /// the permits of the tasks reading request bodies if `max_body_pumps` is set
+    pub(super) _body_pumps: Option<Arc<Semaphore>>,
+    /// the tracer emitting a span for every request if `otel_endpoint` is set
+    #[cfg(feature = "otel")]
+    pub(super) _tracer: Option<RequestTracer>,
+}
+
+impl WatchdogState {
+    pub(super) fn new(config: WatchdogConfig, metrics: Arc<Metrics>) -> Result<Self> {
+        let root_page = match config._root_page_file {
+            Some(ref path) if config._operational_mode == WatchdogMode::ModeWasm => {
+                let page = std::fs::read(path)
+                    .map_err(|e| anyhow!("Cannot read the root page file `{}`: {}", path, e))?;
+                info!("Serve the root page from `{}`", path);
+                Some((Bytes::from(page), root_page_content_type(path)))
+            }
+            Some(_) => {
+                warn!("The root page file is only served in wasm mode");
+                None
+            }
+            None => None,
+        };
+
+        let get_default_response = match config._get_default_response.as_deref() {
+            Some(v) => Some(Bytes::from(read_literal_or_file(
+                "get default response",
+                v,
+            )?)),
+            None => None,
+        };
+
+        let timeout_response_body = match config._timeout_response_body.as_deref() {
+            Some(v) => {
+                let body = read_literal_or_file("timeout response body", v)?;
+                String::from_utf8_lossy(&body).into_owned()
+            }
+            None => DEFAULT_TIMEOUT_RESPONSE_BODY.to_string(),
+        };
+
+        let body_pumps = config._max_body_pumps.map(|n| Arc::new(Semaphore::new(n)));
+
+        #[cfg(feature = "otel")]
+        let tracer = match config._otel_endpoint.as_deref() {
+            Some(endpoint) => {
+                info!("Export the request spans to `{}`", endpoint);
+                Some(RequestTracer::new(endpoint, config._function_name.clone())?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            _config: config,
+            _shutting_down: AtomicBool::new(false),
+            _root_page: root_page,
+            _get_default_response: get_default_response,
+            _timeout_response_body: timeout_response_body,
+            _body_pumps: body_pumps,
+            _metrics: metrics,
+            #[cfg(feature = "otel")]
+            _tracer: tracer,
+        })
+    }
+}
+
+/// the value itself, or the content of file if it starts with `@`
+fn read_literal_or_file(name: &str, value: &str) -> Result<Vec<u8>> {
+    match value.strip_prefix('@') {
+        Some(path) => std::fs::read(path)
+            .map_err(|e| anyhow!("Cannot read the {} file `{}`: {}", name, path, e)),
+        None => Ok(value.as_bytes().to_vec()),
+    }
+}
+
+/// guess the content type of root page from the file extension
+fn root_page_content_type(path: &str) -> HeaderValue {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    let content_type = match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    };
+    HeaderValue::from_static(content_type)
+}
+
 pub(super) struct WatchdogMakeSvc<R>
 where
     R: Runner + Clone + Send + 'static,
 {
     pub(super) _runner: R,
+    pub(super) _state: Arc<WatchdogState>,
+    /// if serves the admin listener
+    pub(super) _admin: bool,
 }
 
 impl<R, T> Service<T> for WatchdogMakeSvc<R>
@@ -58,7 +175,15 @@ where
 
     fn call(&mut self, _: T) -> Self::Future {
         let runner = self._runner.clone();
-        let fut = async move { Ok(WatchdogService { _runner: runner }) };
+        let state = self._state.clone();
+        let admin = self._admin;
+        let fut = async move {
+            Ok(WatchdogService {
+                _runner: runner,
+                _state: state,
+                _admin: admin,
+            })
+        };
         Box::pin(fut)
     }
 }
@@ -68,6 +193,8 @@ where
     R: Runner,
 {
     _runner: R,
+    _state: Arc<WatchdogState>,
+    _admin: bool,
 }
 
 impl<R> Service<Request<Body>> for WatchdogService<R>
@@ -83,15 +210,119 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        Box::pin(handle(self._runner.clone(), req))
+        Box::pin(handle(
+            self._runner.clone(),
+            self._state.clone(),
+            self._admin,
+            req,
+        ))
+    }
+}
+
+/// handle the request, within a span if tracing is enabled
+async fn handle<R: Runner>(
+    runner: R,
+    state: Arc<WatchdogState>,
+    admin: bool,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    #[cfg(feature = "otel")]
+    if let Some(ref tracer) = state._tracer {
+        let span = tracer.start(req.method(), req.uri().path(), req.headers());
+        let start = Instant::now();
+        let res = handle_timeout(runner, state.clone(), admin, req).await;
+        let status = res.as_ref().ok().map(|r| r.status());
+        RequestTracer::end(span, status, start.elapsed());
+        return res;
+    }
+
+    handle_timeout(runner, state, admin, req).await
+}
+
+/// handle the request, bounded by the overall request timeout if set
+async fn handle_timeout<R: Runner>(
+    runner: R,
+    state: Arc<WatchdogState>,
+    admin: bool,
+    req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let request_timeout = state._config._request_timeout;
+    if request_timeout.is_zero() {
+        return handle_request(runner, state, admin, req).await;
+    }
+
+    let call_id = req
+        .headers()
+        .get(X_CALL_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(|id| id.to_string());
+    let fut = handle_request(runner, state.clone(), admin, req);
+    match tokio::time::timeout(request_timeout, fut).await {
+        Ok(res) => res,
+        Err(_) => {
+            let err = format!("request timeout after {:?}", request_timeout);
+            error!(
+                "Call id `{}`: {}",
+                call_id.as_deref().unwrap_or_default(),
+                err
+            );
+            Ok(error_response(
+                Response::<Body>::default().into_parts().0,
+                StatusCode::GATEWAY_TIMEOUT,
+                state._timeout_response_body.clone(),
+                state._config._error_format,
+                call_id,
+            ))
+        }
     }
 }
 
 /// handle the request
-async fn handle<R: Runner>(runner: R, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+async fn handle_request<R: Runner>(
+    runner: R,
+    state: Arc<WatchdogState>,
+    admin: bool,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let config = &state._config;
     let mut response = Response::default(); // default is 200 OK
 
-    if req.method() == &Method::OPTIONS {
+    if state._shutting_down.load(Ordering::Acquire) {
+        // tell the client to retry elsewhere
+        *response.status_mut() = config._shutdown_reject_status;
+        response
+            .headers_mut()
+            .insert(CONNECTION, CONNECTION_CLOSE.clone());
+        return Ok(response);
+    }
+
+    // prefer the call id from gateway, else generate a new one and pass it to the function
+    let call_id = match req.headers().get(X_CALL_ID).and_then(|v| v.to_str().ok()) {
+        Some(id) => id.to_string(),
+        None => {
+            let id = generate_call_id();
+            req.headers_mut()
+                .insert(X_CALL_ID, HeaderValue::from_str(id.as_str()).unwrap());
+            id
+        }
+    };
+
+    // the path and query become the environment of function, so reject the huge ones early
+    let uri_bytes = req.uri().path_and_query().map_or(0, |p| p.as_str().len());
+    if uri_bytes > config._max_uri_bytes {
+        return Ok(error_response(
+            response.into_parts().0,
+            StatusCode::URI_TOO_LONG,
+            format!(
+                "The request uri of {} bytes exceeds the limit {}",
+                uri_bytes, config._max_uri_bytes
+            ),
+            config._error_format,
+            Some(call_id),
+        ));
+    }
+
+    if req.method() == &Method::OPTIONS && !config._options_passthrough {
         // for options methods, just return accept
         response
             .headers_mut()
@@ -99,15 +330,30 @@ async fn handle<R: Runner>(runner: R, req: Request<Body>) -> Result<Response<Bod
         response
             .headers_mut()
             .insert("Access-Control-Allow-Origin", CONTENT_ALLOW_ALL.clone());
+        if let Some(max_age) = config._cors_max_age {
+            // let browsers cache the preflight result
+            response
+                .headers_mut()
+                .insert("Access-Control-Max-Age", HeaderValue::from(max_age));
+        }
+        return Ok(response);
+    }
+
+    // with a dedicated admin port, the control endpoints are only served on it
+    if config._admin_port.is_some() && admin != is_control_path(req.uri().path()) {
+        *response.status_mut() = StatusCode::NOT_FOUND;
         return Ok(response);
     }
 
     match req.uri().path() {
         "/_/health" => {
             // check healthy
-            if req.method() == &Method::GET {
-                if check_healthy() {
-                    *response.body_mut() = Body::from("OK");
+            if matches!(*req.method(), Method::GET | Method::HEAD) {
+                if check_healthy() && runner.is_ready() {
+                    // probes using HEAD only need the status
+                    if *req.method() != Method::HEAD {
+                        *response.body_mut() = Body::from("OK");
+                    }
                 } else {
                     *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
                 }
@@ -116,85 +362,295 @@ async fn handle<R: Runner>(runner: R, req: Request<Body>) -> Result<Response<Bod
                 *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
             }
         }
+        "/_/logs" => match runner.recent_logs() {
+            Some(lines) if req.method() == Method::GET => {
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, TEXT_CONTENT_TYPE.clone());
+                *response.body_mut() = Body::from(lines.join("\n"));
+            }
+            Some(_) => *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED,
+            None => *response.status_mut() = StatusCode::NOT_FOUND,
+        },
+        "/_/reset-counters" => match config._enable_reset_counters {
+            true if req.method() == Method::POST => {
+                runner.reset_counters();
+                info!("Reset the counters");
+            }
+            true => *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED,
+            false => *response.status_mut() = StatusCode::NOT_FOUND,
+        },
+        "/" if req.method() == Method::GET && state._root_page.is_some() => {
+            let (page, content_type) = state._root_page.as_ref().unwrap();
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, content_type.clone());
+            *response.body_mut() = Body::from(page.clone());
+        }
         "/scale-reader" => {
             let (replicas, available_replicas, invocation_count) = runner.get_scale();
-            let status = ReplicaFuncStatus::new(
-                replicas as u64,
-                available_replicas as u64,
-                invocation_count as u64,
-            );
+            if accepts_text_only(req.headers()) {
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, TEXT_CONTENT_TYPE.clone());
+                *response.body_mut() = Body::from(format!(
+                    "replicas={} available={} invocations={}",
+                    replicas, available_replicas, invocation_count
+                ));
+            } else {
+                let status = ReplicaFuncStatus::new(
+                    replicas as u64,
+                    available_replicas as u64,
+                    invocation_count as u64,
+                );
 
-            response
-                .headers_mut()
-                .insert(CONTENT_TYPE, JSON_CONTENT_TYPE.clone());
-            *response.body_mut() = Body::from(status.into_json());
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, JSON_CONTENT_TYPE.clone());
+                *response.body_mut() = Body::from(status.into_json());
+            }
         }
         "/scale-updater" => match ScaleServiceRequest::from_json(get_body_string(req).await) {
             Ok(r) => {
                 if let Err(e) = runner.set_scale(r._replicas as usize) {
-                    *response.body_mut() = Body::from(e.to_string());
-                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                    response = error_response(
+                        response.into_parts().0,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        e.to_string(),
+                        config._error_format,
+                        Some(call_id),
+                    );
                 }
             }
             Err(e) => {
-                *response.body_mut() = Body::from(format!(
-                    "Cannot parse request. Please pass valid JSON. Error={}",
-                    e.to_string()
-                ));
-                *response.status_mut() = StatusCode::BAD_REQUEST;
+                response = error_response(
+                    response.into_parts().0,
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Cannot parse request. Please pass valid JSON. Error={}",
+                        e.to_string()
+                    ),
+                    config._error_format,
+                    Some(call_id),
+                );
             }
         },
         _ => {
-            IN_FLIGHT.inc();
+            // for every other path and method
+            let (mut parts, mut body) = req.into_parts();
+            // hyper sends `100 Continue` only when the body is read, so reject before reading it
+            if let Err((status, err)) = check_before_body(&state, &parts.headers) {
+                return Ok(error_response(
+                    response.into_parts().0,
+                    status,
+                    err,
+                    config._error_format,
+                    Some(call_id),
+                ));
+            }
+            if config._decompress_request {
+                let max_size = config._decompress_max_size;
+                match decompress_body(&mut parts, body, max_size, config._http_read_timeout).await {
+                    Ok(b) => body = b,
+                    Err((status, err)) => {
+                        return Ok(error_response(
+                            response.into_parts().0,
+                            status,
+                            err,
+                            config._error_format,
+                            Some(call_id),
+                        ));
+                    }
+                }
+            }
+
+            let metrics = &state._metrics;
+            let _in_flight = InFlightGuard::new(metrics);
             let start_time = SystemTime::now();
-            let method = method_to_str!(req.method());
+            let method = method_to_str!(&parts.method);
+            let is_get = parts.method == Method::GET;
             let label;
+            let status;
 
-            // for every other path and method
-            let (parts, body) = req.into_parts();
-            let (sender, receiver) =
-                mpsc::channel(get_body_chunk_size(body.size_hint().lower() as usize));
+            let capacity = config
+                ._body_channel_capacity
+                .unwrap_or_else(|| get_body_chunk_size(body.size_hint().lower() as usize));
+            // the bounded channel makes the body reading wait for a slow function
+            let (sender, receiver) = mpsc::channel(capacity);
 
+            // wait for a free pump under an upload flood, the permit is held until the body is read
+            let permit = match state._body_pumps {
+                Some(ref pumps) => pumps.clone().acquire_owned().await.ok(),
+                None => None,
+            };
             // spawn to fetch rest request body and send to stdin
-            tokio::spawn(async { recv_body(sender, body).await });
+            let read_timeout = config._http_read_timeout;
+            tokio::spawn(async move {
+                recv_body(sender, body, read_timeout).await;
+                drop(permit);
+            });
 
             let mut res_header = response.into_parts().0;
+            res_header
+                .headers
+                .insert(X_CALL_ID, HeaderValue::from_str(call_id.as_str()).unwrap());
 
             match runner.run(parts, receiver, &mut res_header).await {
-                Ok(Ok(body)) => {
+                Ok(Ok(res)) => {
+                    let (parts, mut body) = res.into_parts();
+                    res_header.status = parts.status;
+                    res_header.headers.extend(parts.headers);
+                    if let Some(ref default) = state._get_default_response {
+                        if is_get && res_header.status.is_success() && body.is_end_stream() {
+                            // give something useful to the browsers
+                            res_header.headers.remove(CONTENT_LENGTH);
+                            body = Body::from(default.clone());
+                        }
+                    }
+                    status = res_header.status;
                     response = Response::from_parts(res_header, body);
-                    label = ["200", method];
+                    label = [status.as_str(), method];
                 }
                 Ok(Err(err)) => {
-                    res_header.status = StatusCode::INTERNAL_SERVER_ERROR;
-                    response = Response::from_parts(res_header, Body::from(err.to_string()));
-                    error!("{}", err.to_string());
-                    label = ["500", method];
+                    // the runner may tell the status, such as 503 for load shedding
+                    status = err
+                        .downcast_ref::<RunnerError>()
+                        .map_or(StatusCode::INTERNAL_SERVER_ERROR, |e| e._status);
+                    error!("Call id `{}`: {}", call_id, err.to_string());
+                    response = error_response(
+                        res_header,
+                        status,
+                        err.to_string(),
+                        config._error_format,
+                        Some(call_id),
+                    );
+                    label = [status.as_str(), method];
                 }
                 Err(err) => {
-                    res_header.status = StatusCode::INTERNAL_SERVER_ERROR;
-                    response = Response::from_parts(res_header, Body::from(err.to_string()));
-                    error!("{}", err.to_string());
+                    error!("Call id `{}`: {}", call_id, err.to_string());
+                    response = error_response(
+                        res_header,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        err.to_string(),
+                        config._error_format,
+                        Some(call_id),
+                    );
                     label = ["500", method];
                 }
             }
 
-            REQUESTS_TOTAL.with_label_values(&label).inc();
-            REQUEST_DURATION_HISTOGRAM
+            metrics._requests_total.with_label_values(&label).inc();
+            metrics
+                ._request_duration
                 .with_label_values(&label)
                 .observe(duration_to_seconds(
                     SystemTime::now().duration_since(start_time).unwrap(),
                 ));
-            IN_FLIGHT.dec();
         }
     }
 
     Ok(response)
 }
 
+/// [```InFlightGuard```]
+/// count a running function in the gauge until dropped,
+/// so the request cancelled by timeout or disconnection is not counted forever
+struct InFlightGuard(Gauge);
+
+impl InFlightGuard {
+    fn new(metrics: &Metrics) -> Self {
+        let gauge = &metrics._in_flight;
+        gauge.inc();
+        metrics
+            ._peak_in_flight
+            .fetch_max(gauge.get() as usize, Ordering::Relaxed);
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// check if the request would be accepted before reading its body
+fn check_before_body(
+    state: &WatchdogState,
+    headers: &HeaderMap,
+) -> std::result::Result<(), (StatusCode, String)> {
+    let config = &state._config;
+    if let Some(expect) = headers.get(EXPECT) {
+        if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+            return Err((
+                StatusCode::EXPECTATION_FAILED,
+                format!("Unsupported expectation: {:?}", expect),
+            ));
+        }
+    }
+    if config._require_content_length
+        && headers.contains_key(TRANSFER_ENCODING)
+        && !headers.contains_key(CONTENT_LENGTH)
+    {
+        // the chunked body is not accepted by the function
+        return Err((
+            StatusCode::LENGTH_REQUIRED,
+            "The request body must have a Content-Length".to_string(),
+        ));
+    }
+    if config._max_inflight > 0 && state._metrics._in_flight.get() >= config._max_inflight as f64 {
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            "Concurrent request limit exceeded".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// the control endpoints of watchdog
+#[inline(always)]
+fn is_control_path(path: &str) -> bool {
+    path.starts_with("/_/") || path == "/scale-reader" || path == "/scale-updater"
+}
+
+/// if the client asks for `text/plain` but not `application/json`, json is the default
+fn accepts_text_only(headers: &HeaderMap) -> bool {
+    let accept = match headers.get(ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return false,
+    };
+    accept.contains("text/plain") && !accept.contains("application/json")
+}
+
+/// generate the error response with the configured body format
+fn error_response(
+    mut res_header: response::Parts,
+    status: StatusCode,
+    err: String,
+    format: ErrorFormat,
+    request_id: Option<String>,
+) -> Response<Body> {
+    res_header.status = status;
+    let body = match format {
+        ErrorFormat::Text => Body::from(err),
+        ErrorFormat::Json => {
+            res_header
+                .headers
+                .insert(CONTENT_TYPE, JSON_CONTENT_TYPE.clone());
+            Body::from(ErrorResponse::new(err, request_id).into_json())
+        }
+    };
+    Response::from_parts(res_header, body)
+}
+
+/// the default body of 504 when the request timeout
+const DEFAULT_TIMEOUT_RESPONSE_BODY: &str = "Request timed out";
+
 lazy_static! {
     static ref CONTENT_ALLOW_ALL: HeaderValue = "*".parse().unwrap();
+    static ref CONNECTION_CLOSE: HeaderValue = "close".parse().unwrap();
     static ref JSON_CONTENT_TYPE: HeaderValue = "application/json; charset=utf-8".parse().unwrap();
+    static ref TEXT_CONTENT_TYPE: HeaderValue = "text/plain; charset=utf-8".parse().unwrap();
 }
 
 /// get the body channel buf size
@@ -209,12 +665,104 @@ fn get_body_chunk_size(b: usize) -> usize {
 }
 
 /// receive the body data and send to channel
-async fn recv_body(send: mpsc::Sender<Result<Bytes, hyper::Error>>, mut body: Body) {
-    while let Some(buf) = body.data().await {
-        if let Err(e) = send.send(buf).await {
-            error!("Body data send error: {}", e);
+/// stop once the receiver is gone (function exited) or the client connection is broken
+async fn recv_body(
+    send: mpsc::Sender<Result<Bytes, hyper::Error>>,
+    mut body: Body,
+    read_timeout: Duration,
+) {
+    while let Some(buf) = next_chunk(&mut body, read_timeout).await {
+        let is_broken = buf.is_err();
+        if send.send(buf).await.is_err() {
+            debug!("Body receiver has been closed, drop the rest of request body");
+            return;
+        }
+        if is_broken {
+            debug!("Client connection is broken when reading request body");
+            return;
+        }
+    }
+}
+
+/// read the next body chunk, zero duration means no timeout,
+/// the client sending no chunk in the read timeout gets the error like a broken connection
+async fn next_chunk(
+    body: &mut Body,
+    read_timeout: Duration,
+) -> Option<Result<Bytes, hyper::Error>> {
+    if read_timeout.is_zero() {
+        return body.data().await;
+    }
+    match tokio::time::timeout(read_timeout, body.data()).await {
+        Ok(chunk) => chunk,
+        Err(_) => {
+            warn!(
+                "No request body is received in the read timeout {:?}",
+                read_timeout
+            );
+            // `hyper::Error` cannot be created outside hyper, so take the one of an aborted body
+            let (sender, mut aborted) = Body::channel();
+            sender.abort();
+            aborted.data().await
+        }
+    }
+}
+
+/// decompress the `gzip`/`deflate` encoded request body and update the headers for the function,
+/// return the error status and message if the body is invalid or too large
+async fn decompress_body(
+    parts: &mut request::Parts,
+    mut body: Body,
+    max_size: usize,
+    read_timeout: Duration,
+) -> Result<Body, (StatusCode, String)> {
+    let encoding = match parts.headers.get(CONTENT_ENCODING) {
+        Some(v) => v.to_str().unwrap_or_default().trim().to_ascii_lowercase(),
+        None => return Ok(body),
+    };
+    if encoding != "gzip" && encoding != "deflate" {
+        return Ok(body);
+    }
+
+    // the compressed body is buffered before decompressing, so it is limited too
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("The decompressed request body exceeds {} bytes", max_size),
+        )
+    };
+    let mut data = Vec::new();
+    while let Some(chunk) = next_chunk(&mut body, read_timeout).await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if data.len() + chunk.len() > max_size {
+            return Err(too_large());
         }
+        data.extend_from_slice(chunk.as_ref());
+    }
+    let decoder: Box<dyn Read> = match encoding.as_str() {
+        "gzip" => Box::new(GzDecoder::new(data.as_ref())),
+        _ => Box::new(ZlibDecoder::new(data.as_ref())),
+    };
+    // read one more byte to check the limit, guard against decompression bombs
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Cannot decompress the {} request body: {}", encoding, e),
+            )
+        })?;
+    if decompressed.len() > max_size {
+        return Err(too_large());
     }
+
+    parts.headers.remove(CONTENT_ENCODING);
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(decompressed.len()));
+    Ok(Body::from(decompressed))
 }
 
 /// helper function, buffer the hole request body to string
@@ -236,45 +784,1634 @@ pub(super) fn build_and_serve(
     addr: SocketAddr,
     num_threads: usize,
     config: WatchdogConfig,
+    metrics: Arc<Metrics>,
 ) -> Result<()> {
+    let state = Arc::new(WatchdogState::new(config.clone(), metrics)?);
+
     match config._operational_mode {
         WatchdogMode::ModeStreaming => {
             let runner = ForkingRunner::new(config)?;
-            build_and_serve!(name, addr, num_threads, WatchdogMakeSvc { _runner: runner });
+            serve(name, addr, num_threads, runner, state)
         }
 
         WatchdogMode::ModeHTTP => {
             let runner = HttpRunner::new(config)?;
-            build_and_serve!(name, addr, num_threads, WatchdogMakeSvc { _runner: runner });
+            serve(name, addr, num_threads, runner, state)
         }
 
         WatchdogMode::ModeStatic => {
             let runner = StaticFileProcessor::new(config)?;
-            build_and_serve!(name, addr, num_threads, WatchdogMakeSvc { _runner: runner });
+            serve(name, addr, num_threads, runner, state)
         }
 
         WatchdogMode::ModeSerializing => {
             let runner = SerializingForkRunner::new(config)?;
-            build_and_serve!(name, addr, num_threads, WatchdogMakeSvc { _runner: runner });
+            serve(name, addr, num_threads, runner, state)
         }
 
         WatchdogMode::ModeWasm => {
             #[cfg(feature = "wasm")]
-            {
+            return {
                 let runner = WasmRunner::new(config)?;
-                build_and_serve!(name, addr, num_threads, WatchdogMakeSvc { _runner: runner });
-            }
+                serve(name, addr, num_threads, runner, state)
+            };
             #[cfg(not(feature = "wasm"))]
             return Err(anyhow!("`wasm` feature doest not be enable"));
         }
 
-        _ => {
-            return Err(anyhow!(
-                "watchdog mode {} is not yet implemented",
-                config._operational_mode
-            ));
+        _ => Err(anyhow!(
+            "watchdog mode {} is not yet implemented",
+            config._operational_mode
+        )),
+    }
+}
+
+/// bind the listener and apply the connection level timeouts
+fn server_builder(
+    addr: &SocketAddr,
+    config: &WatchdogConfig,
+) -> hyper::Result<Builder<TimeoutIncoming>> {
+    let mut incoming = TimeoutIncoming::bind(addr, config._http_write_timeout)?;
+    incoming.set_nodelay(config._tcp_nodelay);
+    let mut builder = hyper::Server::builder(incoming);
+    // drop the connections which do not send the complete request headers in time,
+    // the read timeout bounds them unless the handshake timeout is set
+    let header_timeout = match config._handshake_timeout.is_zero() {
+        true => config._http_read_timeout,
+        false => config._handshake_timeout,
+    };
+    if !header_timeout.is_zero() {
+        builder = builder.http1_header_read_timeout(header_timeout);
+    }
+    if let Some(max) = config._max_header_bytes {
+        // hyper responds 431 if the request headers do not fit the buffer
+        builder = builder.http1_max_buf_size(max);
+    }
+    Ok(builder)
+}
+
+/// serve the function listener (and admin listener if enabled) and block to listen connections
+fn serve<R>(
+    name: &'static str,
+    addr: SocketAddr,
+    num_threads: usize,
+    runner: R,
+    state: Arc<WatchdogState>,
+) -> Result<()>
+where
+    R: Runner + Clone + Send + Sync + 'static,
+{
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(num_threads)
+        .enable_all()
+        .build()?
+        .block_on(serve_until(addr, runner, state, shutdown_signal(name)))
+}
+
+/// serve the listeners until the shutdown future completes
+async fn serve_until<R, F>(
+    addr: SocketAddr,
+    runner: R,
+    state: Arc<WatchdogState>,
+    shutdown: F,
+) -> Result<()>
+where
+    R: Runner + Clone + Send + Sync + 'static,
+    F: Future<Output = ()> + Send + 'static,
+{
+    // hyper stops accepting once the graceful shutdown begins, so it waits for the drain period.
+    // both listeners close by the one trigger
+    let (close, closed) = watch::channel(());
+    let close_signal = |mut closed: watch::Receiver<()>| async move {
+        let _ = closed.changed().await;
+    };
+
+    let admin_server = match state._config._admin_port {
+        Some(port) => {
+            info!("Admin listening on port: {}", port);
+            let admin_addr = SocketAddr::new(addr.ip(), port);
+            let admin_server = server_builder(&admin_addr, &state._config)?
+                .serve(WatchdogMakeSvc {
+                    _runner: runner.clone(),
+                    _state: state.clone(),
+                    _admin: true,
+                })
+                .with_graceful_shutdown(close_signal(closed.clone()));
+            Some(tokio::spawn(admin_server))
+        }
+        None => None,
+    };
+
+    let server = server_builder(&addr, &state._config)?;
+    // the runner is loaded and the listeners are bound now
+    let ready_file = match state._config._ready_file_path {
+        Some(ref path) => Some(ReadyFile::create(path)?),
+        None => None,
+    };
+
+    let metrics = state._metrics.clone();
+    let join_timeout = state._config._join_timeout;
+    let write_timeout = state._config._http_write_timeout;
+    let drain_period = state._config._health_check_interval;
+    let mut server = tokio::spawn(
+        server
+            .serve(WatchdogMakeSvc {
+                _runner: runner.clone(),
+                _state: state.clone(),
+                _admin: false,
+            })
+            .with_graceful_shutdown(close_signal(closed)),
+    );
+
+    // wait for the shutdown, unless the server stops by error before it
+    let mut shutdown = Box::pin(shutdown);
+    let stopped = poll_fn(|cx| match shutdown.as_mut().poll(cx) {
+        Poll::Ready(()) => Poll::Ready(None),
+        Poll::Pending => Pin::new(&mut server).poll(cx).map(Some),
+    })
+    .await;
+    if let Some(res) = stopped {
+        res??;
+        info!(
+            "Shutdown summary: {}",
+            metrics.summary(runner.get_scale().2)
+        );
+        return Ok(());
+    }
+
+    // the new requests get the reject status instead of a refused connection until the listeners close
+    state._shutting_down.store(true, Ordering::Release);
+    // not ready any more while draining the connections
+    drop(ready_file);
+    if !drain_period.is_zero() {
+        info!(
+            "Reject the new requests for {:?} before closing the listeners",
+            drain_period
+        );
+        tokio::time::sleep(drain_period).await;
+    }
+    let _ = close.send(());
+
+    // drain the workers, the stuck functions never finish their connections, do not wait them forever
+    let join_runner = runner.clone();
+    let abandoned = tokio::task::spawn_blocking(move || join_runner.join(join_timeout))
+        .await
+        .unwrap_or_default();
+    if abandoned > 0 {
+        warn!(
+            "Abandon {} busy workers which do not finish in {:?}",
+            abandoned, join_timeout
+        );
+        info!(
+            "Shutdown summary: {}",
+            metrics.summary(runner.get_scale().2)
+        );
+        return Ok(());
+    }
+    // the in-flight requests finish in the write timeout, do not wait the stuck connections
+    let servers = async move {
+        if let Some(admin_server) = admin_server {
+            if let Err(e) = admin_server.await? {
+                error!("Admin server error! {}", e);
+            }
         }
+        server.await
+    };
+    match tokio::time::timeout(write_timeout, servers).await {
+        Ok(res) => res??,
+        Err(_) => warn!(
+            "The in-flight requests do not finish in the write timeout {:?}",
+            write_timeout
+        ),
     }
 
+    info!(
+        "Shutdown summary: {}",
+        metrics.summary(runner.get_scale().2)
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        handle, recv_body, serve_until, server_builder, Metrics, WatchdogMakeSvc, WatchdogState,
+    };
+    use crate::runner::{Runner, RunnerError};
+    use crate::WatchdogConfig;
+    use anyhow::{anyhow, Result};
+    use hyper::body::{to_bytes, Bytes};
+    use hyper::http::{request, response};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, oneshot};
+
+    /// the runner which always fails
+    #[derive(Clone)]
+    struct ErrorRunner;
+
+    impl Runner for ErrorRunner {
+        fn run(
+            &self,
+            _req_head: request::Parts,
+            _req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            let (sender, receiver) = oneshot::channel();
+            sender.send(Err(anyhow!("function error"))).unwrap();
+            receiver
+        }
+    }
+
+    /// the runner which responses the call id from injected environment
+    #[derive(Clone)]
+    struct CallIdRunner;
+
+    impl Runner for CallIdRunner {
+        fn run(
+            &self,
+            req_head: request::Parts,
+            _req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            let (sender, receiver) = oneshot::channel();
+            let env = crate::inject_environment(false, &req_head, usize::MAX, &Default::default());
+            let id = env.get("Http_X_Call_Id").cloned().unwrap_or_default();
+            sender.send(Ok(Response::new(Body::from(id)))).unwrap();
+            receiver
+        }
+    }
+
+    /// the runner which reads the whole body and then runs slowly
+    #[derive(Clone)]
+    struct SlowRunner(Duration);
+
+    impl Runner for SlowRunner {
+        fn run(
+            &self,
+            _req_head: request::Parts,
+            mut req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            let (sender, receiver) = oneshot::channel();
+            let delay = self.0;
+            tokio::spawn(async move {
+                while req_body.recv().await.is_some() {}
+                tokio::time::sleep(delay).await;
+                let _ = sender.send(Ok(Response::new(Body::from("done"))));
+            });
+            receiver
+        }
+    }
+
+    /// the runner which never finishes the function, like a wedged worker
+    #[derive(Clone)]
+    struct StuckRunner;
+
+    impl Runner for StuckRunner {
+        fn run(
+            &self,
+            _req_head: request::Parts,
+            _req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            let (sender, receiver) = oneshot::channel();
+            std::mem::forget(sender);
+            receiver
+        }
+
+        fn join(&self, timeout: Duration) -> usize {
+            std::thread::sleep(timeout);
+            1
+        }
+    }
+
+    /// the runner which responses the given status
+    #[derive(Clone)]
+    struct StatusRunner(StatusCode);
+
+    impl Runner for StatusRunner {
+        fn run(
+            &self,
+            _req_head: request::Parts,
+            _req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            let (sender, receiver) = oneshot::channel();
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = self.0;
+            sender.send(Ok(res)).unwrap();
+            receiver
+        }
+    }
+
+    /// the runner which responses the request body and the injected content headers
+    #[derive(Clone)]
+    struct EchoRunner;
+
+    impl Runner for EchoRunner {
+        fn run(
+            &self,
+            req_head: request::Parts,
+            mut req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            let (sender, receiver) = oneshot::channel();
+            let env = crate::inject_environment(false, &req_head, usize::MAX, &Default::default());
+            tokio::spawn(async move {
+                let mut body = Vec::new();
+                while let Some(Ok(chunk)) = req_body.recv().await {
+                    body.extend_from_slice(chunk.as_ref());
+                }
+                let mut res = Response::new(Body::from(body));
+                for (key, header) in [
+                    ("Http_Content_Length", "Http-Content-Length"),
+                    ("Http_Content_Encoding", "Http-Content-Encoding"),
+                ] {
+                    if let Some(v) = env.get(key) {
+                        res.headers_mut().insert(header, v.parse().unwrap());
+                    }
+                }
+                let _ = sender.send(Ok(res));
+            });
+            receiver
+        }
+    }
+
+    /// the runner which keeps the stderr lines
+    #[derive(Clone)]
+    #[cfg(feature = "wasm")]
+    struct LogRunner(crate::LogRing);
+
+    #[cfg(feature = "wasm")]
+    impl Runner for LogRunner {
+        fn recent_logs(&self) -> Option<Vec<String>> {
+            Some(self.0.lines())
+        }
+    }
+
+    fn new_state(vars: &[(&str, &str)]) -> Arc<WatchdogState> {
+        let mut env = HashMap::new();
+        env.insert("fprocess".to_string(), "process".to_string());
+        for (k, v) in vars {
+            env.insert(k.to_string(), v.to_string());
+        }
+        let config = WatchdogConfig::new(&env).unwrap();
+        let metrics = Arc::new(Metrics::new(&config).unwrap());
+        Arc::new(WatchdogState::new(config, metrics).unwrap())
+    }
+
+    /// create an empty directory used only by the test of this process
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn test_json_error() {
+        let state = new_state(&[("error_format", "json")]);
+        let req = Request::builder()
+            .uri("/")
+            .header("X-Call-Id", "call-1")
+            .body(Body::empty())
+            .unwrap();
+
+        let res = block_on(async {
+            let res = handle(ErrorRunner, state, false, req).await.unwrap();
+            let (parts, body) = res.into_parts();
+            (parts, to_bytes(body).await.unwrap())
+        });
+        assert_eq!(res.0.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            res.0.headers.get("Content-Type").unwrap(),
+            "application/json; charset=utf-8"
+        );
+        assert_eq!(res.1, r#"{"error":"function error","request_id":"call-1"}"#);
+    }
+
+    #[test]
+    fn test_call_id() {
+        let state = new_state(&[]);
+        let req = Request::builder()
+            .uri("/")
+            .header("X-Call-Id", "call-1")
+            .body(Body::empty())
+            .unwrap();
+        let (parts, body) = block_on(async {
+            let (parts, body) = handle(CallIdRunner, state.clone(), false, req)
+                .await
+                .unwrap()
+                .into_parts();
+            (parts, to_bytes(body).await.unwrap())
+        });
+        assert_eq!(body, "call-1");
+        assert_eq!(parts.headers.get("X-Call-Id").unwrap(), "call-1");
+
+        // generate one if the client does not give
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let (parts, body) = block_on(async {
+            let (parts, body) = handle(CallIdRunner, state, false, req)
+                .await
+                .unwrap()
+                .into_parts();
+            (parts, to_bytes(body).await.unwrap())
+        });
+        assert!(!body.is_empty());
+        assert_eq!(parts.headers.get("X-Call-Id").unwrap(), &body[..]);
+    }
+
+    #[test]
+    fn test_shutdown_reject() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::time::Instant;
+
+        let state = new_state(&[("healthcheck_interval", "1")]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let server = rt.spawn(serve_until(addr, CallIdRunner, state.clone(), async {
+            let _ = signal.await;
+        }));
+        let get = || {
+            let mut stream = TcpStream::connect(addr).ok()?;
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+                .unwrap();
+            let mut buf = [0u8; 256];
+            let n = stream.read(&mut buf).unwrap();
+            Some(String::from_utf8_lossy(&buf[..n]).to_string())
+        };
+        let start = Instant::now();
+        while get().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // the new connection in the drain period is answered instead of refused
+        let start = Instant::now();
+        shutdown.send(()).unwrap();
+        while !state._shutting_down.load(Ordering::Acquire) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let res = get().unwrap();
+        assert!(res.starts_with("HTTP/1.1 503"));
+        assert!(res.to_ascii_lowercase().contains("connection: close"));
+
+        // the listener closes after the drain period
+        rt.block_on(server).unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(get().is_none());
+    }
+
+    /// the runner reporting a fixed scale
+    #[derive(Clone)]
+    struct ScaleRunner;
+
+    impl Runner for ScaleRunner {
+        fn run(
+            &self,
+            _req_head: request::Parts,
+            _req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            oneshot::channel().1
+        }
+
+        fn get_scale(&self) -> (usize, usize, usize) {
+            (3, 2, 7)
+        }
+    }
+
+    #[test]
+    fn test_scale_reader_accept() {
+        let state = new_state(&[]);
+        let get = |accept: Option<&str>| {
+            let mut req = Request::builder().uri("/scale-reader");
+            if let Some(accept) = accept {
+                req = req.header("Accept", accept);
+            }
+            let req = req.body(Body::empty()).unwrap();
+            block_on(async {
+                let (parts, body) = handle(ScaleRunner, state.clone(), false, req)
+                    .await
+                    .unwrap()
+                    .into_parts();
+                (parts, to_bytes(body).await.unwrap())
+            })
+        };
+
+        for accept in [
+            None,
+            Some("application/json"),
+            Some("text/plain, application/json"),
+        ] {
+            let (parts, body) = get(accept);
+            assert_eq!(
+                parts.headers.get("Content-Type").unwrap(),
+                "application/json; charset=utf-8"
+            );
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains(r#""replicas":3"#));
+            assert!(body.contains(r#""availableReplicas":2"#));
+            assert!(body.contains(r#""invocationCount":7"#));
+        }
+
+        let (parts, body) = get(Some("text/plain"));
+        assert!(parts.headers["Content-Type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        assert_eq!(body, "replicas=3 available=2 invocations=7");
+    }
+
+    /// the runner which counts the invocations until reset
+    #[derive(Clone)]
+    struct CountRunner(Arc<AtomicUsize>);
+
+    impl Runner for CountRunner {
+        fn get_scale(&self) -> (usize, usize, usize) {
+            (1, 0, self.0.load(Ordering::Relaxed))
+        }
+
+        fn reset_counters(&self) {
+            self.0.store(0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_reset_counters() {
+        let count = Arc::new(AtomicUsize::new(5));
+        let runner = CountRunner(count.clone());
+        let send = |state: &Arc<WatchdogState>, method: Method| {
+            let req = Request::builder()
+                .method(method)
+                .uri("/_/reset-counters")
+                .body(Body::empty())
+                .unwrap();
+            block_on(handle(runner.clone(), state.clone(), false, req))
+                .unwrap()
+                .status()
+        };
+
+        // not served unless enabled
+        let state = new_state(&[]);
+        assert_eq!(send(&state, Method::POST), StatusCode::NOT_FOUND);
+        assert_eq!(count.load(Ordering::Relaxed), 5);
+
+        let state = new_state(&[("enable_reset_counters", "true")]);
+        assert_eq!(send(&state, Method::GET), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(count.load(Ordering::Relaxed), 5);
+        assert_eq!(send(&state, Method::POST), StatusCode::OK);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_admin_port() {
+        let state = new_state(&[("admin_port", "8082")]);
+        let get = |path: &str, admin: bool| {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            block_on(handle(CallIdRunner, state.clone(), admin, req))
+                .unwrap()
+                .status()
+        };
+
+        assert_ne!(get("/_/health", true), StatusCode::NOT_FOUND);
+        assert_eq!(get("/scale-reader", true), StatusCode::OK);
+        assert_eq!(get("/_/health", false), StatusCode::NOT_FOUND);
+        assert_eq!(get("/scale-reader", false), StatusCode::NOT_FOUND);
+        assert_eq!(get("/api", false), StatusCode::OK);
+        assert_eq!(get("/api", true), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_admin_port_shutdown() {
+        use std::net::TcpStream;
+
+        let free_port = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let (addr, admin_addr) = (free_port(), free_port());
+        let admin_port = admin_addr.port().to_string();
+        let state = new_state(&[
+            ("admin_port", admin_port.as_str()),
+            ("healthcheck_interval", "0"),
+        ]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let server = rt.spawn(serve_until(addr, CallIdRunner, state, async {
+            let _ = signal.await;
+        }));
+        let start = std::time::Instant::now();
+        while TcpStream::connect(admin_addr).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // the admin listener is closed with the function listener by the same shutdown
+        shutdown.send(()).unwrap();
+        rt.block_on(server).unwrap().unwrap();
+        assert!(TcpStream::connect(admin_addr).is_err());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_health_head() {
+        let state = new_state(&[]);
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri("/_/health")
+            .body(Body::empty())
+            .unwrap();
+        let res = block_on(handle(CallIdRunner, state, false, req)).unwrap();
+
+        assert_ne!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        match crate::health::check_healthy() {
+            true => assert_eq!(res.status(), StatusCode::OK),
+            false => assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE),
+        }
+        assert!(block_on(to_bytes(res.into_body())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_request_timeout() {
+        let state = new_state(&[("request_timeout", "1")]);
+        let phase = Duration::from_millis(600);
+
+        let status = block_on(async {
+            // both the body reading and the function are in time, but not the whole request
+            let (mut body_sender, body) = Body::channel();
+            tokio::spawn(async move {
+                tokio::time::sleep(phase).await;
+                let _ = body_sender.send_data(Bytes::from("slow body")).await;
+            });
+            let req = Request::builder().uri("/").body(body).unwrap();
+            handle(SlowRunner(phase), state, false, req)
+                .await
+                .unwrap()
+                .status()
+        });
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn test_timeout_response_body() {
+        let timeout_body = |state| {
+            let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+            let runner = SlowRunner(Duration::from_millis(1500));
+            let res = block_on(handle(runner, state, false, req)).unwrap();
+            assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+            block_on(to_bytes(res.into_body())).unwrap()
+        };
+
+        let state = new_state(&[("request_timeout", "1")]);
+        assert_eq!(timeout_body(state), "Request timed out");
+
+        let vars = [
+            ("request_timeout", "1"),
+            ("timeout_response_body", "Please try again later"),
+        ];
+        assert_eq!(timeout_body(new_state(&vars)), "Please try again later");
+
+        let dir = test_dir("watchdog_test_timeout_response_body");
+        let path = dir.join("timeout.html");
+        std::fs::write(&path, "<p>timeout</p>").unwrap();
+        let value = format!("@{}", path.display());
+        let vars = [
+            ("request_timeout", "1"),
+            ("timeout_response_body", value.as_str()),
+        ];
+        assert_eq!(timeout_body(new_state(&vars)), "<p>timeout</p>");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let state = new_state(&[("request_timeout", "1")]);
+        let in_flight = state._metrics._in_flight.clone();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let slow = rt.spawn(handle(
+            SlowRunner(Duration::from_millis(500)),
+            state.clone(),
+            false,
+            req,
+        ));
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(in_flight.get(), 1.0);
+        rt.block_on(slow).unwrap().unwrap();
+        assert_eq!(in_flight.get(), 0.0);
+
+        // the request cancelled by the timeout is not counted any more
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let runner = SlowRunner(Duration::from_secs(3));
+        let res = rt
+            .block_on(handle(runner, state.clone(), false, req))
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(in_flight.get(), 0.0);
+
+        // the watchdog endpoints are not counted
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        rt.block_on(handle(EchoRunner, state, false, req)).unwrap();
+        assert_eq!(in_flight.get(), 0.0);
+    }
+
+    #[test]
+    fn test_requests_total() {
+        let state = new_state(&[]);
+        let get = |runner, path: &str| {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            block_on(handle(runner, state.clone(), false, req)).unwrap();
+        };
+        get(StatusRunner(StatusCode::OK), "/");
+        get(StatusRunner(StatusCode::NOT_FOUND), "/missing");
+        get(StatusRunner(StatusCode::NOT_FOUND), "/missing");
+        // the watchdog endpoints are not counted
+        get(StatusRunner(StatusCode::OK), "/_/health");
+
+        let text = String::from_utf8(state._metrics.encode().unwrap()).unwrap();
+        assert!(text.contains("requests_total{code=\"200\",method=\"get\"} 1"));
+        assert!(text.contains("requests_total{code=\"404\",method=\"get\"} 2"));
+        assert!(text.contains("request_duration_seconds_count{code=\"404\",method=\"get\"} 2"));
+    }
+
+    #[test]
+    fn test_shutdown_summary() {
+        let state = new_state(&[]);
+        let get = |runner| {
+            let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+            block_on(handle(runner, state.clone(), false, req)).unwrap();
+        };
+        get(StatusRunner(StatusCode::OK));
+        get(StatusRunner(StatusCode::INTERNAL_SERVER_ERROR));
+        get(StatusRunner(StatusCode::NOT_FOUND));
+
+        let summary = state._metrics.summary(3);
+        assert!(
+            summary.starts_with("requests=3 errors=1 peak_in_flight=1 invocations=3 uptime="),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn test_max_body_pumps() {
+        let state = new_state(&[("max_body_pumps", "1")]);
+        let pumps = state._body_pumps.clone().unwrap();
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        // the slow upload holds the only pump
+        let (mut upload, body) = Body::channel();
+        let req = Request::builder().uri("/").body(body).unwrap();
+        let first = rt.spawn(handle(EchoRunner, state.clone(), false, req));
+        let start = std::time::Instant::now();
+        while pumps.available_permits() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // the next request waits before its body is read
+        let req = Request::builder()
+            .uri("/")
+            .body(Body::from("second"))
+            .unwrap();
+        let mut second = rt.spawn(handle(EchoRunner, state.clone(), false, req));
+        let waited = rt.block_on(async {
+            tokio::time::timeout(Duration::from_millis(300), &mut second).await
+        });
+        assert!(waited.is_err());
+
+        rt.block_on(upload.send_data(Bytes::from("first"))).unwrap();
+        drop(upload);
+        let res = rt.block_on(first).unwrap().unwrap();
+        assert_eq!(rt.block_on(to_bytes(res.into_body())).unwrap(), "first");
+        let res = rt.block_on(second).unwrap().unwrap();
+        assert_eq!(rt.block_on(to_bytes(res.into_body())).unwrap(), "second");
+        assert_eq!(pumps.available_permits(), 1);
+    }
+
+    #[test]
+    fn test_options_passthrough() {
+        let options = |state| {
+            let req = Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/")
+                .body(Body::from("to function"))
+                .unwrap();
+            block_on(handle(EchoRunner, state, false, req)).unwrap()
+        };
+
+        // answered by the watchdog
+        let res = options(new_state(&[]));
+        assert!(res.headers().contains_key("Access-Control-Allow-Origin"));
+        assert!(block_on(to_bytes(res.into_body())).unwrap().is_empty());
+
+        // answered by the function
+        let res = options(new_state(&[("options_passthrough", "true")]));
+        assert!(!res.headers().contains_key("Access-Control-Allow-Origin"));
+        assert_eq!(block_on(to_bytes(res.into_body())).unwrap(), "to function");
+    }
+
+    #[test]
+    fn test_cors_max_age() {
+        let options = |state| {
+            let req = Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/")
+                .body(Body::empty())
+                .unwrap();
+            block_on(handle(CallIdRunner, state, false, req)).unwrap()
+        };
+
+        let res = options(new_state(&[("cors_max_age", "600")]));
+        assert_eq!(res.headers().get("Access-Control-Max-Age").unwrap(), "600");
+
+        let res = options(new_state(&[]));
+        assert!(res.headers().get("Access-Control-Max-Age").is_none());
+    }
+
+    #[test]
+    fn test_root_page() {
+        let dir = test_dir("watchdog_test_root_page");
+        let path = dir.join("root.html");
+        std::fs::write(&path, "<html>welcome</html>").unwrap();
+        let state = new_state(&[("root_page_file", path.to_str().unwrap())]);
+        let get = |path: &str| {
+            let req = Request::builder()
+                .uri(path)
+                .header("X-Call-Id", "call-1")
+                .body(Body::empty())
+                .unwrap();
+            block_on(async {
+                let res = handle(CallIdRunner, state.clone(), false, req)
+                    .await
+                    .unwrap();
+                let (parts, body) = res.into_parts();
+                (parts, to_bytes(body).await.unwrap())
+            })
+        };
+
+        let (parts, body) = get("/");
+        assert_eq!(
+            parts.headers.get("Content-Type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(body, "<html>welcome</html>");
+        // other paths invoke the function
+        assert_eq!(get("/api").1, "call-1");
+
+        std::fs::remove_file(&path).unwrap();
+        let mut env = HashMap::new();
+        env.insert("fprocess".to_string(), "process".to_string());
+        env.insert("root_page_file".to_string(), path.display().to_string());
+        let config = WatchdogConfig::new(&env).unwrap();
+        let metrics = Arc::new(Metrics::new(&config).unwrap());
+        assert!(WatchdogState::new(config, metrics).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_default_response() {
+        let get = |state: Arc<WatchdogState>, method: Method, body: &'static str| {
+            let req = Request::builder()
+                .method(method)
+                .uri("/")
+                .body(Body::from(body))
+                .unwrap();
+            block_on(async {
+                let res = handle(EchoRunner, state, false, req).await.unwrap();
+                to_bytes(res.into_body()).await.unwrap()
+            })
+        };
+
+        let state = new_state(&[("get_default_response", "use POST")]);
+        assert_eq!(get(state.clone(), Method::GET, ""), "use POST");
+        assert_eq!(get(state.clone(), Method::GET, "output"), "output");
+        assert_eq!(get(state, Method::POST, ""), "");
+        // the default is empty
+        assert_eq!(get(new_state(&[]), Method::GET, ""), "");
+
+        let dir = test_dir("watchdog_test_get_default_response");
+        let path = dir.join("default.txt");
+        std::fs::write(&path, "from file").unwrap();
+        let value = format!("@{}", path.display());
+        let state = new_state(&[("get_default_response", value.as_str())]);
+        assert_eq!(get(state, Method::GET, ""), "from file");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_uri_bytes() {
+        let state = new_state(&[("max_uri_bytes", "1k")]);
+        let status = |uri: String| {
+            let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            block_on(handle(EchoRunner, state.clone(), false, req))
+                .unwrap()
+                .status()
+        };
+
+        assert_eq!(status(format!("/?q={}", "a".repeat(1000))), StatusCode::OK);
+        assert_eq!(
+            status(format!("/?q={}", "a".repeat(1024))),
+            StatusCode::URI_TOO_LONG
+        );
+        assert_eq!(
+            status(format!("/{}", "a".repeat(2048))),
+            StatusCode::URI_TOO_LONG
+        );
+    }
+
+    #[test]
+    fn test_require_content_length() {
+        let state = new_state(&[("require_content_length", "true")]);
+        let status = |state, req| {
+            block_on(async {
+                let res = handle(EchoRunner, state, false, req).await.unwrap();
+                res.status()
+            })
+        };
+        let chunked = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header("Transfer-Encoding", "chunked")
+                .body(Body::from("hello"))
+                .unwrap()
+        };
+
+        assert_eq!(
+            status(state.clone(), chunked()),
+            StatusCode::LENGTH_REQUIRED
+        );
+        let sized = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header("Content-Length", 5)
+            .body(Body::from("hello"))
+            .unwrap();
+        assert_eq!(status(state.clone(), sized), StatusCode::OK);
+        // the request without body does not need it
+        let get = Request::builder().uri("/").body(Body::empty()).unwrap();
+        assert_eq!(status(state, get), StatusCode::OK);
+        // accept the chunked request by default
+        assert_eq!(status(new_state(&[]), chunked()), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_decompress_request() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let plain = "hello ".repeat(100);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(plain.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let post = |state| {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header("Content-Encoding", "gzip")
+                .header("Content-Length", gzipped.len())
+                .body(Body::from(gzipped.clone()))
+                .unwrap();
+            block_on(async {
+                let res = handle(EchoRunner, state, false, req).await.unwrap();
+                let (parts, body) = res.into_parts();
+                (parts, to_bytes(body).await.unwrap())
+            })
+        };
+
+        let (parts, body) = post(new_state(&[("decompress_request", "true")]));
+        assert_eq!(body, plain);
+        let length = plain.len().to_string();
+        assert_eq!(parts.headers.get("Http-Content-Length").unwrap(), &length);
+        assert!(parts.headers.get("Http-Content-Encoding").is_none());
+
+        // the decompressed body is over the limit
+        let state = new_state(&[
+            ("decompress_request", "true"),
+            ("decompress_max_size", "100"),
+        ]);
+        assert_eq!(post(state).0.status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // not decompress by default
+        let (parts, body) = post(new_state(&[]));
+        assert_eq!(body, gzipped);
+        assert_eq!(parts.headers.get("Http-Content-Encoding").unwrap(), "gzip");
+
+        // the chunked compressed body is rejected once it is over the limit, without the rest
+        let state = new_state(&[
+            ("decompress_request", "true"),
+            ("decompress_max_size", "1k"),
+        ]);
+        let status = block_on(async {
+            let (mut body_sender, body) = Body::channel();
+            tokio::spawn(async move {
+                while body_sender
+                    .send_data(Bytes::from(vec![0u8; 256]))
+                    .await
+                    .is_ok()
+                {}
+            });
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header("Content-Encoding", "gzip")
+                .body(body)
+                .unwrap();
+            let res = handle(EchoRunner, state, false, req);
+            tokio::time::timeout(Duration::from_secs(5), res).await
+        });
+        assert_eq!(
+            status.unwrap().unwrap().status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        let state = new_state(&[("max_inflight", "1")]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let builder = rt.block_on(async { server_builder(&addr, &state._config).unwrap() });
+        rt.spawn(builder.serve(WatchdogMakeSvc {
+            _runner: EchoRunner,
+            _state: state.clone(),
+            _admin: false,
+        }));
+
+        let head =
+            b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n";
+        let read = |stream: &mut TcpStream| {
+            let mut buf = [0u8; 1024];
+            let n = stream.read(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        };
+
+        // accepted: the body is sent after the interim response
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(head).unwrap();
+        assert!(read(&mut stream).starts_with("HTTP/1.1 100 Continue"));
+        stream.write_all(b"hello").unwrap();
+        let res = read(&mut stream);
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+        assert!(res.ends_with("hello"));
+
+        // rejected: the limit is reached, so the body is never asked
+        state._metrics._in_flight.set(1.0);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(head).unwrap();
+        assert!(read(&mut stream).starts_with("HTTP/1.1 429"));
+        state._metrics._in_flight.set(0.0);
+
+        // unsupported expectation
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header("Expect", "something")
+            .body(Body::from("hello"))
+            .unwrap();
+        let res = block_on(handle(EchoRunner, state, false, req)).unwrap();
+        assert_eq!(res.status(), StatusCode::EXPECTATION_FAILED);
+    }
+
+    #[test]
+    fn test_ready_file() {
+        let dir = test_dir("watchdog_test_ready_file");
+        let path = dir.join("ready");
+        let state = new_state(&[
+            ("ready_file_path", path.to_str().unwrap()),
+            ("healthcheck_interval", "0"),
+        ]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let server = rt.spawn(serve_until(addr, EchoRunner, state, async {
+            let _ = signal.await;
+        }));
+
+        // the file appears once the listener is bound
+        let start = std::time::Instant::now();
+        while !path.exists() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        shutdown.send(()).unwrap();
+        rt.block_on(server).unwrap().unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// the runner which counts the joins when shutting down
+    #[derive(Clone)]
+    struct JoinRunner(Arc<AtomicUsize>);
+
+    impl Runner for JoinRunner {
+        fn join(&self, timeout: Duration) -> usize {
+            assert!(timeout.is_zero());
+            self.0.fetch_add(1, Ordering::Relaxed);
+            0
+        }
+    }
+
+    #[test]
+    fn test_join_without_timeout() {
+        let joins = Arc::new(AtomicUsize::new(0));
+        let state = new_state(&[("healthcheck_interval", "0")]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let runner = JoinRunner(joins.clone());
+        let server = rt.spawn(serve_until(addr, runner, state, async {
+            let _ = signal.await;
+        }));
+        std::thread::sleep(Duration::from_millis(100));
+
+        // the default join timeout still drains the workers
+        shutdown.send(()).unwrap();
+        rt.block_on(server).unwrap().unwrap();
+        assert_eq!(joins.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_join_timeout() {
+        use std::io::Write;
+        use std::net::TcpStream;
+
+        let state = new_state(&[("join_timeout", "1"), ("healthcheck_interval", "0")]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let server = rt.spawn(serve_until(addr, StuckRunner, state, async {
+            let _ = signal.await;
+        }));
+
+        // an in-flight request which never gets the response
+        let start = std::time::Instant::now();
+        let mut stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(s) => break s,
+                Err(_) if start.elapsed() < Duration::from_secs(5) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+
+        let start = std::time::Instant::now();
+        shutdown.send(()).unwrap();
+        rt.block_on(server).unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_max_header_bytes() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        let state = new_state(&[("max_header_bytes", "8k")]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let builder = rt.block_on(async { server_builder(&addr, &state._config).unwrap() });
+        rt.spawn(builder.serve(WatchdogMakeSvc {
+            _runner: EchoRunner,
+            _state: state,
+            _admin: false,
+        }));
+
+        let get = |header_size: usize| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let req = format!(
+                "GET / HTTP/1.1\r\nHost: test\r\nX-Large: {}\r\n\r\n",
+                "a".repeat(header_size)
+            );
+            stream.write_all(req.as_bytes()).unwrap();
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        };
+
+        assert!(get(1 << 10).starts_with("HTTP/1.1 200"));
+        assert!(get(16 << 10).starts_with("HTTP/1.1 431"));
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::time::Instant;
+
+        let state = new_state(&[("handshake_timeout", "1")]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        // take a free port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let builder = rt.block_on(async { server_builder(&addr, &state._config).unwrap() });
+        rt.spawn(builder.serve(make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|_| async {
+                Ok::<_, hyper::Error>(Response::new(Body::empty()))
+            }))
+        })));
+
+        // trickle the request headers slower than the handshake timeout but fast enough for the read timeout
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let start = Instant::now();
+        let mut closed = false;
+        while start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(200));
+            if stream.write_all(b"X-Slow: 1\r\n").is_err() {
+                closed = true;
+                break;
+            }
+            stream
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            let mut buf = [0u8; 256];
+            match stream.read(&mut buf) {
+                Ok(_) => {
+                    closed = true;
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => {}
+            }
+        }
+        assert!(closed);
+        assert!(start.elapsed() >= Duration::from_millis(800));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+        use std::time::Instant;
+
+        let state = new_state(&[("read_timeout", "1")]);
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let builder = rt.block_on(async { server_builder(&addr, &state._config).unwrap() });
+        rt.spawn(builder.serve(WatchdogMakeSvc {
+            _runner: SlowRunner(Duration::from_millis(1500)),
+            _state: state,
+            _admin: false,
+        }));
+        let connect = || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+        };
+
+        // the function running longer than the read timeout still responds
+        let mut stream = connect();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let n = stream.read(&mut buf).unwrap();
+        let res = String::from_utf8_lossy(&buf[..n]);
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.ends_with("done"));
+
+        // the client stalling in the request headers is disconnected
+        let mut stream = connect();
+        stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let start = Instant::now();
+        let closed = match stream.read(&mut buf) {
+            Ok(n) => n == 0 || buf[..n].starts_with(b"HTTP/1.1 408"),
+            Err(e) => e.kind() == std::io::ErrorKind::ConnectionReset,
+        };
+        assert!(closed);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[cfg(feature = "wasm")]
+    fn test_recent_logs() {
+        fn get<R: Runner>(runner: R) -> (StatusCode, Bytes) {
+            let req = Request::builder()
+                .uri("/_/logs")
+                .body(Body::empty())
+                .unwrap();
+            block_on(async {
+                let res = handle(runner, new_state(&[]), false, req).await.unwrap();
+                let (parts, body) = res.into_parts();
+                (parts.status, to_bytes(body).await.unwrap())
+            })
+        }
+
+        let ring = crate::LogRing::new(2);
+        for line in ["line 1", "line 2", "line 3"] {
+            ring.push(line);
+        }
+        assert_eq!(
+            get(LogRunner(ring)),
+            (StatusCode::OK, Bytes::from("line 2\nline 3"))
+        );
+        // the runner does not keep the logs
+        assert_eq!(get(ErrorRunner).0, StatusCode::NOT_FOUND);
+    }
+
+    /// the runner which sheds all the requests
+    #[derive(Clone)]
+    struct BusyRunner;
+
+    impl Runner for BusyRunner {
+        fn run(
+            &self,
+            _req_head: request::Parts,
+            _req_body: mpsc::Receiver<Result<Bytes, hyper::Error>>,
+            _res_head: &mut response::Parts,
+        ) -> oneshot::Receiver<Result<Response<Body>>> {
+            let (sender, receiver) = oneshot::channel();
+            let err = RunnerError::new(StatusCode::SERVICE_UNAVAILABLE, "busy".to_string());
+            sender.send(Err(err.into())).unwrap();
+            receiver
+        }
+    }
+
+    #[test]
+    fn test_runner_error_status() {
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let res = block_on(handle(BusyRunner, new_state(&[]), false, req)).unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = block_on(to_bytes(res.into_body())).unwrap();
+        assert_eq!(res, "busy");
+    }
+
+    /// the span exporter keeping the spans in memory
+    #[cfg(feature = "otel")]
+    #[derive(Debug, Clone, Default)]
+    struct MemoryExporter(Arc<std::sync::Mutex<Vec<opentelemetry_sdk::export::trace::SpanData>>>);
+
+    #[cfg(feature = "otel")]
+    impl opentelemetry_sdk::export::trace::SpanExporter for MemoryExporter {
+        fn export(
+            &mut self,
+            batch: Vec<opentelemetry_sdk::export::trace::SpanData>,
+        ) -> std::pin::Pin<
+            Box<
+                dyn std::future::Future<Output = opentelemetry_sdk::export::trace::ExportResult>
+                    + Send,
+            >,
+        > {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "otel")]
+    fn test_otel_span() {
+        use opentelemetry::trace::{Status, TraceId};
+        use opentelemetry::{Key, Value};
+
+        let mut env = HashMap::new();
+        env.insert("fprocess".to_string(), "process".to_string());
+        env.insert("function_name".to_string(), "busy".to_string());
+        let config = WatchdogConfig::new(&env).unwrap();
+        let metrics = Arc::new(Metrics::new(&config).unwrap());
+        let mut state = WatchdogState::new(config, metrics).unwrap();
+        let exporter = MemoryExporter::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        state._tracer = Some(super::RequestTracer::with_provider(
+            provider.clone(),
+            Some("busy".to_string()),
+        ));
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/path")
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let res = block_on(handle(BusyRunner, Arc::new(state), false, req)).unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        provider.force_flush();
+
+        let spans = exporter.0.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        let span = &spans[0];
+        assert_eq!(span.name, "POST");
+        // the span continues the incoming trace
+        assert_eq!(
+            span.span_context.trace_id(),
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        );
+        assert!(matches!(span.status, Status::Error { .. }));
+        let attribute = |key: &'static str| {
+            span.attributes
+                .iter()
+                .find(|kv| kv.key == Key::from_static_str(key))
+                .map(|kv| kv.value.clone())
+        };
+        assert_eq!(attribute("http.method"), Some(Value::from("POST")));
+        assert_eq!(attribute("http.target"), Some(Value::from("/path")));
+        assert_eq!(attribute("http.status_code"), Some(Value::I64(503)));
+        assert_eq!(attribute("faas.name"), Some(Value::from("busy")));
+        assert!(attribute("http.duration_ms").is_some());
+    }
+
+    #[test]
+    fn test_recv_body_client_dropped() {
+        block_on(async {
+            let (mut body_sender, body) = Body::channel();
+            let (sender, mut receiver) = mpsc::channel(4);
+            let pump = tokio::spawn(recv_body(sender, body, Duration::ZERO));
+
+            body_sender.send_data(Bytes::from("chunk")).await.unwrap();
+            assert_eq!(receiver.recv().await.unwrap().unwrap(), "chunk");
+
+            // the client disconnects in the middle of the body
+            body_sender.abort();
+            assert!(receiver.recv().await.unwrap().is_err());
+            pump.await.unwrap();
+            assert!(receiver.recv().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_recv_body_read_timeout() {
+        block_on(async {
+            let (mut body_sender, body) = Body::channel();
+            let (sender, mut receiver) = mpsc::channel(4);
+            let pump = tokio::spawn(recv_body(sender, body, Duration::from_millis(200)));
+
+            body_sender.send_data(Bytes::from("chunk")).await.unwrap();
+            assert_eq!(receiver.recv().await.unwrap().unwrap(), "chunk");
+
+            // the client stalls in the middle of the body
+            assert!(receiver.recv().await.unwrap().is_err());
+            pump.await.unwrap();
+            assert!(receiver.recv().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_recv_body_backpressure() {
+        block_on(async {
+            let (mut body_sender, body) = Body::channel();
+            let (sender, mut receiver) = mpsc::channel(1);
+            let pump = tokio::spawn(recv_body(sender, body, Duration::ZERO));
+            tokio::spawn(async move {
+                for _ in 0..3 {
+                    body_sender.send_data(Bytes::from("chunk")).await.unwrap();
+                }
+            });
+
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            // the reader is slow: only one chunk is buffered and the pump is waiting
+            assert!(!pump.is_finished());
+            assert!(receiver.try_recv().is_ok());
+            assert!(receiver.try_recv().is_err());
+
+            let mut chunks = 1;
+            while let Some(chunk) = receiver.recv().await {
+                assert_eq!(chunk.unwrap(), "chunk");
+                chunks += 1;
+            }
+            assert_eq!(chunks, 3);
+        });
+    }
+
+    #[test]
+    fn test_recv_body_receiver_dropped() {
+        block_on(async {
+            let (mut body_sender, body) = Body::channel();
+            let (sender, receiver) = mpsc::channel(4);
+            // the function has exited without reading stdin
+            drop(receiver);
+            let pump = tokio::spawn(recv_body(sender, body, Duration::ZERO));
+
+            body_sender.send_data(Bytes::from("chunk")).await.unwrap();
+            pump.await.unwrap();
+            assert!(body_sender.send_data(Bytes::from("rest")).await.is_err());
+        });
+    }
+}
@@ -1,9 +1,17 @@
 use std::collections::HashMap;
+#[cfg(feature = "wasm")]
+use std::collections::VecDeque;
 use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "wasm")]
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
+use hyper::header::HeaderName;
 use hyper::http::request::Parts;
 use lazy_static::lazy_static;
+use log::warn;
 
 lazy_static! {
     // skip the no UTF-8 env var
@@ -15,6 +23,9 @@ lazy_static! {
     }).collect();
 }
 
+/// the request id header which is set by OpenFaaS gateway
+pub(crate) const X_CALL_ID: &str = "X-Call-Id";
+
 #[inline(always)]
 pub(crate) fn parse_command(func: &String) -> Result<Vec<String>> {
     let v = func
@@ -33,17 +44,44 @@ pub(crate) fn environment_vars() -> &'static HashMap<String, String> {
     &ENVIRONMENT_VARS
 }
 
+/// which request headers are injected as environment, the denied ones never pass
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HeaderFilter {
+    /// the only headers passed if set
+    pub(crate) _allow: Option<Vec<HeaderName>>,
+    pub(crate) _deny: Vec<HeaderName>,
+}
+
+impl HeaderFilter {
+    /// the header names are lowercase, so the matching is case insensitive
+    pub(crate) fn allows(&self, name: &HeaderName) -> bool {
+        if self._deny.contains(name) {
+            return false;
+        }
+        match self._allow {
+            Some(ref allow) => allow.contains(name),
+            None => true,
+        }
+    }
+}
+
 #[inline(always)]
-pub(crate) fn inject_environment(inherit: bool, req_head: &Parts) -> HashMap<String, String> {
+pub(crate) fn inject_environment(
+    inherit: bool,
+    req_head: &Parts,
+    max_value_bytes: usize,
+    filter: &HeaderFilter,
+) -> HashMap<String, String> {
     let mut res = if inherit {
         ENVIRONMENT_VARS.clone()
     } else {
         HashMap::new()
     };
 
-    for (k, v) in req_head.headers.iter() {
+    for (k, v) in req_head.headers.iter().filter(|(k, _)| filter.allows(k)) {
         if let Ok(val) = v.to_str() {
-            let key = format!("Http_{}", k.to_string().replace('-', "_"));
+            let key = format!("Http_{}", canonical_header_key(k.as_str()));
+            let val = truncate_env_value(&key, val, max_value_bytes);
             res.insert(key, val.to_string());
         }
     }
@@ -58,6 +96,159 @@ pub(crate) fn inject_environment(inherit: bool, req_head: &Parts) -> HashMap<Str
     res
 }
 
+/// cut the value to at most max bytes, so a huge header does not bloat the environment
+fn truncate_env_value<'a>(key: &str, val: &'a str, max_bytes: usize) -> &'a str {
+    if val.len() <= max_bytes {
+        return val;
+    }
+    warn!(
+        "The environment `{}` has {} bytes, truncated to {} bytes",
+        key,
+        val.len(),
+        max_bytes
+    );
+    let mut end = max_bytes;
+    while !val.is_char_boundary(end) {
+        end -= 1;
+    }
+    &val[..end]
+}
+
+/// add the url decoded query params as `Http_Query_<Name>`, the repeated ones are joined with comma,
+/// the params which cannot be an environment (empty name, `=` in name or NUL) are skipped,
+/// and the values are cut to at most max bytes like the headers
+#[cfg(feature = "wasm")]
+pub(crate) fn inject_query_params(
+    env: &mut HashMap<String, String>,
+    query: &str,
+    max_value_bytes: usize,
+) {
+    let mut params: Vec<(String, String)> = Vec::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let (name, value) = (url_decode(name), url_decode(value));
+        if name.is_empty() || name.contains(['=', '\0']) || value.contains('\0') {
+            warn!(
+                "Skip the query param `{}` which cannot be an environment",
+                name.escape_debug()
+            );
+            continue;
+        }
+        match params.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, values)) => {
+                values.push(',');
+                values.push_str(&value);
+            }
+            None => params.push((name, value)),
+        }
+    }
+    for (name, value) in params {
+        let key = format!("Http_Query_{}", name);
+        let value = truncate_env_value(&key, &value, max_value_bytes).to_string();
+        env.insert(key, value);
+    }
+}
+
+/// decode the `%XX` escapes and `+` of the query component
+#[cfg(feature = "wasm")]
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => res.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        res.push(b);
+                        i += 2;
+                    }
+                    None => res.push(b'%'),
+                }
+            }
+            b => res.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+/// convert the header name to canonical format joined with `_`, such as `x-call-id` => `X_Call_Id`
+fn canonical_header_key(name: &str) -> String {
+    name.split('-')
+        .map(|s| {
+            let mut chars = s.chars();
+            match chars.next() {
+                Some(c) => {
+                    c.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                }
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("_")
+}
+
+/// generate a unique call id for the request which comes without `X-Call-Id`
+pub(crate) fn generate_call_id() -> String {
+    static CALL_COUNT: AtomicUsize = AtomicUsize::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{:x}-{:x}-{:x}",
+        now.as_nanos(),
+        std::process::id(),
+        CALL_COUNT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// parse the byte size with optional suffix `k`/`m`/`g` (case insensitive, 1024 based), such as `512k`, `1M`
+pub(crate) fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (num, unit) = match s.char_indices().last()? {
+        (i, 'k') | (i, 'K') => (&s[..i], 1 << 10),
+        (i, 'm') | (i, 'M') => (&s[..i], 1 << 20),
+        (i, 'g') | (i, 'G') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    num.trim().parse::<usize>().ok()?.checked_mul(unit)
+}
+
+/// [```LogRing```]
+/// the ring buffer keeps the recent log lines, the oldest line is dropped when it is full
+#[cfg(feature = "wasm")]
+#[derive(Clone, Debug)]
+pub(crate) struct LogRing {
+    _lines: Arc<Mutex<VecDeque<String>>>,
+    _capacity: usize,
+}
+
+#[cfg(feature = "wasm")]
+impl LogRing {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            _lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            _capacity: capacity,
+        }
+    }
+
+    pub(crate) fn push(&self, line: &str) {
+        let mut lines = self._lines.lock().unwrap();
+        if lines.len() >= self._capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
+
+    /// get the recent lines, the oldest is the first
+    pub(crate) fn lines(&self) -> Vec<String> {
+        self._lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
 macro_rules! env_get_or_warn {
     ($cfg:expr,$key:expr,$default:expr) => {
         match $cfg {
@@ -76,3 +267,139 @@ macro_rules! env_get_or_warn {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::Request;
+
+    #[test]
+    #[cfg(feature = "wasm")]
+    fn test_inject_query_params() {
+        let mut env = HashMap::new();
+        inject_query_params(&mut env, "a=1&a=2&b=x", usize::MAX);
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["Http_Query_a"], "1,2");
+        assert_eq!(env["Http_Query_b"], "x");
+
+        let mut env = HashMap::new();
+        inject_query_params(&mut env, "q=hello+world%21&flag&bad=%zz%4", usize::MAX);
+        assert_eq!(env["Http_Query_q"], "hello world!");
+        assert_eq!(env["Http_Query_flag"], "");
+        assert_eq!(env["Http_Query_bad"], "%zz%4");
+
+        // the params failing the wasi environment are skipped
+        let mut env = HashMap::new();
+        inject_query_params(&mut env, "a%3Db=1&x=%00&%00=1&=x&ok=1", usize::MAX);
+        assert_eq!(env.len(), 1);
+        assert_eq!(env["Http_Query_ok"], "1");
+
+        // the long values are truncated, after the repeated ones are joined
+        let mut env = HashMap::new();
+        inject_query_params(&mut env, "a=123&a=456&b=%C3%A9%C3%A9", 5);
+        assert_eq!(env["Http_Query_a"], "123,4");
+        assert_eq!(env["Http_Query_b"], "\u{e9}\u{e9}");
+        inject_query_params(&mut env, "b=%C3%A9%C3%A9%C3%A9", 5);
+        assert_eq!(env["Http_Query_b"], "\u{e9}\u{e9}");
+    }
+
+    #[test]
+    fn test_inject_environment() {
+        let (head, _) = Request::builder()
+            .method("POST")
+            .uri("/path?a=1")
+            .header("X-Call-Id", "call-1")
+            .header("content-type", "text/plain")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let env = inject_environment(false, &head, usize::MAX, &HeaderFilter::default());
+        assert_eq!(env.get("Http_X_Call_Id").unwrap(), "call-1");
+        assert_eq!(env.get("Http_Content_Type").unwrap(), "text/plain");
+        assert_eq!(env.get("Http_Path").unwrap(), "/path");
+        assert_eq!(env.get("Http_Method").unwrap(), "POST");
+        assert_eq!(env.get("Http_Query").unwrap(), "a=1");
+    }
+
+    #[test]
+    fn test_max_env_value_bytes() {
+        let token = "x".repeat(100);
+        let (head, _) = Request::builder()
+            .uri("/path")
+            .header("Authorization", token.as_str())
+            .header("X-Call-Id", "call-1")
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        let env = inject_environment(false, &head, 16, &HeaderFilter::default());
+        assert_eq!(env.get("Http_Authorization").unwrap(), &token[..16]);
+        assert_eq!(env.get("Http_X_Call_Id").unwrap(), "call-1");
+        assert_eq!(truncate_env_value("k", "ab\u{e9}", 3), "ab");
+    }
+
+    #[test]
+    fn test_header_filter() {
+        let (head, _) = Request::builder()
+            .uri("/path")
+            .header("Authorization", "token")
+            .header("Cookie", "a=1")
+            .header("X-Call-Id", "call-1")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let names = |s: &[&str]| s.iter().map(|n| n.parse().unwrap()).collect::<Vec<_>>();
+
+        // only the denied ones are dropped
+        let filter = HeaderFilter {
+            _allow: None,
+            _deny: names(&["AUTHORIZATION", "cookie"]),
+        };
+        let env = inject_environment(false, &head, usize::MAX, &filter);
+        assert!(!env.contains_key("Http_Authorization"));
+        assert!(!env.contains_key("Http_Cookie"));
+        assert_eq!(env.get("Http_X_Call_Id").unwrap(), "call-1");
+        assert_eq!(env.get("Http_Path").unwrap(), "/path");
+
+        // only the allowed ones pass, unless denied
+        let filter = HeaderFilter {
+            _allow: Some(names(&["x-call-id", "Cookie"])),
+            _deny: names(&["COOKIE"]),
+        };
+        let env = inject_environment(false, &head, usize::MAX, &filter);
+        assert!(!env.contains_key("Http_Authorization"));
+        assert!(!env.contains_key("Http_Cookie"));
+        assert_eq!(env.get("Http_X_Call_Id").unwrap(), "call-1");
+        assert_eq!(env.get("Http_Path").unwrap(), "/path");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("512k"), Some(512 * 1024));
+        assert_eq!(parse_size("1M"), Some(1024 * 1024));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("1.5M"), None);
+        assert_eq!(parse_size(""), None);
+    }
+
+    #[test]
+    #[cfg(feature = "wasm")]
+    fn test_log_ring() {
+        let ring = LogRing::new(2);
+        ring.push("line 1");
+        ring.push("line 2");
+        ring.push("line 3");
+        assert_eq!(ring.lines(), vec!["line 2", "line 3"]);
+    }
+
+    #[test]
+    fn test_generate_call_id() {
+        let id1 = generate_call_id();
+        let id2 = generate_call_id();
+        assert!(!id1.is_empty());
+        assert_ne!(id1, id2);
+    }
+}